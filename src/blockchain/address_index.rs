@@ -0,0 +1,684 @@
+use blockchain::hash_tree::{ HashTree, MerkleProof };
+use blockchain::transaction::{ Address, TransactionInput };
+use protocol::event::Error as ProtocolError;
+use utils::Hash;
+use utils::hash::{ Hashable, HashWriter };
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::collections::{ BTreeMap, BTreeSet, HashSet };
+use std::io::{ Error, ErrorKind };
+
+// 40 bytes per entry, chunks of about 500 KB
+const CHUNK_ENTRIES: u64 = 12_500;
+
+// runs of addresses between boundaries are split into chunks of at most
+// this many times the expected chunk length
+const MAX_CHUNK_FACTOR: u64 = 4;
+
+// chunks longer than this are rejected while reading
+const MAX_CHUNK_ENTRIES: u32 = (MAX_CHUNK_FACTOR * CHUNK_ENTRIES) as u32;
+
+/// An address and the id of its unspent output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AddressIndexEntry {
+    pub address: Address,
+    pub output_id: TransactionInput
+}
+
+/// Consecutive entries of the address index, sorted by address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk(pub Vec<AddressIndexEntry>);
+
+impl Hashable for Chunk {}
+
+impl Chunk {
+
+    // the leaf committing to the chunk in the chunks tree
+    fn leaf( &self ) -> Option<ChunkLeaf> {
+        Some(ChunkLeaf{ first: self.0.first()?.address, chunk_hash: self.hash() })
+    }
+
+    fn find( &self, address: Address ) -> Result<usize, usize> {
+        self.0.binary_search_by(|entry| entry.address.cmp(&address))
+    }
+}
+
+/// A chunk's leaf in the chunks tree. It commits to the chunk's first address,
+/// such that the range of a chunk is bounded by the leaf of the next chunk alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkLeaf {
+    pub first: Address,
+    pub chunk_hash: Hash
+}
+
+impl Hashable for ChunkLeaf {}
+
+/// The unspent output ids sorted by address and split into chunks under a Merkle tree,
+/// the chunks tree. Nano nodes search an address in a single chunk, which proves the
+/// address's output or that the address has none.
+///
+/// A run of addresses ends after every address whose first 8 bytes are a multiple of
+/// the expected chunk length. Addresses are cheap to grind, such that a run can be
+/// filled with arbitrary many addresses, so runs are split into chunks of at most
+/// `MAX_CHUNK_FACTOR` times the expected length. The chunks depend only on the set of
+/// addresses and an update changes only the chunks of its address's run.
+pub struct AddressIndex {
+    // the chunks by their first address
+    chunks: BTreeMap<Address, Chunk>,
+    // the chunks changed since the last commit
+    dirty: HashSet<Address>,
+    // the leaves of all chunks as of the last commit
+    leaves: BTreeMap<Address, Hash>,
+    tree: HashTree,
+    chunk_entries: u64
+}
+
+impl AddressIndex {
+
+    pub fn new() -> AddressIndex {
+        AddressIndex::with_chunk_entries(CHUNK_ENTRIES)
+    }
+
+    fn with_chunk_entries( chunk_entries: u64 ) -> AddressIndex {
+        AddressIndex {
+            chunks: BTreeMap::new(),
+            dirty: HashSet::new(),
+            leaves: BTreeMap::new(),
+            tree: HashTree::new(vec![]),
+            chunk_entries
+        }
+    }
+
+    /// Builds the committed index of the entries at once.
+    pub fn from_entries( mut entries: Vec<AddressIndexEntry> ) -> AddressIndex {
+        let mut index = AddressIndex::new();
+        entries.sort_by_key(|a| a.address);
+        index.put_chunks(Chunk(entries));
+        index.commit();
+        index
+    }
+
+    fn is_boundary( &self, address: &Address ) -> bool {
+        let mut prefix = 0u64;
+        for byte in address.as_bytes()[..8].iter().rev() {
+            prefix = prefix << 8 | *byte as u64;
+        }
+        prefix.is_multiple_of(self.chunk_entries)
+    }
+
+    // whether the chunk ends with a boundary, otherwise the next chunk continues its run
+    fn ends_run( &self, chunk: &Chunk ) -> bool {
+        chunk.0.last().is_some_and(|last| self.is_boundary(&last.address))
+    }
+
+    fn max_chunk_entries( &self ) -> usize {
+        (MAX_CHUNK_FACTOR * self.chunk_entries) as usize
+    }
+
+    pub fn insert( &mut self, address: Address, output_id: TransactionInput ) {
+        let keys = self.run_of(address);
+        // a run of a single chunk keeps its place unless the address starts it,
+        // ends it or does not fit into it
+        if keys.len() == 1 && keys[0] < address && !self.is_boundary(&address) {
+            let max_chunk_entries = self.max_chunk_entries();
+            let chunk = self.chunks.get_mut(&keys[0]).unwrap();
+            let position = chunk.find(address);
+            if position.is_ok() || chunk.0.len() < max_chunk_entries {
+                match position {
+                    Ok(position) => chunk.0[position].output_id = output_id,
+                    Err(position) => chunk.0.insert(position, AddressIndexEntry{ address, output_id }),
+                }
+                self.dirty.insert(keys[0]);
+                return;
+            }
+        }
+        let mut entries = self.take_chunks(&keys);
+        match entries.find(address) {
+            Ok(position) => entries.0[position].output_id = output_id,
+            Err(position) => entries.0.insert(position, AddressIndexEntry{ address, output_id }),
+        }
+        self.put_chunks(entries);
+    }
+
+    pub fn remove( &mut self, address: Address ) {
+        let keys = self.run_of(address);
+        if keys.len() == 1 && keys[0] < address && !self.is_boundary(&address) {
+            let chunk = self.chunks.get_mut(&keys[0]).unwrap();
+            if let Ok(position) = chunk.find(address) {
+                chunk.0.remove(position);
+            }
+            self.dirty.insert(keys[0]);
+            return;
+        }
+        let mut entries = self.take_chunks(&keys);
+        if let Ok(position) = entries.find(address) {
+            entries.0.remove(position);
+            // without its boundary the run continues with the next run
+            if self.is_boundary(&address) {
+                let next_keys = self.next_run(address);
+                entries.0.extend(self.take_chunks(&next_keys).0);
+            }
+        }
+        self.put_chunks(entries);
+    }
+
+    // the chunks of the run in which the address is or belongs, in order
+    fn run_of( &self, address: Address ) -> Vec<Address> {
+        let key = match self.chunks.range(..=address).next_back() {
+            Some((key, chunk)) => {
+                // the address is after the chunk's boundary
+                if self.ends_run(chunk) && chunk.0.last().unwrap().address < address { return self.next_run(address) }
+                *key
+            },
+            None => match self.chunks.keys().next() {
+                Some(key) => *key,
+                None => return vec![],
+            },
+        };
+        let mut keys: Vec<Address> = self.chunks.range(..key).rev()
+            .take_while(|&(_, chunk)| !self.ends_run(chunk))
+            .map(|(key, _)| *key)
+            .collect();
+        keys.reverse();
+        for (key, chunk) in self.chunks.range(key..) {
+            keys.push(*key);
+            if self.ends_run(chunk) { break }
+        }
+        keys
+    }
+
+    // the chunks of the first run starting after the address
+    fn next_run( &self, address: Address ) -> Vec<Address> {
+        let mut keys = Vec::new();
+        for (key, chunk) in self.chunks.range(address..).filter(|&(key, _)| *key > address) {
+            keys.push(*key);
+            if self.ends_run(chunk) { break }
+        }
+        keys
+    }
+
+    fn take_chunks( &mut self, keys: &[Address] ) -> Chunk {
+        let mut entries = Vec::new();
+        for key in keys {
+            entries.extend(self.take(*key).0);
+        }
+        Chunk(entries)
+    }
+
+    fn take( &mut self, key: Address ) -> Chunk {
+        self.dirty.insert(key);
+        self.chunks.remove(&key).unwrap()
+    }
+
+    // splits runs of entries after every boundary and into full chunks,
+    // the entries start with a run
+    fn put_chunks( &mut self, entries: Chunk ) {
+        let max_chunk_entries = self.max_chunk_entries();
+        let mut chunk = Vec::new();
+        let count = entries.0.len();
+        for (index, entry) in entries.0.into_iter().enumerate() {
+            let is_end = self.is_boundary(&entry.address) || index + 1 == count || chunk.len() + 1 == max_chunk_entries;
+            chunk.push(entry);
+            if is_end {
+                let key = chunk[0].address;
+                self.dirty.insert(key);
+                self.chunks.insert(key, Chunk(chunk));
+                chunk = Vec::new();
+            }
+        }
+    }
+
+    /// Rehashes the changed chunks and rebuilds the chunks tree.
+    pub fn commit( &mut self ) {
+        for key in self.dirty.drain() {
+            match self.chunks.get(&key) {
+                Some(chunk) => { self.leaves.insert(key, chunk.leaf().unwrap().hash()); },
+                None => { self.leaves.remove(&key); },
+            }
+        }
+        self.tree = HashTree::new(self.leaves.values().cloned().collect());
+    }
+
+    /// The root of the index after setting the outputs of the addresses, `None` removes
+    /// the address's entry. The index must be committed and is not changed.
+    pub fn root_after( &self, updates: &[( Address, Option<TransactionInput> )] ) -> Hash {
+        // an update only takes the chunks of its address's run, and of the next run
+        // if it removes the run's boundary
+        let mut keys = BTreeSet::new();
+        for &(address, output_id) in updates {
+            let run = self.run_of(address);
+            if output_id.is_none() {
+                let last = run.last().cloned().unwrap_or(address);
+                keys.extend(self.next_run(last));
+            }
+            keys.extend(run);
+        }
+
+        // update copies of these chunks and replace their leaves
+        let mut updated = AddressIndex::with_chunk_entries(self.chunk_entries);
+        updated.chunks = keys.iter().map(|key| (*key, self.chunks[key].clone())).collect();
+        for &(address, output_id) in updates {
+            match output_id {
+                Some(output_id) => updated.insert(address, output_id),
+                None => updated.remove(address),
+            }
+        }
+        let mut leaves = self.leaves.clone();
+        for key in &keys {
+            leaves.remove(key);
+        }
+        for (key, chunk) in &updated.chunks {
+            leaves.insert(*key, chunk.leaf().unwrap().hash());
+        }
+        index_root(leaves.len() as u32, HashTree::new(leaves.values().cloned().collect()).root())
+    }
+
+    /// The root of the index as of the last commit. It commits to the chunks count,
+    /// such that the last chunk proves that no addresses follow.
+    pub fn root( &self ) -> Hash {
+        index_root(self.leaves.len() as u32, self.tree.root())
+    }
+
+    /// Proves the output of the address or that the address has none.
+    /// The index must be committed.
+    pub fn prove( &self, address: Address ) -> AddressProof {
+        let chunks_count = self.leaves.len() as u32;
+        if chunks_count == 0 {
+            return AddressProof{ chunks_count, chunk: Chunk(vec![]), chunk_proof: MerkleProof{ index: 0, siblings: vec![] }, next: None };
+        }
+
+        // the address is either in the chunk or between its first address and the next chunk
+        let key = match self.chunks.range(..=address).next_back() {
+            Some((key, _)) => *key,
+            None => *self.chunks.keys().next().unwrap(),
+        };
+        let index = self.chunks.range(..key).count() as u32;
+        let next = if index + 1 < chunks_count {
+            let next_chunk = self.chunks.range(key..).nth(1).unwrap().1;
+            Some(( next_chunk.leaf().unwrap(), self.tree.proof(index + 1).unwrap() ))
+        } else {
+            None
+        };
+        AddressProof {
+            chunks_count,
+            chunk: self.chunks[&key].clone(),
+            chunk_proof: self.tree.proof(index).unwrap(),
+            next
+        }
+    }
+}
+
+fn index_root( chunks_count: u32, tree_root: Hash ) -> Hash {
+    let mut writer = HashWriter::new();
+    chunks_count.write(&mut writer).unwrap();
+    tree_root.write(&mut writer).unwrap();
+    writer.finalize()
+}
+
+// the depth of a tree of `count` leaves
+fn tree_depth( count: u32 ) -> usize {
+    if count < 2 { return 0 }
+    32 - (count - 1).leading_zeros() as usize
+}
+
+/// A chunk of the address index and its path in the chunks tree. Unless the chunk is
+/// the last one, the leaf of the next chunk bounds the chunk's range of addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AddressProof {
+    pub chunks_count: u32,
+    pub chunk: Chunk,
+    pub chunk_proof: MerkleProof,
+    pub next: Option<( ChunkLeaf, MerkleProof )>
+}
+
+impl AddressProof {
+
+    // whether the leaf is at the proof's index of the index with this root
+    fn verify_leaf( &self, leaf: &ChunkLeaf, proof: &MerkleProof, root: Hash ) -> bool {
+        proof.index < self.chunks_count
+            && proof.siblings.len() == tree_depth(self.chunks_count)
+            && index_root(self.chunks_count, proof.root(leaf.hash())) == root
+    }
+
+    /// The id of the address's unspent output under the index `root`,
+    /// none if the address has no balance.
+    pub fn verify( &self, address: Address, root: Hash ) -> Result<Option<TransactionInput>, ProtocolError> {
+        // an empty index
+        if self.chunks_count == 0 {
+            if self.chunk.0.is_empty() && index_root(0, HashTree::new(vec![]).root()) == root { return Ok(None) }
+            return Err(ProtocolError::InvalidAddressProof);
+        }
+
+        let leaf = self.chunk.leaf().ok_or(ProtocolError::InvalidAddressProof)?;
+        if !self.verify_leaf(&leaf, &self.chunk_proof, root) { return Err(ProtocolError::InvalidAddressProof) }
+        if !self.chunk.0.windows(2).all(|pair| pair[0].address < pair[1].address) { return Err(ProtocolError::InvalidAddressProof) }
+
+        if let Ok(position) = self.chunk.find(address) {
+            return Ok(Some(self.chunk.0[position].output_id));
+        }
+
+        // the address would be in this chunk
+        let index = self.chunk_proof.index;
+        if index > 0 && address < leaf.first { return Err(ProtocolError::InvalidAddressProof) }
+        match self.next {
+            Some(( ref next_leaf, ref next_proof )) => {
+                if next_proof.index != index + 1 || !self.verify_leaf(next_leaf, next_proof, root) { return Err(ProtocolError::InvalidAddressProof) }
+                if address >= next_leaf.first { return Err(ProtocolError::InvalidAddressProof) }
+            },
+            None => if index + 1 != self.chunks_count { return Err(ProtocolError::InvalidAddressProof) },
+        }
+        Ok(None)
+    }
+}
+
+impl Writeable for AddressIndexEntry {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.address.write(writer)?;
+        self.output_id.write(writer)
+    }
+}
+
+impl Readable for AddressIndexEntry {
+    fn read(reader: &mut dyn Reader) -> Result<AddressIndexEntry, Error>{
+        Ok(AddressIndexEntry {
+            address: Address::read(reader)?,
+            output_id: TransactionInput::read(reader)?
+        })
+    }
+}
+
+impl Writeable for Chunk {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        (self.0.len() as u32).write(writer)?;
+        for entry in &self.0 {
+            entry.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Readable for Chunk {
+    fn read(reader: &mut dyn Reader) -> Result<Chunk, Error>{
+        let count = u32::read(reader)?;
+        if count > MAX_CHUNK_ENTRIES { return Err(Error::new(ErrorKind::InvalidData, "Chunk too long")) }
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(AddressIndexEntry::read(reader)?);
+        }
+        Ok(Chunk(entries))
+    }
+}
+
+impl Writeable for ChunkLeaf {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.first.write(writer)?;
+        self.chunk_hash.write(writer)
+    }
+}
+
+impl Readable for ChunkLeaf {
+    fn read(reader: &mut dyn Reader) -> Result<ChunkLeaf, Error>{
+        Ok(ChunkLeaf {
+            first: Address::read(reader)?,
+            chunk_hash: Hash::read(reader)?
+        })
+    }
+}
+
+impl Writeable for AddressProof {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.chunks_count.write(writer)?;
+        self.chunk.write(writer)?;
+        self.chunk_proof.write(writer)?;
+        match self.next {
+            Some(( ref leaf, ref proof )) => {
+                1u8.write(writer)?;
+                leaf.write(writer)?;
+                proof.write(writer)
+            },
+            None => 0u8.write(writer),
+        }
+    }
+}
+
+impl Readable for AddressProof {
+    fn read(reader: &mut dyn Reader) -> Result<AddressProof, Error>{
+        let chunks_count = u32::read(reader)?;
+        let chunk = Chunk::read(reader)?;
+        let chunk_proof = MerkleProof::read(reader)?;
+        let next = match u8::read(reader)? {
+            0 => None,
+            _ => Some(( ChunkLeaf::read(reader)?, MerkleProof::read(reader)? )),
+        };
+        Ok(AddressProof{ chunks_count, chunk, chunk_proof, next })
+    }
+}
+
+/// A proof together with its address, as sent to peers.
+#[derive(Debug)]
+pub struct AddressOutputProof {
+    pub address: Address,
+    pub proof: AddressProof
+}
+
+impl Writeable for AddressOutputProof {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.address.write(writer)?;
+        self.proof.write(writer)
+    }
+}
+
+impl Readable for AddressOutputProof {
+    fn read(reader: &mut dyn Reader) -> Result<AddressOutputProof, Error>{
+        Ok(AddressOutputProof {
+            address: Address::read(reader)?,
+            proof: AddressProof::read(reader)?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_address() -> Address {
+        Address::new(*Hash::random().as_bytes())
+    }
+
+    fn output_id( index: u32 ) -> TransactionInput {
+        TransactionInput{ block_id: index, transaction_id: 0, output_id: 0 }
+    }
+
+    #[test]
+    fn updates() {
+        let mut index = AddressIndex::with_chunk_entries(4);
+        let mut addresses: Vec<Address> = Vec::new();
+        for round in 0..20u32 {
+            for i in 0..20 {
+                let address = random_address();
+                index.insert(address, output_id(round * 100 + i));
+                addresses.push(address);
+            }
+            // replace some outputs and remove others
+            for i in 0..5 {
+                index.insert(addresses[(round * 7 + i) as usize % addresses.len()], output_id(round * 100 + 50 + i));
+                let address = addresses.remove((round * 13 + i) as usize % addresses.len());
+                index.remove(address);
+            }
+            index.commit();
+        }
+
+        // the chunks depend only on the entries
+        let mut rebuilt = AddressIndex::with_chunk_entries(4);
+        for chunk in index.chunks.values().rev() {
+            for entry in chunk.0.iter().rev() {
+                rebuilt.insert(entry.address, entry.output_id);
+            }
+        }
+        rebuilt.commit();
+        assert_eq!(rebuilt.root(), index.root());
+        assert!(index.leaves.len() > 1);
+        assert_eq!(index.chunks.values().map(|chunk| chunk.0.len()).sum::<usize>(), addresses.len());
+
+        for chunk in index.chunks.values() {
+            let (last, rest) = chunk.0.split_last().unwrap();
+            assert!(rest.iter().all(|entry| !index.is_boundary(&entry.address)));
+            assert!(index.is_boundary(&last.address) || chunk.0.len() == index.max_chunk_entries() || chunk.0.last() == index.chunks.values().last().unwrap().0.last());
+        }
+    }
+
+    // addresses ground to share a prefix that is no boundary
+    fn ground_address() -> Address {
+        let mut bytes = *Hash::random().as_bytes();
+        bytes[..8].copy_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        Address::new(bytes)
+    }
+
+    #[test]
+    fn ground_addresses() {
+        let mut index = AddressIndex::with_chunk_entries(4);
+        let mut outputs = Vec::new();
+        for round in 0..10u32 {
+            let mut updates = Vec::new();
+            for i in 0..20 {
+                let address = if i % 10 == 0 { random_address() } else { ground_address() };
+                updates.push((address, Some(output_id(round * 100 + i))));
+                outputs.push((address, output_id(round * 100 + i)));
+            }
+            for i in 0..3 {
+                let (address, _) = outputs.remove((round * 13 + i) as usize % outputs.len());
+                updates.push((address, None));
+            }
+
+            let root = index.root_after(&updates);
+            for &(address, output_id) in &updates {
+                match output_id {
+                    Some(output_id) => index.insert(address, output_id),
+                    None => index.remove(address),
+                }
+            }
+            index.commit();
+            assert_eq!(root, index.root());
+        }
+
+        // the run of the ground addresses is split into chunks of bounded length
+        assert!(index.chunks.values().all(|chunk| chunk.0.len() <= index.max_chunk_entries()));
+        assert!(index.chunks.values().filter(|chunk| chunk.0.len() == index.max_chunk_entries()).count() > 5);
+
+        // the chunks depend only on the entries
+        let mut rebuilt = AddressIndex::with_chunk_entries(4);
+        for &(address, id) in outputs.iter().rev() {
+            rebuilt.insert(address, id);
+        }
+        rebuilt.commit();
+        assert_eq!(rebuilt.root(), index.root());
+
+        let root = index.root();
+        for &(address, id) in &outputs {
+            let proof = index.prove(address);
+            assert!(proof.chunk.0.len() <= index.max_chunk_entries());
+            assert_eq!(proof.verify(address, root).unwrap(), Some(id));
+        }
+        for _ in 0..20 {
+            let address = ground_address();
+            assert_eq!(index.prove(address).verify(address, root).unwrap(), None);
+        }
+
+        // removing every output empties the index
+        for &(address, _) in &outputs {
+            index.remove(address);
+        }
+        index.commit();
+        assert!(index.chunks.is_empty());
+        assert_eq!(index.root(), AddressIndex::with_chunk_entries(4).root());
+    }
+
+    #[test]
+    fn root_after_updates() {
+        let mut index = AddressIndex::with_chunk_entries(4);
+        let mut addresses: Vec<Address> = Vec::new();
+        for round in 0..20u32 {
+            // new addresses, replaced and removed outputs and an absent address
+            let mut updates = Vec::new();
+            for i in 0..10 {
+                let address = random_address();
+                updates.push((address, Some(output_id(round * 100 + i))));
+                addresses.push(address);
+            }
+            for i in 0..5 {
+                updates.push((addresses[(round * 7 + i) as usize % addresses.len()], Some(output_id(round * 100 + 50 + i))));
+                let address = addresses.remove((round * 13 + i) as usize % addresses.len());
+                updates.push((address, None));
+            }
+            updates.push((random_address(), None));
+
+            let root = index.root_after(&updates);
+            for &(address, output_id) in &updates {
+                match output_id {
+                    Some(output_id) => index.insert(address, output_id),
+                    None => index.remove(address),
+                }
+            }
+            index.commit();
+            assert_eq!(root, index.root());
+        }
+        assert!(index.leaves.len() > 1);
+    }
+
+    #[test]
+    fn proofs() {
+        let mut index = AddressIndex::with_chunk_entries(4);
+        let root = index.root();
+        let address = random_address();
+        assert_eq!(index.prove(address).verify(address, root).unwrap(), None);
+
+        let mut outputs = Vec::new();
+        for i in 0..100 {
+            let address = random_address();
+            index.insert(address, output_id(i));
+            outputs.push((address, output_id(i)));
+        }
+        index.commit();
+        let root = index.root();
+
+        for &(address, id) in &outputs {
+            assert_eq!(index.prove(address).verify(address, root).unwrap(), Some(id));
+        }
+        let first = *index.chunks.keys().next().unwrap();
+        let last = index.chunks.values().last().unwrap().0.last().unwrap().address;
+        let mut absent = vec![Address::new([0u8; 32]), Address::new([0xffu8; 32])];
+        absent.extend((0..100).map(|_| random_address()));
+        for address in absent {
+            let proof = index.prove(address);
+            assert_eq!(proof.verify(address, root).unwrap(), None);
+            if address < first { assert_eq!(proof.chunk_proof.index, 0) }
+            if address > last { assert!(proof.next.is_none()) }
+
+            let mut bytes = Vec::new();
+            proof.write(&mut bytes).unwrap();
+            assert_eq!(AddressProof::read(&mut &bytes[..]).unwrap(), proof);
+        }
+
+        // a proof that omits the address's entry
+        let (address, _) = outputs[0];
+        let mut proof = index.prove(address);
+        let position = proof.chunk.find(address).unwrap();
+        proof.chunk.0.remove(position);
+        assert!(proof.verify(address, root).is_err());
+
+        // a proof of another chunk
+        let proof = index.prove(Address::new([0u8; 32]));
+        let address = index.chunks.values().last().unwrap().0[0].address;
+        assert!(proof.verify(address, root).is_err());
+
+        // a proof that omits the next chunk
+        let address = random_address();
+        let mut proof = index.prove(Address::new([0u8; 32]));
+        proof.next = None;
+        assert!(proof.verify(address, root).is_err());
+
+        // a proof with a false chunks count
+        let mut proof = index.prove(Address::new([0xffu8; 32]));
+        proof.chunks_count -= 1;
+        assert!(proof.verify(Address::new([0xffu8; 32]), root).is_err());
+    }
+}
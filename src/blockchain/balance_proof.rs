@@ -0,0 +1,251 @@
+use blockchain::block::{ Block, BlockHeader };
+use blockchain::hash_tree::MerkleProof;
+use blockchain::state_tree::{ StateTree, StateTreeProof };
+use blockchain::transaction::{ Transaction, TransactionInput, TransactionOutput };
+use protocol::event::Error as ProtocolError;
+use utils::Hash;
+use utils::hash::Hashable;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::io::Error;
+
+/// Proves the aggregated balance of an address by the path from its latest
+/// output up through the block's transactions tree and the state tree.
+#[derive(Debug, Clone)]
+pub struct BalanceProof {
+    pub output_id: TransactionInput,
+    // the transaction containing the output
+    pub transaction: Transaction,
+    // from the transaction up to the header's transactions root
+    pub transaction_proof: MerkleProof,
+    pub header: BlockHeader,
+    // from the header's hash up to the state root
+    pub block_proof: StateTreeProof
+}
+
+impl BalanceProof {
+
+    /// Verifies the proof against a state root and returns the proven output.
+    pub fn verify( &self, state_root: Hash ) -> Result<TransactionOutput, ProtocolError> {
+        let output = match self.transaction.outputs.get(self.output_id.output_id as usize) {
+            Some(output) => *output,
+            None => return Err(ProtocolError::InvalidBalanceProof),
+        };
+
+        // the transaction is in the block
+        if self.transaction_proof.index != self.output_id.transaction_id { return Err(ProtocolError::InvalidBalanceProof) }
+        if !self.transaction_proof.verify(self.transaction.hash(), self.header.transactions_root) { return Err(ProtocolError::InvalidBalanceProof) }
+
+        // the block is in the chain
+        if self.block_proof.block_id != self.output_id.block_id { return Err(ProtocolError::InvalidBalanceProof) }
+        if self.block_proof.block_hash != self.header.hash() { return Err(ProtocolError::InvalidBalanceProof) }
+        if !self.block_proof.verify(state_root) { return Err(ProtocolError::InvalidBalanceProof) }
+
+        Ok(output)
+    }
+
+    /// Updates a proof verified at `state_root` to the state after the blocks of
+    /// `extension`. The proof is superseded if the address spent or received funds since.
+    pub fn refresh( &self, state_root: Hash, extension: &StateTreeExtension ) -> Result<RefreshedBalanceProof, ProtocolError> {
+        let output = self.verify(state_root)?;
+
+        // the extension starts at the head of the proof's state tree
+        let head_proof = &extension.head_proof;
+        if !head_proof.is_head() || !head_proof.verify(state_root) { return Err(ProtocolError::InvalidBalanceProof) }
+        if head_proof.block_id < self.output_id.block_id { return Err(ProtocolError::InvalidBalanceProof) }
+
+        let mut state_tree = StateTree::from_proofs(head_proof, &[&self.block_proof]);
+        for block in &extension.blocks {
+            // every block extends the previous state
+            if block.header.state != state_tree.root_hash { return Err(ProtocolError::StateMissMatch) }
+            if !block.verify_transactions_root() { return Err(ProtocolError::InvalidTransactionsRoot) }
+
+            if self.is_superseded_by(block, &output) {
+                return Ok(RefreshedBalanceProof::Superseded{ block_id: state_tree.head_id + 1 });
+            }
+            state_tree.insert(block.hash(), block.header.pow_level())?;
+        }
+
+        let mut proof = self.clone();
+        proof.block_proof = state_tree.prove(self.output_id.block_id).unwrap();
+        Ok(RefreshedBalanceProof::Refreshed{ proof: Box::new(proof), state_root: state_tree.root_hash })
+    }
+
+    // whether the block spends the output or aggregates a new output for its address
+    fn is_superseded_by( &self, block: &Block, output: &TransactionOutput ) -> bool {
+        for transaction in [&block.reward].iter().cloned().chain(block.transactions.iter()) {
+            if transaction.inputs.contains(&self.output_id) { return true }
+            if transaction.outputs.iter().any(|new_output| new_output.address == output.address) { return true }
+        }
+        false
+    }
+}
+
+pub enum RefreshedBalanceProof {
+    // the updated proof and the state root it is valid at
+    Refreshed{ proof: Box<BalanceProof>, state_root: Hash },
+    // the address has a newer output since the block at `block_id`
+    Superseded{ block_id: u32 }
+}
+
+/// The blocks appended to the state tree since its head was `head_proof.block_id`.
+#[derive(Debug)]
+pub struct StateTreeExtension {
+    // the proof of the old head at the old state root
+    pub head_proof: StateTreeProof,
+    pub blocks: Vec<Block>
+}
+
+impl StateTreeExtension {
+
+    /// Verifies that the blocks extend the state tree at `state_root` and returns
+    /// the root after them.
+    pub fn verify( &self, state_root: Hash ) -> Result<Hash, ProtocolError> {
+        if !self.head_proof.is_head() || !self.head_proof.verify(state_root) { return Err(ProtocolError::StateMissMatch) }
+
+        let mut state_tree = StateTree::from_proofs(&self.head_proof, &[]);
+        for block in &self.blocks {
+            if block.header.state != state_tree.root_hash { return Err(ProtocolError::StateMissMatch) }
+            if !block.verify_transactions_root() { return Err(ProtocolError::InvalidTransactionsRoot) }
+            state_tree.insert(block.hash(), block.header.pow_level())?;
+        }
+        Ok(state_tree.root_hash)
+    }
+}
+
+impl Writeable for StateTreeExtension {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.head_proof.write(writer)?;
+        let blocks_count = self.blocks.len() as u32;
+        blocks_count.write(writer)?;
+        for block in &self.blocks {
+            block.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Readable for StateTreeExtension {
+    fn read(reader: &mut dyn Reader) -> Result<StateTreeExtension, Error>{
+        let head_proof = StateTreeProof::read(reader)?;
+        let blocks_count = u32::read(reader)?;
+        let mut blocks = Vec::new();
+        for _ in 0..blocks_count {
+            blocks.push(Block::read(reader)?);
+        }
+        Ok(StateTreeExtension{ head_proof, blocks })
+    }
+}
+
+impl Writeable for BalanceProof {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.output_id.write(writer)?;
+        self.transaction.write(writer)?;
+        self.transaction_proof.write(writer)?;
+        self.header.write(writer)?;
+        self.block_proof.write(writer)
+    }
+}
+
+impl Readable for BalanceProof {
+    fn read(reader: &mut dyn Reader) -> Result<BalanceProof, Error>{
+        Ok(BalanceProof {
+            output_id: TransactionInput::read(reader)?,
+            transaction: Transaction::read(reader)?,
+            transaction_proof: MerkleProof::read(reader)?,
+            header: BlockHeader::read(reader)?,
+            block_proof: StateTreeProof::read(reader)?
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::block_tree::BlockStore;
+    use blockchain::blockchain::Blockchain;
+    use blockchain::transaction::Address;
+    use blockchain::test_helpers::{ test_params, genesis, next_block };
+
+    struct Blocks(Vec<Block>);
+
+    impl BlockStore for Blocks {
+        fn get_block(&self, block_id: u32) -> Option<&Block> {
+            self.0.get(block_id as usize)
+        }
+    }
+
+    #[test]
+    fn prove_balance() {
+        let mut blockchain = Blockchain::new(&genesis(), test_params());
+        let mut blocks = Blocks(vec![genesis()]);
+
+        for seed in 0..5u8 {
+            let address = Address::new([seed; 32]);
+            let reward = Transaction::new_coinbase(TransactionOutput::new(address, 10));
+            let block = next_block(&blockchain, reward, vec![], seed as u64);
+            blockchain.apply_block(&block).unwrap();
+            blocks.0.push(block);
+        }
+
+        let address = Address::new([2u8; 32]);
+        let proof = blockchain.balance_proof(address, &blocks).unwrap();
+        let output = proof.verify(blockchain.state_hash()).unwrap();
+        assert_eq!(output.address, address);
+        assert_eq!(output.balance, 10);
+
+        // wrong root
+        assert!(proof.verify(Hash::random()).is_err());
+
+        // tampered balance
+        let mut tampered = proof.clone();
+        tampered.transaction.outputs[0].balance = 1000;
+        assert!(tampered.verify(blockchain.state_hash()).is_err());
+    }
+
+    #[test]
+    fn refresh() {
+        let mut blockchain = Blockchain::new(&genesis(), test_params());
+        let mut blocks = Blocks(vec![genesis()]);
+        let alice = Address::new([1u8; 32]);
+
+        let mine = |blockchain: &mut Blockchain, blocks: &mut Blocks, address: Address, seed: u64| {
+            let mut reward = Transaction::new_coinbase(TransactionOutput::new(address, 10));
+            blockchain.validation_context().update_balances(&mut reward);
+            let block = next_block(blockchain, reward, vec![], seed);
+            blockchain.apply_block(&block).unwrap();
+            blocks.0.push(block);
+        };
+        mine(&mut blockchain, &mut blocks, alice, 1);
+        mine(&mut blockchain, &mut blocks, Address::new([2u8; 32]), 2);
+        let old_root = blockchain.state_hash();
+        let old_head_id = blockchain.block_count();
+        let proof = blockchain.balance_proof(alice, &blocks).unwrap();
+
+        for seed in 3..8u8 {
+            mine(&mut blockchain, &mut blocks, Address::new([seed; 32]), seed as u64);
+        }
+        let extension = blockchain.state_tree_extension(old_head_id, &blocks).unwrap();
+        assert_eq!(extension.verify(old_root).unwrap(), blockchain.state_hash());
+        match proof.refresh(old_root, &extension).unwrap() {
+            RefreshedBalanceProof::Refreshed{ proof, state_root } => {
+                assert_eq!(state_root, blockchain.state_hash());
+                assert_eq!(proof.verify(state_root).unwrap().balance, 10);
+            },
+            RefreshedBalanceProof::Superseded{ .. } => panic!("alice received nothing"),
+        }
+
+        // an extension starting at another head is rejected
+        let other = blockchain.state_tree_extension(old_head_id + 1, &blocks).unwrap();
+        assert!(proof.refresh(old_root, &other).is_err());
+        assert!(other.verify(old_root).is_err());
+
+        // alice receives a second reward
+        mine(&mut blockchain, &mut blocks, alice, 8);
+        let extension = blockchain.state_tree_extension(old_head_id, &blocks).unwrap();
+        match proof.refresh(old_root, &extension).unwrap() {
+            RefreshedBalanceProof::Superseded{ block_id } => assert_eq!(block_id, blockchain.block_count()),
+            RefreshedBalanceProof::Refreshed{ .. } => panic!("alice has a newer output"),
+        }
+    }
+}
@@ -1,54 +1,102 @@
 use protocol::event::EventResult;
 use protocol::event::Event;
-use utils::hash::Hashable;
-use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use utils::hash::{ Hashable, HashWriter };
+use utils::serializer::{ Reader, Readable, Writer, Writeable, LimitedReader, SizeWriter };
 
-use blockchain::transaction::Transaction;
+use blockchain::transaction::{ Transaction, MAX_OUTPUTS_COUNT };
+use blockchain::hash_tree::{ HashTree, MerkleProof };
 use std::io::{ Error };
 use std::fmt;
 use network::message::{ Message };
 use utils::Hash;
 use protocol::protocol::message_type;
+use protocol::protocol_config::ChainParams;
+use protocol::event::Error as ProtocolError;
+use pow::cuckoo::{ Cuckoo, Proof };
 
+// the serialized size of a block is at most 1 MB
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+
+// transaction indexes have 17 bits and the reward takes index zero
+pub const MAX_TRANSACTIONS_COUNT: usize = ( 1 << 17 ) - 1;
+
+#[derive(Clone)]
 pub struct BlockHeader {
     pub state: Hash,
+    pub transactions_root: Hash,
+    // the root of the address index after the block
+    pub address_index_root: Hash,
     pub timestamp: u32,
-    pub difficulty_target: u32
+    pub difficulty_target: u32,
+    pub nonce: u64,
+    pub proof: Proof
 }
 
 impl BlockHeader {
 
     pub fn new(state: Hash, timestamp: u32, difficulty_target: u32) -> BlockHeader {
-        BlockHeader{ state, timestamp, difficulty_target }
+        BlockHeader{ state, transactions_root: Hash::zeros(), address_index_root: Hash::zeros(), timestamp, difficulty_target, nonce: 0, proof: Proof::empty() }
+    }
+
+    // hash of all fields except the proof; it seeds the Cuckoo graph
+    pub fn pre_pow_hash(&self) -> Hash {
+        let mut writer = HashWriter::new();
+        self.write_pre_pow(&mut writer).unwrap();
+        writer.finalize()
+    }
+
+    fn write_pre_pow(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.state.write(writer)?;
+        self.transactions_root.write(writer)?;
+        self.address_index_root.write(writer)?;
+        self.timestamp.write(writer)?;
+        self.difficulty_target.write(writer)?;
+        self.nonce.write(writer)
     }
 
-    pub fn verify_proof_of_work(&self) -> EventResult {
-        // Todo implementation
+    pub fn verify_proof_of_work(&self, params: &ChainParams) -> EventResult {
+        // verify the cycle is in the graph of this header
+        let cuckoo = Cuckoo::new(self.pre_pow_hash(), params.edge_bits, params.proof_size);
+        if !cuckoo.verify(&self.proof) { return Err( ProtocolError::InvalidProofOfWork ) }
+        // verify the cycle meets the difficulty target
+        if self.proof.difficulty() < self.difficulty_target as u64 { return Err( ProtocolError::InvalidProofOfWork ) }
         Ok(Event::Nothing)
     }
 
+    // how many times the proof's difficulty doubles the target, the level of a superblock
+    pub fn pow_level(&self) -> u8 {
+        let target = ::std::cmp::max(self.difficulty_target as u64, 1);
+        let ratio = self.proof.difficulty() / target;
+        if ratio == 0 { return 0 }
+        63 - ratio.leading_zeros() as u8
+    }
+
 }
 
 impl Writeable for BlockHeader{
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
-        self.state.write(writer)?;
-        self.timestamp.write(writer)?;
-        self.difficulty_target.write(writer)
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.write_pre_pow(writer)?;
+        self.proof.write(writer)
     }
 }
 
 impl Readable for BlockHeader {
-    fn read(reader: &mut Reader) -> Result<BlockHeader, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<BlockHeader, Error>{
         Ok(BlockHeader{
             state: Hash::read(reader)?,
+            transactions_root: Hash::read(reader)?,
+            address_index_root: Hash::read(reader)?,
             timestamp: u32::read(reader)?,
-            difficulty_target: u32::read(reader)?
+            difficulty_target: u32::read(reader)?,
+            nonce: u64::read(reader)?,
+            proof: Proof::read(reader)?
         })
     }
 } 
 
 
 // #[derive(Debug)]
+#[derive(Clone)]
 pub struct Block {
     pub header: BlockHeader,
     pub reward: Transaction,
@@ -57,17 +105,58 @@ pub struct Block {
 
 impl Block {
     pub fn new( header: BlockHeader, reward : Transaction ) -> Block{
-        Block {
+        let mut block = Block {
             header,
             reward : reward,
             transactions : vec![]
-        }
+        };
+        block.update_transactions_root();
+        block
     }
 
+    // call `update_transactions_root` after adding transactions
     pub fn add_transaction(&mut self, transaction:Transaction){
         self.transactions.push(transaction);
     }
 
+    // the Merkle tree over the reward and all transactions, indexed by transaction id
+    pub fn transactions_tree(&self) -> HashTree {
+        let mut leaves = vec![ self.reward.hash() ];
+        for transaction in &self.transactions {
+            leaves.push(transaction.hash());
+        }
+        HashTree::new(leaves)
+    }
+
+    pub fn update_transactions_root(&mut self) {
+        self.header.transactions_root = self.transactions_tree().root();
+    }
+
+    pub fn verify_transactions_root(&self) -> bool {
+        self.header.transactions_root == self.transactions_tree().root()
+    }
+
+    // the inclusion proof of a transaction in the block's transactions tree
+    pub fn transaction_proof(&self, transaction_id: u32) -> Option<MerkleProof> {
+        self.transactions_tree().proof(transaction_id)
+    }
+
+    // the size of the serialized block in bytes
+    pub fn size(&self) -> usize {
+        let mut writer = SizeWriter::new();
+        self.write(&mut writer).unwrap();
+        writer.size
+    }
+
+    pub fn verify_limits(&self) -> EventResult {
+        if self.transactions.len() > MAX_TRANSACTIONS_COUNT { return Err(ProtocolError::TooManyTransactions) }
+        for transaction in [&self.reward].iter().cloned().chain(self.transactions.iter()) {
+            if transaction.outputs.len() > MAX_OUTPUTS_COUNT { return Err(ProtocolError::TooManyOutputs) }
+        }
+        if self.size() > MAX_BLOCK_SIZE { return Err(ProtocolError::BlockTooLarge) }
+        Ok(Event::Nothing)
+    }
+
      pub fn to_message(self) -> Message<Block> {
         Message::new( message_type::BLOCK, self )
     }
@@ -75,7 +164,7 @@ impl Block {
 
 
 impl Writeable for Block {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         
         // write header
         self.header.write(writer)?;
@@ -97,35 +186,51 @@ impl Writeable for Block {
 } 
 
 impl Readable for Block {
-    fn read(reader: &mut Reader) -> Result<Block, Error>{
-        // read header
-        let header = BlockHeader::read(reader)?;
+    fn read(reader: &mut dyn Reader) -> Result<Block, Error>{
+        let mut reader = LimitedReader::new(reader, MAX_BLOCK_SIZE);
+        match read_block(&mut reader) {
+            Err(_) if reader.exceeded() => Err(ProtocolError::BlockTooLarge.into_io()),
+            result => result,
+        }
+    }
+}
 
-        // read reward transaction
-        let reward = Transaction::read(reader)?;
+fn read_block(reader: &mut dyn Reader) -> Result<Block, Error>{
+    // read header
+    let header = BlockHeader::read(reader)?;
 
-        // read transactions_count
-        let transactions_count: u32 = u32::read(reader)?;
-        
-        // read all transactions  
-        let mut transactions = Vec::new();
-        for _ in 0..transactions_count {
-            transactions.push(Transaction::read(reader)?);
-        }
-        
-        Ok(Block{
-            header,
-            reward,
-            transactions
-        })
+    // read reward transaction
+    let reward = Transaction::read(reader)?;
+
+    // read transactions_count
+    let transactions_count: u32 = u32::read(reader)?;
+    if transactions_count as usize > MAX_TRANSACTIONS_COUNT { return Err(ProtocolError::TooManyTransactions.into_io()) }
+    
+    // read all transactions  
+    let mut transactions = Vec::new();
+    for _ in 0..transactions_count {
+        transactions.push(Transaction::read(reader)?);
     }
+    
+    Ok(Block{
+        header,
+        reward,
+        transactions
+    })
 }
 
-impl Hashable for Block {}
+impl Hashable for BlockHeader {}
+
+// the header commits to all transactions, so a block is identified by its header
+impl Hashable for Block {
+    fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+}
 
 impl fmt::Debug for BlockHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "BlockHeader\n\tstate: {:?}\n\ttimestamp: {:?}", self.state,  self.timestamp)
+        write!(f, "BlockHeader\n\tstate: {:?}\n\ttimestamp: {:?}\n\tnonce: {:?}", self.state,  self.timestamp, self.nonce)
     }
 }
 
@@ -134,3 +239,61 @@ impl fmt::Debug for Block {
         write!(f, "{:?}\n\tTransactions count: {}", self.header, self.transactions.len())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::transaction::{ Address, TransactionInput, TransactionOutput };
+    use protocol::event::Error as ProtocolError;
+
+    fn block_with( transactions: Vec<Transaction> ) -> Block {
+        let reward = Transaction::new(vec![TransactionInput::new_coinbase()], vec![]);
+        let mut block = Block::new(BlockHeader::new(Hash::zeros(), 0, 1), reward);
+        for transaction in transactions {
+            block.add_transaction(transaction);
+        }
+        block
+    }
+
+    fn read( bytes: &[u8] ) -> Result<Block, ProtocolError> {
+        Ok(Block::read(&mut &bytes[..])?)
+    }
+
+    #[test]
+    fn limits() {
+        let output = TransactionOutput::new(Address::new([1u8; 32]), 1);
+        let input = TransactionInput::new_coinbase();
+
+        // a valid block round trips
+        let block = block_with(vec![Transaction::new(vec![input], vec![output; MAX_OUTPUTS_COUNT])]);
+        assert!(block.verify_limits().is_ok());
+        let mut bytes = Vec::new();
+        block.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), block.size());
+        assert_eq!(read(&bytes).unwrap().hash(), block.hash());
+
+        // too many outputs
+        let block = block_with(vec![Transaction::new(vec![input], vec![output; MAX_OUTPUTS_COUNT + 1])]);
+        assert!(matches!(block.verify_limits(), Err(ProtocolError::TooManyOutputs)));
+        let mut bytes = Vec::new();
+        block.write(&mut bytes).unwrap();
+        assert!(matches!(read(&bytes), Err(ProtocolError::TooManyOutputs)));
+
+        // too large
+        let inputs = vec![input; MAX_BLOCK_SIZE / 8 / 2];
+        let block = block_with(vec![Transaction::new(inputs.clone(), vec![]), Transaction::new(inputs, vec![])]);
+        assert!(matches!(block.verify_limits(), Err(ProtocolError::BlockTooLarge)));
+        let mut bytes = Vec::new();
+        block.write(&mut bytes).unwrap();
+        assert!(matches!(read(&bytes), Err(ProtocolError::BlockTooLarge)));
+
+        // a transactions count beyond the 17 bit index is rejected before reading them
+        let mut bytes = Vec::new();
+        block_with(vec![]).write(&mut bytes).unwrap();
+        let length = bytes.len();
+        bytes.truncate(length - 4);
+        (MAX_TRANSACTIONS_COUNT as u32 + 1).write(&mut bytes).unwrap();
+        assert!(matches!(read(&bytes), Err(ProtocolError::TooManyTransactions)));
+    }
+}
@@ -0,0 +1,500 @@
+use blockchain::block::Block;
+use blockchain::blockchain::Blockchain;
+use blockchain::difficulty::{ DifficultyData, next_difficulty };
+use blockchain::state_tree::StateTree;
+use blockchain::undo::MAX_REORG_DEPTH;
+use protocol::protocol_config::ChainParams;
+use protocol::event::Error;
+use utils::Hash;
+use utils::hash::Hashable;
+use utils::serializer::{ Readable, DiscReader };
+use std::collections::{ BTreeMap, HashMap };
+
+/// The blocks disconnected from and connected to the main chain by a block,
+/// each in the order they were disconnected or connected.
+pub struct ChainUpdate {
+    pub disconnected: Vec<Block>,
+    pub connected: Vec<Block>
+}
+
+impl ChainUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.disconnected.is_empty() && self.connected.is_empty()
+    }
+}
+
+/// Implemented by everything that keeps state derived from the main chain.
+pub trait ChainListener {
+    fn on_chain_update(&mut self, update: &ChainUpdate, blockchain: &Blockchain);
+}
+
+/// Looks up the blocks of the main chain.
+pub trait BlockStore {
+    fn get_block(&self, block_id: u32) -> Option<&Block>;
+}
+
+struct BlockNode {
+    block: Block,
+    parent: Hash,
+    height: u32,
+    cumulative_work: u64,
+    // the state root after applying this block
+    state_root: Hash
+}
+
+/// All known blocks including side branches. The main chain is the branch
+/// with the most cumulative work, it is the one applied to the `Blockchain`.
+/// Side branches forking more than `MAX_REORG_DEPTH` blocks below the tip are pruned.
+pub struct BlockTree {
+    nodes: HashMap<Hash, BlockNode>,
+    // block hash by the state root after applying the block
+    by_state: HashMap<Hash, Hash>,
+    // block hashes of the main chain by height
+    main_chain: Vec<Hash>,
+    children: HashMap<Hash, Vec<Hash>>,
+    // block hashes of the side branches by height
+    side_nodes: BTreeMap<u32, Vec<Hash>>
+}
+
+impl BlockTree {
+
+    #[cfg(test)]
+    pub fn new( genesis_block: Block, blockchain: &Blockchain ) -> BlockTree {
+        let hash = genesis_block.hash();
+        let mut block_tree = BlockTree {
+            nodes: HashMap::new(),
+            by_state: HashMap::new(),
+            main_chain: vec![hash],
+            children: HashMap::new(),
+            side_nodes: BTreeMap::new()
+        };
+        block_tree.insert_node(hash, BlockNode {
+            block: genesis_block,
+            parent: Hash::zeros(),
+            height: 0,
+            cumulative_work: 0,
+            state_root: blockchain.state_hash()
+        });
+        block_tree
+    }
+
+    /// Rebuilds the main chain of a blockchain opened from disk out of the archived blocks.
+    /// Blocks are archived after they are applied, so the blockchain is reverted
+    /// to the last archived block if a crash happened in between.
+    pub fn load( genesis_block: Block, blockchain: &mut Blockchain, archive_path: &str ) -> Result<BlockTree, Error> {
+        let genesis_hash = genesis_block.hash();
+        // an in-memory tree, its inserts cannot fail
+        let mut state_tree = StateTree::new(genesis_hash);
+        let mut block_tree = BlockTree {
+            nodes: HashMap::new(),
+            by_state: HashMap::new(),
+            main_chain: vec![genesis_hash],
+            children: HashMap::new(),
+            side_nodes: BTreeMap::new()
+        };
+        block_tree.insert_node(genesis_hash, BlockNode {
+            block: genesis_block,
+            parent: Hash::zeros(),
+            height: 0,
+            cumulative_work: 0,
+            state_root: state_tree.root_hash
+        });
+
+        for block_id in 1 .. blockchain.block_count() + 1 {
+            let block = match DiscReader::block_reader(archive_path, block_id) {
+                Ok(mut reader) => Block::read(&mut reader)?,
+                Err(_) => break,
+            };
+            let hash = block.hash();
+            let parent = block_tree.tip();
+            if block.header.state != state_tree.root_hash { return Err(Error::StateMissMatch) }
+            state_tree.insert(hash, block.header.pow_level()).unwrap();
+            let state_root = state_tree.root_hash;
+            block_tree.push_node(hash, parent, block, state_root);
+            block_tree.main_chain.push(hash);
+        }
+
+        while blockchain.block_count() as usize >= block_tree.main_chain.len() {
+            blockchain.revert_block()?;
+        }
+        if blockchain.state_hash() != state_tree.root_hash { return Err(Error::StateMissMatch) }
+        Ok(block_tree)
+    }
+
+    fn insert_node( &mut self, hash: Hash, node: BlockNode ) {
+        self.by_state.insert(node.state_root, hash);
+        self.children.entry(node.parent).or_default().push(hash);
+        self.nodes.insert(hash, node);
+    }
+
+    pub fn tip( &self ) -> Hash {
+        self.main_chain[self.main_chain.len() - 1]
+    }
+
+    fn tip_height( &self ) -> u32 {
+        self.main_chain.len() as u32 - 1
+    }
+
+    // the height of the main chain's block after which the state tree had this root
+    pub fn main_height_of_state( &self, state_root: &Hash ) -> Option<u32> {
+        let hash = self.by_state.get(state_root)?;
+        let height = self.nodes[hash].height;
+        if self.main_chain.get(height as usize) != Some(hash) { return None }
+        Some(height)
+    }
+
+    pub fn contains( &self, hash: &Hash ) -> bool {
+        self.nodes.contains_key(hash)
+    }
+
+    /// Adds a block to the tree. A block extending the main chain is verified
+    /// and applied right away. A block on a side branch is only checked for its
+    /// proof of work and difficulty, the branch is verified once it has more work than the main chain.
+    pub fn add_block( &mut self, block: Block, blockchain: &mut Blockchain ) -> Result<ChainUpdate, Error> {
+        let hash = block.hash();
+        if self.contains(&hash) {
+            return Ok(ChainUpdate{ disconnected: vec![], connected: vec![] });
+        }
+
+        // find the parent by the state the block extends
+        let parent = match self.by_state.get(&block.header.state) {
+            Some(parent) => *parent,
+            None => return Err(Error::StateMissMatch),
+        };
+
+        // extend the main chain
+        if parent == self.tip() {
+            blockchain.apply_block(&block)?;
+            self.push_node(hash, parent, block.clone(), blockchain.state_hash());
+            self.main_chain.push(hash);
+            self.prune_side_branches();
+            return Ok(ChainUpdate{ disconnected: vec![], connected: vec![block] });
+        }
+
+        // extend a side branch
+        let fork_height = self.nodes[&self.fork_point(parent)].height;
+        if fork_height + MAX_REORG_DEPTH < self.tip_height() { return Err(Error::ForkTooDeep) }
+        block.header.verify_proof_of_work(&blockchain.params)?;
+        if block.header.difficulty_target != self.branch_difficulty_target(parent, &blockchain.params) {
+            return Err(Error::InvalidDifficulty);
+        }
+        let state_root = self.branch_state_root(parent, &block, blockchain)?;
+        self.push_node(hash, parent, block, state_root);
+        self.insert_side_node(hash);
+
+        if self.nodes[&hash].cumulative_work <= self.nodes[&self.tip()].cumulative_work {
+            return Ok(ChainUpdate{ disconnected: vec![], connected: vec![] });
+        }
+        let update = self.reorganize(hash, blockchain)?;
+        self.prune_side_branches();
+        Ok(update)
+    }
+
+    fn push_node( &mut self, hash: Hash, parent: Hash, block: Block, state_root: Hash ) {
+        let (height, cumulative_work) = {
+            let parent_node = &self.nodes[&parent];
+            (parent_node.height + 1, parent_node.cumulative_work + block.header.difficulty_target as u64)
+        };
+        self.insert_node(hash, BlockNode{ block, parent, height, cumulative_work, state_root });
+    }
+
+    // the last block of the main chain on the branch ending in `hash`
+    fn fork_point( &self, hash: Hash ) -> Hash {
+        let mut curr = hash;
+        while !self.is_main_chain(&curr) {
+            curr = self.nodes[&curr].parent;
+        }
+        curr
+    }
+
+    // the state root after appending `block` to the branch ending in `parent`,
+    // derived from the main chain's state at the fork point
+    fn branch_state_root( &self, parent: Hash, block: &Block, blockchain: &Blockchain ) -> Result<Hash, Error> {
+        let mut side_blocks = Vec::new();
+        let mut curr = parent;
+        while !self.is_main_chain(&curr) {
+            side_blocks.push(curr);
+            curr = self.nodes[&curr].parent;
+        }
+        let head_proof = blockchain.head_proof_at(self.nodes[&curr].height).ok_or(Error::StateMissMatch)?;
+
+        let mut state_tree = StateTree::from_proofs(&head_proof, &[]);
+        for block_hash in side_blocks.iter().rev() {
+            state_tree.insert(*block_hash, self.nodes[block_hash].block.header.pow_level())?;
+        }
+        state_tree.insert(block.hash(), block.header.pow_level())?;
+        Ok(state_tree.root_hash)
+    }
+
+    // the difficulty target of the block following `parent` on its branch
+    fn branch_difficulty_target( &self, parent: Hash, params: &ChainParams ) -> u32 {
+        // the retargeting depends on the last `retarget_window` blocks and the one before
+        let mut history = Vec::new();
+        let mut curr = parent;
+        while curr != Hash::zeros() && history.len() <= params.retarget_window as usize {
+            let header = &self.nodes[&curr].block.header;
+            history.push(DifficultyData{ timestamp: header.timestamp, difficulty_target: header.difficulty_target });
+            curr = self.nodes[&curr].parent;
+        }
+        history.reverse();
+        next_difficulty(&history, params)
+    }
+
+    fn is_main_chain( &self, hash: &Hash ) -> bool {
+        let height = self.nodes[hash].height as usize;
+        height < self.main_chain.len() && self.main_chain[height] == *hash
+    }
+
+    // switches the main chain to the branch ending in `new_tip`
+    fn reorganize( &mut self, new_tip: Hash, blockchain: &mut Blockchain ) -> Result<ChainUpdate, Error> {
+        // collect the new branch down to the fork point
+        let mut branch = Vec::new();
+        let mut curr = new_tip;
+        while !self.is_main_chain(&curr) {
+            branch.push(curr);
+            curr = self.nodes[&curr].parent;
+        }
+        branch.reverse();
+        let fork_height = self.nodes[&curr].height;
+
+        // roll back the main chain to the fork point
+        let disconnected: Vec<Hash> = self.main_chain.split_off(fork_height as usize + 1);
+        while blockchain.block_count() > fork_height {
+            blockchain.revert_block()?;
+        }
+
+        // replay the new branch
+        for hash in &branch {
+            if let Err(err) = blockchain.apply_block(&self.nodes[hash].block) {
+                // the branch is invalid, restore the previous main chain
+                self.remove_branch(*hash);
+                self.main_chain.truncate(fork_height as usize + 1);
+                while blockchain.block_count() > fork_height {
+                    blockchain.revert_block()?;
+                }
+                for hash in &disconnected {
+                    blockchain.apply_block(&self.nodes[hash].block)?;
+                }
+                self.main_chain.extend(disconnected);
+                return Err(err);
+            }
+            self.main_chain.push(*hash);
+        }
+
+        // the blocks switch their branches
+        for hash in &branch {
+            self.remove_side_node(hash);
+        }
+        for hash in &disconnected {
+            self.insert_side_node(*hash);
+        }
+
+        Ok(ChainUpdate {
+            disconnected: disconnected.iter().rev().map(|hash| self.nodes[hash].block.clone()).collect(),
+            connected: branch.iter().map(|hash| self.nodes[hash].block.clone()).collect()
+        })
+    }
+
+    fn insert_side_node( &mut self, hash: Hash ) {
+        let height = self.nodes[&hash].height;
+        self.side_nodes.entry(height).or_default().push(hash);
+    }
+
+    fn remove_side_node( &mut self, hash: &Hash ) {
+        let height = self.nodes[hash].height;
+        let is_empty = match self.side_nodes.get_mut(&height) {
+            Some(hashes) => {
+                hashes.retain(|side_hash| side_hash != hash);
+                hashes.is_empty()
+            },
+            None => false,
+        };
+        if is_empty {
+            self.side_nodes.remove(&height);
+        }
+    }
+
+    // removes the side branches which fork too far below the tip to be reorganized to
+    fn prune_side_branches( &mut self ) {
+        loop {
+            // the lowest side block is the first of its branch, its parent is the fork point
+            let hash = match self.side_nodes.iter().next() {
+                Some((height, hashes)) => {
+                    if height - 1 + MAX_REORG_DEPTH >= self.tip_height() { return }
+                    hashes[0]
+                },
+                None => return,
+            };
+            self.remove_branch(hash);
+        }
+    }
+
+    // removes a block and all its descendants
+    fn remove_branch( &mut self, hash: Hash ) {
+        let mut removed = vec![hash];
+        while let Some(hash) = removed.pop() {
+            if let Some(children) = self.children.remove(&hash) {
+                removed.extend(children);
+            }
+            self.remove_side_node(&hash);
+            if let Some(node) = self.nodes.remove(&hash) {
+                self.by_state.remove(&node.state_root);
+                if let Some(siblings) = self.children.get_mut(&node.parent) {
+                    siblings.retain(|sibling| *sibling != hash);
+                }
+            }
+        }
+    }
+}
+
+impl BlockStore for BlockTree {
+    fn get_block(&self, block_id: u32) -> Option<&Block> {
+        let hash = self.main_chain.get(block_id as usize)?;
+        Some(&self.nodes[hash].block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::transaction::{ Transaction, TransactionInput };
+    use blockchain::test_helpers::{ test_params, genesis, mine, next_block };
+
+    fn empty_reward() -> Transaction {
+        Transaction::new(vec![TransactionInput::new_coinbase()], vec![])
+    }
+
+    #[test]
+    fn reorganize() {
+        let params = test_params();
+        let mut blockchain = Blockchain::new(&genesis(), params.clone());
+        let mut block_tree = BlockTree::new(genesis(), &blockchain);
+
+        // main chain of two blocks
+        let a1 = next_block(&blockchain, empty_reward(), vec![], 1);
+        block_tree.add_block(a1.clone(), &mut blockchain).unwrap();
+        let a2 = next_block(&blockchain, empty_reward(), vec![], 1);
+        block_tree.add_block(a2.clone(), &mut blockchain).unwrap();
+        let state_a = blockchain.state_hash();
+
+        // a competing branch of three blocks
+        let mut branch_chain = Blockchain::new(&genesis(), params.clone());
+        let mut branch = Vec::new();
+        for _ in 0..3 {
+            let block = next_block(&branch_chain, empty_reward(), vec![], 2);
+            branch_chain.apply_block(&block).unwrap();
+            branch.push(block);
+        }
+
+        // the first two blocks do not have more work
+        assert!(block_tree.add_block(branch[0].clone(), &mut blockchain).unwrap().is_empty());
+        assert!(block_tree.add_block(branch[1].clone(), &mut blockchain).unwrap().is_empty());
+        assert_eq!(blockchain.state_hash(), state_a);
+
+        // the third block switches the main chain
+        let update = block_tree.add_block(branch[2].clone(), &mut blockchain).unwrap();
+        assert_eq!(update.disconnected.len(), 2);
+        assert_eq!(update.disconnected[0].hash(), a2.hash());
+        assert_eq!(update.disconnected[1].hash(), a1.hash());
+        assert_eq!(update.connected.len(), 3);
+        assert_eq!(blockchain.state_hash(), branch_chain.state_hash());
+        assert_eq!(block_tree.tip(), branch[2].hash());
+
+        // only the states of the main chain have a height
+        assert_eq!(block_tree.main_height_of_state(&blockchain.state_hash()), Some(3));
+        assert_eq!(block_tree.main_height_of_state(&branch[2].header.state), Some(2));
+        assert_eq!(block_tree.main_height_of_state(&state_a), None);
+    }
+
+    #[test]
+    fn side_branches() {
+        let params = test_params();
+        let mut blockchain = Blockchain::new(&genesis(), params.clone());
+        let mut block_tree = BlockTree::new(genesis(), &blockchain);
+        let mut branch_chain = Blockchain::new(&genesis(), params.clone());
+        let a1 = next_block(&blockchain, empty_reward(), vec![], 1);
+        block_tree.add_block(a1.clone(), &mut blockchain).unwrap();
+        branch_chain.apply_block(&a1).unwrap();
+
+        // a side branch forking after the first block
+        let b2 = next_block(&branch_chain, empty_reward(), vec![], 2);
+        branch_chain.apply_block(&b2).unwrap();
+        let b3 = next_block(&branch_chain, empty_reward(), vec![], 2);
+        let a2 = next_block(&blockchain, empty_reward(), vec![], 1);
+        block_tree.add_block(a2, &mut blockchain).unwrap();
+        assert!(block_tree.add_block(b2.clone(), &mut blockchain).unwrap().is_empty());
+
+        // the difficulty must follow the branch's retargeting
+        let mut cheap = b3.clone();
+        cheap.header.difficulty_target -= 1;
+        mine(&mut cheap, 3, &params);
+        assert!(matches!(block_tree.add_block(cheap, &mut blockchain), Err(Error::InvalidDifficulty)));
+
+        // the branch's state is derived from the fork point
+        let update = block_tree.add_block(b3.clone(), &mut blockchain).unwrap();
+        assert_eq!(update.connected.len(), 2);
+        assert_eq!(blockchain.state_hash(), { branch_chain.apply_block(&b3).unwrap(); branch_chain.state_hash() });
+
+        // branches forking too far below the tip are pruned
+        let mut fork_chain = Blockchain::new(&genesis(), params.clone());
+        fork_chain.apply_block(&a1).unwrap();
+        let fork = next_block(&fork_chain, empty_reward(), vec![], 5);
+        for _ in 0..MAX_REORG_DEPTH {
+            let block = next_block(&blockchain, empty_reward(), vec![], 4);
+            block_tree.add_block(block, &mut blockchain).unwrap();
+        }
+        assert!(!block_tree.contains(&update.disconnected[0].hash()));
+        assert!(block_tree.side_nodes.is_empty());
+        assert!(matches!(block_tree.add_block(fork, &mut blockchain), Err(Error::ForkTooDeep)));
+        assert_eq!(block_tree.nodes.len(), block_tree.main_chain.len());
+    }
+
+    #[test]
+    fn reject_unknown_parent() {
+        let params = test_params();
+        let mut blockchain = Blockchain::new(&genesis(), params.clone());
+        let mut block_tree = BlockTree::new(genesis(), &blockchain);
+
+        let mut block = next_block(&blockchain, empty_reward(), vec![], 1);
+        block.header.state = Hash::random();
+        assert!(block_tree.add_block(block, &mut blockchain).is_err());
+    }
+
+    #[test]
+    fn load() {
+        use utils::serializer::{ Writeable, DiscWriter };
+        use std::{ env, fs };
+
+        let path = env::temp_dir().join(format!("treecoin-block-tree-{}", Hash::random().to_hex()));
+        let archive_path = format!("{}/archive/", path.to_str().unwrap());
+        fs::create_dir_all(&archive_path).unwrap();
+        let open = || {
+            let mut blockchain = Blockchain::open(&genesis(), test_params(), path.join("chain")).unwrap();
+            blockchain.set_archive_path(archive_path.clone());
+            blockchain
+        };
+
+        let tip = {
+            let mut blockchain = open();
+            let mut block_tree = BlockTree::load(genesis(), &mut blockchain, &archive_path).unwrap();
+            for seed in 0..3 {
+                let block = next_block(&blockchain, empty_reward(), vec![], seed);
+                block_tree.add_block(block.clone(), &mut blockchain).unwrap();
+                block.write(&mut DiscWriter::block_writer(&archive_path, blockchain.block_count())).unwrap();
+            }
+            // a crash before the next block is archived
+            let block = next_block(&blockchain, empty_reward(), vec![], 3);
+            block_tree.add_block(block, &mut blockchain).unwrap();
+            block_tree.main_chain[3]
+        };
+
+        let mut blockchain = open();
+        let mut block_tree = BlockTree::load(genesis(), &mut blockchain, &archive_path).unwrap();
+        assert_eq!(blockchain.block_count(), 3);
+        assert_eq!(block_tree.tip(), tip);
+        assert!(block_tree.get_block(4).is_none());
+        let block = next_block(&blockchain, empty_reward(), vec![], 4);
+        assert_eq!(block_tree.add_block(block, &mut blockchain).unwrap().connected.len(), 1);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -2,9 +2,9 @@ use blockchain::transaction::Value;
 use blockchain::transaction::Address;
 use blockchain::block::BlockHeader;
 use blockchain::transaction::TransactionInput;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use blockchain::transaction::Transaction;
-use blockchain::state_tree::StateTree;
+use blockchain::state_tree::{ StateTree, StateTreeProof };
 use protocol::event::EventResult;
 use protocol::event::Event;
 use protocol::event::Error;
@@ -12,129 +12,291 @@ use utils::Hash;
 use utils::hash::Hashable;
 use blockchain::block::Block;
 use blockchain::transaction::TransactionOutput;
+use protocol::protocol_config::ChainParams;
+use blockchain::difficulty::{ DifficultyData, next_difficulty, median_time_past };
+use blockchain::emission::block_reward;
+use blockchain::undo::UndoJournal;
+use blockchain::validation::{ BlockValidationContext, BlockChanges };
+use blockchain::balance_proof::{ BalanceProof, StateTreeExtension };
+use blockchain::block_tree::BlockStore;
+use blockchain::address_index::{ AddressIndex, AddressIndexEntry, AddressProof };
+use blockchain::chain_proof::ChainProof;
+use blockchain::chainstate::{ ChainStateStore, ChainStateUpdate };
+use blockchain::state_tree_store::FileStore;
+use utils::clock::{ Clock, SystemClock };
+use std::path::Path;
 
 pub struct Blockchain {
     pub unspent_outputs: UnspentOutputs,
-    pub difficulty_target : u32,
-    state_tree : StateTree
+    pub params : ChainParams,
+    difficulty_history : Vec<DifficultyData>,
+    undo_journal : UndoJournal,
+    state_tree : StateTree,
+    // bounds the timestamps of new blocks
+    clock : Box<dyn Clock>,
+    // persists the unspent outputs and metadata of every applied block
+    chainstate : Option<ChainStateStore>
 }
 
 impl Blockchain {
 
-    pub fn new( genesis_block: &Block ) -> Blockchain {
+    pub fn new( genesis_block: &Block, params: ChainParams ) -> Blockchain {
         Blockchain {
             state_tree : StateTree::new(genesis_block.hash()),
-            difficulty_target : genesis_block.header.difficulty_target,
-            unspent_outputs : UnspentOutputs(HashMap::new())
+            difficulty_history : vec![ difficulty_data(&genesis_block.header) ],
+            undo_journal : UndoJournal::new(),
+            unspent_outputs : UnspentOutputs::new(),
+            params,
+            clock : Box::new(SystemClock),
+            chainstate : None
         }
     }
+
+    /// Opens the blockchain persisted in the directory `path`, or starts
+    /// a new one at the genesis block. The blocks applied since are persisted.
+    pub fn open<P: AsRef<Path>>( genesis_block: &Block, params: ChainParams, path: P ) -> Result<Blockchain, Error> {
+        let path = path.as_ref();
+        let mut blockchain = Blockchain::new(genesis_block, params);
+        let store = FileStore::open(path.join("state_tree"))?;
+        blockchain.state_tree = StateTree::open(Box::new(store), genesis_block.hash())?;
+        let (mut chainstate, state) = ChainStateStore::open(path.join("chainstate"))?;
+
+        match state {
+            Some(state) => {
+                // the state tree is committed before the chainstate, a crash in between leaves it one block ahead
+                if blockchain.state_tree.head_id == state.tip + 1 {
+                    blockchain.state_tree.revert()?;
+                }
+                if blockchain.state_tree.root_hash != state.state_root || blockchain.state_tree.head_id != state.tip {
+                    return Err( Error::StateMissMatch )
+                }
+                blockchain.unspent_outputs = UnspentOutputs::from_outputs(state.unspent_outputs);
+                blockchain.difficulty_history = state.difficulty_history;
+            },
+            None => {
+                if blockchain.state_tree.head_id != 0 { return Err( Error::StateMissMatch ) }
+                chainstate.write_snapshot(0, blockchain.state_hash(), &blockchain.difficulty_history, blockchain.unspent_outputs.outputs())?;
+            },
+        }
+        blockchain.chainstate = Some(chainstate);
+        Ok(blockchain)
+    }
+
+    #[cfg(test)]
+    pub fn set_clock( &mut self, clock: Box<dyn Clock> ) {
+        self.clock = clock;
+    }
     
-    pub fn apply_block( &mut self, block: &mut Block ) -> EventResult {
-        // insert block into state tree
-        self.state_tree.insert(block.hash());
+    // persist the undo records of applied blocks into the archive
+    pub fn set_archive_path( &mut self, archive_path: String ) {
+        self.undo_journal.set_archive_path(archive_path);
+    }
+
+    // verifies the block and applies it only if it is valid
+    pub fn apply_block( &mut self, block: &Block ) -> EventResult {
+        let changes = self.verify_block(block)?.into_changes();
+        self.commit_block(block, changes)
+    }
+
+    fn commit_block( &mut self, block: &Block, changes: BlockChanges ) -> EventResult {
+        let block_id = self.block_count() + 1;
+        self.undo_journal.push(block_id, changes.undo)?;
 
-        // apply miner's reward
-        self.apply_miner_reward(block)?;
+        // insert block into state tree
+        if let Err(err) = self.state_tree.insert(block.hash(), block.header.pow_level()) {
+            self.undo_journal.pop(block_id)?;
+            return Err(err.into());
+        }
+        self.difficulty_history.push(difficulty_data(&block.header));
+
+        if self.chainstate.is_some() {
+            let update = ChainStateUpdate {
+                tip: block_id,
+                state_root: self.state_tree.root_hash,
+                difficulty: Some(difficulty_data(&block.header)),
+                removed: changes.removed.iter().cloned().collect(),
+                added: changes.added.iter().map(|(id, output)| (*id, *output)).collect()
+            };
+            // the block is not applied unless it is persisted
+            if let Err(err) = self.persist(&update) {
+                self.difficulty_history.pop();
+                self.state_tree.revert()?;
+                self.undo_journal.pop(block_id)?;
+                return Err(err);
+            }
+        }
 
-        // apply transactions 
-        let mut transaction_id = 1; // index starts at one because zero is the miner's reward
-        for transaction in &block.transactions {
-            self.apply_transaction(transaction, transaction_id)?;
-            transaction_id += 1;
+        // update the unspent outputs
+        for id in &changes.removed {
+            self.unspent_outputs.remove(id);
         }
+        for (id, output) in changes.added {
+            self.unspent_outputs.insert(id, output);
+        }
+        self.unspent_outputs.address_index.commit();
         Ok(Event::Nothing)
     }
 
-    fn apply_miner_reward( &mut self, block : &Block )-> EventResult{
-        self.apply_outputs(&block.reward, 0)
+    // appends the update to the chainstate, the unspent outputs are not updated yet
+    fn persist( &mut self, update: &ChainStateUpdate ) -> Result<(), Error> {
+        let chainstate = self.chainstate.as_mut().unwrap();
+        chainstate.append(update)?;
+        if chainstate.needs_snapshot() {
+            let mut unspent_outputs = self.unspent_outputs.outputs().clone();
+            for id in &update.removed {
+                unspent_outputs.remove(id);
+            }
+            for &(id, output) in &update.added {
+                unspent_outputs.insert(id, output);
+            }
+            chainstate.write_snapshot(update.tip, update.state_root, &self.difficulty_history, &unspent_outputs)?;
+        }
+        Ok(())
     }
 
-    fn apply_transaction( &mut self, transaction: &Transaction, transaction_id : u32 ) -> EventResult {
-        // remove spent outputs
-        for input in &transaction.inputs {
-            self.unspent_outputs.0.remove( &input );
+    // reverts the head block
+    pub fn revert_block( &mut self ) -> EventResult {
+        let block_id = self.block_count();
+        let undo = self.undo_journal.pop(block_id)?;
+
+        // the outputs which existed before the block
+        let restored: Vec<(TransactionInput, TransactionOutput)> = undo.removed.iter().chain(undo.replaced.iter())
+            .filter(|&&(id, _)| id.block_id < block_id)
+            .cloned()
+            .collect();
+
+        // persist before reverting the state tree, such that a crash leaves the tree ahead
+        let difficulty = self.difficulty_history.pop().ok_or(Error::StateMissMatch)?;
+        if self.chainstate.is_some() {
+            let update = ChainStateUpdate {
+                tip: block_id - 1,
+                state_root: self.state_tree.prove_head_at(block_id - 1).ok_or(Error::StateMissMatch)?.root(),
+                difficulty: None,
+                removed: undo.created.to_vec(),
+                added: restored.clone()
+            };
+            // the block is not reverted unless it is persisted
+            if let Err(err) = self.persist(&update) {
+                self.difficulty_history.push(difficulty);
+                self.undo_journal.push(block_id, undo)?;
+                return Err(err);
+            }
         }
 
-        self.apply_outputs(transaction, transaction_id)
-    }
+        // delete the created outputs
+        for id in &undo.created {
+            self.unspent_outputs.remove(id);
+        }
 
-    fn apply_outputs( &mut self, transaction: &Transaction, transaction_id : u32) -> EventResult {
-        // add unspent outputs
-        let block_height = self.block_count();
-        let mut output_id = 0;
-        for output in &transaction.outputs {
-            let ouput_id = TransactionInput{ block_id: block_height, transaction_id: transaction_id, output_id : output_id };
-            // we aggregate all outputs of an address into one 
-            // therefore we have to delete all other outputs with the same address
-            if let Some(id) = self.unspent_outputs.get_id_by_address(output.address){
-                self.unspent_outputs.0.remove( &id );
-            }
-            self.unspent_outputs.0.insert( ouput_id, *output );
-            output_id += 1;
+        // restore the outputs which existed before the block
+        for (id, output) in restored {
+            self.unspent_outputs.insert(id, output);
         }
+        self.unspent_outputs.address_index.commit();
+
+        self.state_tree.revert()?;
         Ok(Event::Nothing)
+    }
 
+    // a context to stage the changes of the next block
+    pub fn validation_context( &self ) -> BlockValidationContext<'_> {
+        BlockValidationContext::new(&self.unspent_outputs, self.block_count() + 1)
     }
 
-    pub fn verify_block( &mut self, block: &mut Block ) -> EventResult {
+    // verifies the block and stages its changes
+    pub fn verify_block( &self, block: &Block ) -> Result<BlockValidationContext<'_>, Error> {
     	
         // verify block header
-        self.verify_block_header(&mut block.header)?;
+        self.verify_block_header(&block.header)?;
 
-        // verify miner's reward
-        self.verify_miner_reward(&mut block.reward)?;
+        // verify the block's size and counts
+        block.verify_limits()?;
+
+        // verify the header commits to the transactions
+        if !block.verify_transactions_root() { return Err( Error::InvalidTransactionsRoot ) }
+
+        // verify and stage the block's transactions
+        let context = self.verify_transactions(block)?;
+
+        // verify the header commits to the address index after the block
+        if context.address_index_root() != block.header.address_index_root { return Err( Error::InvalidAddressIndexRoot ) }
+        Ok( context )
+    }
+
+    fn verify_transactions( &self, block: &Block ) -> Result<BlockValidationContext<'_>, Error> {
+        let mut context = self.validation_context();
+
+        if !block.reward.is_coinbase() { return Err( Error::InvalidCoinbase ) }
 
         // verify transactions
-    	for transaction in &mut block.transactions{
-    		self.verify_transaction(transaction)?;
+        let mut fees: Value = 0;
+    	for (index, transaction) in block.transactions.iter().enumerate() {
+    		let fee = self.verify_transaction(transaction, &context)?;
+    		fees = fees.checked_add(fee).ok_or(Error::InvalidReward)?;
+            // index starts at one because zero is the miner's reward
+            context.apply_transaction(transaction, index as u32 + 1)?;
     	}
 
-    	Ok( Event::Nothing )
+        // stage miner's reward after the transactions, such that they can pay the miner
+        // with balances computed before the block
+        context.apply_outputs(&block.reward, 0)?;
+
+        // verify miner's reward including the fees
+        self.verify_miner_reward(&block.reward, fees)?;
+
+    	Ok( context )
     }
 
-    pub fn verify_block_header( &mut self, block_header: &mut BlockHeader ) -> EventResult {
+    pub fn verify_block_header( &self, block_header: &BlockHeader ) -> EventResult {
     	// verify chain head extension
     	if block_header.state != self.state_tree.root_hash { return Err( Error::StateMissMatch ) }
+    	// verify timestamp
+    	if block_header.timestamp <= self.median_time_past() { return Err( Error::TimestampTooEarly ) }
+    	if block_header.timestamp as u64 > self.clock.now() as u64 + self.params.max_future_drift as u64 { return Err( Error::TimestampTooFarAhead ) }
     	// verify difficulty target
-    	if block_header.difficulty_target != self.difficulty_target { return Err( Error::InvalidDifficulty ) }
+    	if block_header.difficulty_target != self.difficulty_target() { return Err( Error::InvalidDifficulty ) }
     	// verify proof of work
-    	block_header.verify_proof_of_work()
+    	block_header.verify_proof_of_work(&self.params)
     }
 
-    pub fn verify_miner_reward( &mut self, reward: &mut Transaction ) -> EventResult {
-        if reward.sum_outputs() > self.current_reward() { return Err( Error::InvalidReward ) }
+    pub fn verify_miner_reward( &self, reward: &Transaction, fees: Value ) -> EventResult {
+        let max_reward = self.current_reward().checked_add(fees).ok_or(Error::InvalidReward)?;
+        let reward_sum = reward.sum_outputs().ok_or(Error::InvalidReward)?;
+        if reward_sum > max_reward { return Err( Error::InvalidReward ) }
         Ok ( Event::Nothing )
     }
 
-    pub fn verify_transaction(&mut self, transaction: &mut Transaction) -> EventResult {
+    // verifies the transaction against the unspent outputs staged in `context` and returns its fee
+    pub fn verify_transaction(&self, transaction: &Transaction, context: &BlockValidationContext) -> Result<Value, Error> {
+
+        // verify: the transaction is unlocked in this block
+        if transaction.lock_height > context.block_id() { return Err(Error::TransactionLocked) }
     	
-    	let mut inputs_sum = 0;
+    	let mut inputs_sum: Value = 0;
     	let mut input_keys = Vec::new();
+    	let mut spent_inputs = HashSet::new();
 
     	// collect all inputs
-    	for input in &mut transaction.inputs{
+    	for input in &transaction.inputs{
+    		// verify: input is spent neither earlier in the block nor twice by this transaction
+    		if context.is_spent(input) || !spent_inputs.insert(*input) { return Err(Error::DoubleSpend) }
     		// verify: input is unspent
-    		match self.unspent_outputs.0.get(input) {
-    		    Some( mut transaction_output ) => {
+    		match context.get(input) {
+    		    Some( transaction_output ) => {
     		    	input_keys.push(transaction_output.address);
-    		    	inputs_sum += transaction_output.balance;
+    		    	inputs_sum = inputs_sum.checked_add(transaction_output.balance).ok_or(Error::InvalidCoinSum)?;
     		    },
     		    None => return Err(Error::InvalidInput),
     		}
     	}
 
-    	// calculate fees
-        let outputs_sum = transaction.sum_outputs();
-
         // verify: inputs_sum - outputs_sum > 0 ( no coins out-of-thin-air )
-        if outputs_sum > inputs_sum  { return Err( Error::InvalidCoinSum ); }
-    	
-        // let fees = inputs_sum - outputs_sum; 
+        let fee = transaction.fee(inputs_sum).ok_or(Error::InvalidCoinSum)?;
 
     	// verify signature
-    	transaction.signature.verify_multi_sig(input_keys)?;
+    	transaction.signature.verify_multi_sig(&transaction.signature_hash(), input_keys)?;
 
-    	Ok(Event::Nothing)
+    	Ok( fee )
     }
 
     pub fn state_hash(&self ) -> Hash {
@@ -145,52 +307,635 @@ impl Blockchain {
         self.state_tree.head_id
     }
 
+    // proves the block at `block_id` is included under the current state hash
+    pub fn block_proof(&self, block_id: u32 ) -> Option<StateTreeProof> {
+        self.state_tree.prove(block_id)
+    }
+
+    // the proof of the block at `block_id` from when it was the head, to extend the state from there
+    pub fn head_proof_at(&self, block_id: u32 ) -> Option<StateTreeProof> {
+        self.state_tree.prove_head_at(block_id)
+    }
+
+    // proves the balance of an address by its current unspent output
+    pub fn balance_proof<S: BlockStore>(&self, address: Address, blocks: &S ) -> Option<BalanceProof> {
+        let output_id = self.unspent_outputs.get_id_by_address(address)?;
+        let block = blocks.get_block(output_id.block_id)?;
+        let transaction = if output_id.transaction_id == 0 {
+            &block.reward
+        } else {
+            block.transactions.get(output_id.transaction_id as usize - 1)?
+        };
+
+        Some(BalanceProof {
+            output_id,
+            transaction: transaction.clone(),
+            transaction_proof: block.transaction_proof(output_id.transaction_id)?,
+            header: block.header.clone(),
+            block_proof: self.block_proof(output_id.block_id)?
+        })
+    }
+
+    // the blocks appended since the block at `old_head_id` was the head,
+    // used by light clients to refresh their balance proofs
+    pub fn state_tree_extension<S: BlockStore>(&self, old_head_id: u32, blocks: &S ) -> Option<StateTreeExtension> {
+        let head_proof = self.state_tree.prove_head_at(old_head_id)?;
+        let mut extension = Vec::new();
+        for block_id in old_head_id + 1 .. self.block_count() + 1 {
+            extension.push(blocks.get_block(block_id)?.clone());
+        }
+        Some(StateTreeExtension{ head_proof, blocks: extension })
+    }
+
+    // proves the chain's work by a logarithmic sample of its blocks
+    pub fn chain_proof<S: BlockStore>(&self, blocks: &S ) -> Option<ChainProof> {
+        ChainProof::new(&self.state_tree, blocks)
+    }
+
+    // commits to the unspent output ids sorted by address
+    pub fn address_index_root(&self) -> Hash {
+        self.unspent_outputs.address_index.root()
+    }
+
+    // proves the address's unspent output, or that it has none, under `address_index_root`
+    pub fn address_proof(&self, address: Address) -> AddressProof {
+        self.unspent_outputs.address_index.prove(address)
+    }
+
+    // the reward of the next block
     pub fn current_reward( &self ) -> Value {
-        10
+        block_reward(self.block_count() + 1, &self.params)
+    }
+
+    // the next block's timestamp must be later than this
+    pub fn median_time_past( &self ) -> u32 {
+        median_time_past(&self.difficulty_history, &self.params)
+    }
+
+    // the difficulty target required for the next block
+    pub fn difficulty_target( &self ) -> u32 {
+        next_difficulty(&self.difficulty_history, &self.params)
     }
 }
 
-pub struct UnspentOutputs( HashMap<TransactionInput, TransactionOutput> );
+fn difficulty_data( header: &BlockHeader ) -> DifficultyData {
+    DifficultyData {
+        timestamp : header.timestamp,
+        difficulty_target : header.difficulty_target
+    }
+}
+
+/// The UTXO set. All outputs of an address are aggregated into one,
+/// so the set is indexed by address as well.
+pub struct UnspentOutputs {
+    outputs: HashMap<TransactionInput, TransactionOutput>,
+    by_address: HashMap<Address, TransactionInput>,
+    // the authenticated index for nano nodes
+    address_index: AddressIndex
+}
 
 impl UnspentOutputs{
 
-    pub fn get_by_address(&self, address: Address) -> Option<( TransactionInput, TransactionOutput )>{
-        for (id, output) in &self.0{
-            if output.address == address {
-                return Some((*id,*output))
-            }
+    pub fn new() -> UnspentOutputs {
+        UnspentOutputs {
+            outputs: HashMap::new(),
+            by_address: HashMap::new(),
+            address_index: AddressIndex::new()
         }
-        return None
     }
 
-    pub fn get_id_by_address(&self, address: Address) -> Option<TransactionInput>{
-        for (id, output) in &self.0{
-            if output.address == address{
-                return Some(*id)
-            }
+    pub fn from_outputs( outputs: HashMap<TransactionInput, TransactionOutput> ) -> UnspentOutputs {
+        let by_address = outputs.iter().map(|(id, output)| (output.address, *id)).collect();
+        let address_index = AddressIndex::from_entries(outputs.iter().map(|(id, output)| AddressIndexEntry{ address: output.address, output_id: *id }).collect());
+        UnspentOutputs{ outputs, by_address, address_index }
+    }
+
+    pub fn outputs(&self) -> &HashMap<TransactionInput, TransactionOutput> {
+        &self.outputs
+    }
+
+    pub fn insert(&mut self, id: TransactionInput, output: TransactionOutput) {
+        self.by_address.insert(output.address, id);
+        self.address_index.insert(output.address, id);
+        self.outputs.insert(id, output);
+    }
+
+    pub fn remove(&mut self, id: &TransactionInput) -> Option<TransactionOutput> {
+        let output = self.outputs.remove(id)?;
+        // the address may already point to the output replacing this one
+        if self.by_address.get(&output.address) == Some(id) {
+            self.by_address.remove(&output.address);
+            self.address_index.remove(output.address);
         }
-        return None
+        Some(output)
+    }
+
+    pub fn get(&self, id: &TransactionInput) -> Option<TransactionOutput> {
+        self.outputs.get(id).copied()
+    }
+
+    pub fn address_index(&self) -> &AddressIndex {
+        &self.address_index
+    }
+
+    pub fn get_by_address(&self, address: Address) -> Option<( TransactionInput, TransactionOutput )>{
+        let id = self.get_id_by_address(address)?;
+        Some((id, self.outputs[&id]))
     }
 
+    pub fn get_id_by_address(&self, address: Address) -> Option<TransactionInput>{
+        self.by_address.get(&address).cloned()
+    }
+
+    #[cfg(test)]
     pub fn get_balance_by_address( &self, address : Address ) -> Value {
-        for ( _id, output) in &self.0{
-            if output.address == address{
-                return output.balance
-            }
+        match self.get_by_address(address) {
+            Some((_id, output)) => output.balance,
+            None => 0,
         }
-        return 0
     }
 
     pub fn count(&self) -> usize {
-        self.0.len()
+        self.outputs.len()
     }
 
     pub fn log(&self){
         println!("\nUnspentOutputs");
-        for (id, output) in &self.0{
+        for (id, output) in &self.outputs{
             println!("{:?}: {:?}" , id, output);
         }
         println!("\n\n");
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::schnorr::SecretKey;
+    use std::slice;
+
+    fn genesis() -> Block {
+        Block::new(BlockHeader::new(Hash::zeros(), 0, 1), Transaction::new(vec![], vec![]))
+    }
+
+    // applies a block without verifying its header
+    fn apply( blockchain: &mut Blockchain, block: &Block ) {
+        let changes = blockchain.verify_transactions(block).unwrap().into_changes();
+        blockchain.commit_block(block, changes).unwrap();
+    }
+
+    fn reward_block( blockchain: &Blockchain, address: Address, value: Value ) -> Block {
+        let header = BlockHeader::new(blockchain.state_hash(), 0, 1);
+        Block::new(header, Transaction::new_coinbase(TransactionOutput::new(address, value)))
+    }
+
+    fn key( seed: u8 ) -> SecretKey {
+        SecretKey::new([seed; 32])
+    }
+
+    fn address( key: &SecretKey ) -> Address {
+        Address::from(key.public_key())
+    }
+
+    fn signed( inputs: Vec<TransactionInput>, outputs: Vec<TransactionOutput>, key: &SecretKey ) -> Transaction {
+        let mut transaction = Transaction::new(inputs, outputs);
+        transaction.sign(slice::from_ref(key));
+        transaction
+    }
+
+    #[test]
+    fn revert_block() {
+        let alice_key = key(1);
+        let alice = address(&alice_key);
+        let bob_key = key(2);
+        let bob = address(&bob_key);
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let block = reward_block(&blockchain, bob, 10);
+        apply(&mut blockchain, &block);
+        let state_hash = blockchain.state_hash();
+        let (alice_id, _) = blockchain.unspent_outputs.get_by_address(alice).unwrap();
+        let (bob_id, _) = blockchain.unspent_outputs.get_by_address(bob).unwrap();
+
+        // alice pays bob, who also mines the block
+        let mut block = reward_block(&blockchain, bob, 10);
+        block.reward.outputs[0].balance = 24;
+        let mut payment = TransactionOutput::new(bob, 4);
+        payment.balance = 14;
+        let mut change = TransactionOutput::new(alice, 6);
+        change.balance = 6;
+        block.add_transaction(signed(vec![alice_id], vec![payment, change], &alice_key));
+        apply(&mut blockchain, &block);
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(bob), 24);
+
+        blockchain.revert_block().unwrap();
+        assert_eq!(blockchain.state_hash(), state_hash);
+        assert_eq!(blockchain.unspent_outputs.count(), 2);
+        assert_eq!(blockchain.unspent_outputs.get_id_by_address(alice), Some(alice_id));
+        assert_eq!(blockchain.unspent_outputs.get_id_by_address(bob), Some(bob_id));
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(alice), 10);
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(bob), 10);
+    }
+
+    #[test]
+    fn verify_balances() {
+        let alice_key = key(1);
+        let alice = address(&alice_key);
+        let bob_key = key(2);
+        let bob = address(&bob_key);
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let alice_id = blockchain.unspent_outputs.get_id_by_address(alice).unwrap();
+
+        // the reward is added to the miner's balance
+        let block = reward_block(&blockchain, alice, 10);
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidBalance)));
+        let mut block = reward_block(&blockchain, alice, 10);
+        block.reward.outputs[0].balance = 20;
+        block.update_transactions_root();
+        assert!(blockchain.verify_transactions(&block).is_ok());
+
+        // alice pays bob twice in one transaction, bob also mines the block
+        // and his reward is added after the block's transactions
+        let payment = |value, balance| {
+            let mut output = TransactionOutput::new(bob, value);
+            output.balance = balance;
+            output
+        };
+        let mut block = reward_block(&blockchain, bob, 10);
+        block.add_transaction(signed(vec![alice_id], vec![payment(3, 3), payment(4, 7), TransactionOutput::new(alice, 3)], &alice_key));
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidBalance)));
+        block.reward.outputs[0].balance = 17;
+        assert!(blockchain.verify_transactions(&block).is_ok());
+
+        // an inflated balance mints coins
+        let mut block = reward_block(&blockchain, bob, 10);
+        block.reward.outputs[0].balance = 17;
+        block.add_transaction(signed(vec![alice_id], vec![payment(3, 3), payment(4, 1000), TransactionOutput::new(alice, 3)], &alice_key));
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidBalance)));
+
+        // alice's spent output does not count towards her change
+        let mut block = reward_block(&blockchain, bob, 10);
+        block.reward.outputs[0].balance = 17;
+        let mut change = TransactionOutput::new(alice, 3);
+        change.balance = 13;
+        block.add_transaction(signed(vec![alice_id], vec![payment(7, 7), change], &alice_key));
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidBalance)));
+    }
+
+    #[test]
+    fn double_spend() {
+        let alice_key = key(1);
+        let alice = address(&alice_key);
+        let bob_key = key(2);
+        let bob = address(&bob_key);
+        let carol_key = key(3);
+        let carol = address(&carol_key);
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let alice_id = blockchain.unspent_outputs.get_id_by_address(alice).unwrap();
+        let is_double_spend = |result| matches!(result, Err(Error::DoubleSpend));
+
+        // two transactions spend the same output
+        let mut block = reward_block(&blockchain, carol, 10);
+        block.add_transaction(signed(vec![alice_id], vec![TransactionOutput::new(bob, 10)], &alice_key));
+        block.add_transaction(signed(vec![alice_id], vec![TransactionOutput::new(bob, 10)], &alice_key));
+        assert!(is_double_spend(blockchain.verify_transactions(&block).map(|_| ())));
+
+        // a transaction spends the same output twice
+        let mut block = reward_block(&blockchain, carol, 10);
+        block.add_transaction(signed(vec![alice_id, alice_id], vec![TransactionOutput::new(bob, 20)], &alice_key));
+        assert!(is_double_spend(blockchain.verify_transactions(&block).map(|_| ())));
+
+        // bob spends the output he received earlier in the block
+        let mut block = reward_block(&blockchain, carol, 10);
+        block.reward.outputs[0].balance = 20;
+        block.add_transaction(signed(vec![alice_id], vec![TransactionOutput::new(bob, 10)], &alice_key));
+        let bob_id = TransactionInput{ block_id: 2, transaction_id: 1, output_id: 0 };
+        block.add_transaction(signed(vec![bob_id], vec![TransactionOutput::new(carol, 10)], &bob_key));
+        apply(&mut blockchain, &block);
+        assert_eq!(blockchain.unspent_outputs.count(), 1);
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(carol), 20);
+    }
+
+    #[test]
+    fn fees() {
+        let alice_key = key(1);
+        let alice = address(&alice_key);
+        let bob_key = key(2);
+        let bob = address(&bob_key);
+        let carol_key = key(3);
+        let carol = address(&carol_key);
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let alice_id = blockchain.unspent_outputs.get_id_by_address(alice).unwrap();
+        let reward = blockchain.current_reward();
+
+        // alice pays a fee of 3
+        let transaction = signed(vec![alice_id], vec![TransactionOutput::new(bob, 5), TransactionOutput::new(alice, 2)], &alice_key);
+        assert_eq!(blockchain.verify_transaction(&transaction, &blockchain.validation_context()).unwrap(), 3);
+
+        // carol may claim the fee
+        let mut block = reward_block(&blockchain, carol, reward + 3);
+        block.add_transaction(transaction.clone());
+        assert!(blockchain.verify_transactions(&block).is_ok());
+
+        // but not more
+        let mut block = reward_block(&blockchain, carol, reward + 4);
+        block.add_transaction(transaction.clone());
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidReward)));
+
+        // only alice can spend her output
+        let mut block = reward_block(&blockchain, carol, reward);
+        block.add_transaction(signed(vec![alice_id], vec![TransactionOutput::new(bob, 5)], &bob_key));
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidSignature)));
+
+        // the signature covers the outputs
+        let mut block = reward_block(&blockchain, carol, reward);
+        let mut tampered = transaction.clone();
+        tampered.outputs[0].address = carol;
+        block.add_transaction(tampered);
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidSignature)));
+
+        // a reward must spend the coinbase input only
+        let mut block = reward_block(&blockchain, carol, reward);
+        block.reward.inputs.push(alice_id);
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidCoinbase)));
+        let mut block = reward_block(&blockchain, carol, reward);
+        block.reward.inputs.clear();
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidCoinbase)));
+    }
+
+    #[test]
+    fn locked_transactions() {
+        let alice_key = key(1);
+        let alice = address(&alice_key);
+        let bob = address(&key(2));
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let alice_id = blockchain.unspent_outputs.get_id_by_address(alice).unwrap();
+
+        // the next block is the third one
+        let mut transaction = Transaction::new(vec![alice_id], vec![TransactionOutput::new(bob, 10)]);
+        transaction.lock_height = 3;
+        transaction.sign(slice::from_ref(&alice_key));
+        assert!(matches!(blockchain.verify_transaction(&transaction, &blockchain.validation_context()), Err(Error::TransactionLocked)));
+        let block = reward_block(&blockchain, bob, 0);
+        apply(&mut blockchain, &block);
+        assert!(blockchain.verify_transaction(&transaction, &blockchain.validation_context()).is_ok());
+    }
+
+    #[test]
+    fn overflowing_sums() {
+        let alice_key = key(1);
+        let alice = address(&alice_key);
+        let bob = address(&key(2));
+        let carol = address(&key(3));
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let alice_id = blockchain.unspent_outputs.get_id_by_address(alice).unwrap();
+        let reward = blockchain.current_reward();
+
+        // a reward whose outputs wrap around to less than the block reward
+        let mut block = reward_block(&blockchain, bob, Value::MAX);
+        block.reward.outputs.push(TransactionOutput::new(carol, 2));
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidReward)));
+
+        // a transaction whose outputs wrap around to less than its inputs
+        let transaction = signed(vec![alice_id], vec![TransactionOutput::new(bob, Value::MAX), TransactionOutput::new(carol, 2)], &alice_key);
+        let mut block = reward_block(&blockchain, carol, reward);
+        block.add_transaction(transaction);
+        assert!(matches!(blockchain.verify_transactions(&block), Err(Error::InvalidCoinSum)));
+
+        // inputs whose balances wrap around
+        let bob_key = key(2);
+        let large_id = TransactionInput{ block_id: 1000, transaction_id: 0, output_id: 0 };
+        blockchain.unspent_outputs.insert(large_id, TransactionOutput::new(bob, Value::MAX));
+        let mut transaction = Transaction::new(vec![alice_id, large_id], vec![TransactionOutput::new(carol, 5)]);
+        transaction.sign(&[alice_key.clone(), bob_key.clone()]);
+        assert!(matches!(blockchain.verify_transaction(&transaction, &blockchain.validation_context()), Err(Error::InvalidCoinSum)));
+    }
+
+    #[test]
+    fn timestamps() {
+        use blockchain::test_helpers::{ test_params, mine, commit_address_index };
+        use utils::clock::FixedClock;
+
+        let params = test_params();
+        let mut blockchain = Blockchain::new(&genesis(), params.clone());
+        blockchain.set_clock(Box::new(FixedClock(1000)));
+        let header = |blockchain: &Blockchain, timestamp: u32| {
+            let reward = Transaction::new(vec![TransactionInput::new_coinbase()], vec![]);
+            let mut block = Block::new(BlockHeader::new(blockchain.state_hash(), timestamp, blockchain.difficulty_target()), reward);
+            commit_address_index(&mut block, blockchain);
+            mine(&mut block, timestamp as u64, &blockchain.params);
+            block
+        };
+
+        for timestamp in &[100, 300, 200] {
+            blockchain.apply_block(&header(&blockchain, *timestamp)).unwrap();
+        }
+        assert_eq!(blockchain.median_time_past(), 200);
+
+        // not later than the median
+        let block = header(&blockchain, 200);
+        assert!(matches!(blockchain.verify_block_header(&block.header), Err(Error::TimestampTooEarly)));
+
+        // too far ahead of our clock
+        let block = header(&blockchain, 1001 + params.max_future_drift);
+        assert!(matches!(blockchain.verify_block_header(&block.header), Err(Error::TimestampTooFarAhead)));
+        let block = header(&blockchain, 1000 + params.max_future_drift);
+        assert!(blockchain.verify_block_header(&block.header).is_ok());
+    }
+
+    #[test]
+    fn failing_persist() {
+        use blockchain::test_helpers::{ test_params, next_block };
+        use std::{ env, fs };
+
+        let path = env::temp_dir().join(format!("treecoin-chainstate-{}", Hash::random().to_hex()));
+        let alice = address(&key(1));
+        let mut blockchain = Blockchain::open(&genesis(), test_params(), &path).unwrap();
+        let reward = Transaction::new_coinbase(TransactionOutput::new(alice, 10));
+        let block = next_block(&blockchain, reward, vec![], 0);
+        blockchain.apply_block(&block).unwrap();
+        let state_hash = blockchain.state_hash();
+        let difficulty_target = blockchain.difficulty_target();
+
+        // a block which cannot be persisted is not applied
+        let mut reward = Transaction::new_coinbase(TransactionOutput::new(alice, 10));
+        blockchain.validation_context().update_balances(&mut reward);
+        let next = next_block(&blockchain, reward, vec![], 1);
+        blockchain.chainstate.as_mut().unwrap().fail_appends().unwrap();
+        assert!(blockchain.apply_block(&next).is_err());
+        assert_eq!(blockchain.state_hash(), state_hash);
+        assert_eq!(blockchain.block_count(), 1);
+        assert_eq!(blockchain.difficulty_target(), difficulty_target);
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(alice), 10);
+
+        // a block which cannot be persisted is not reverted
+        assert!(blockchain.revert_block().is_err());
+        assert_eq!(blockchain.state_hash(), state_hash);
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(alice), 10);
+
+        // the chain is consistent with the persisted state
+        drop(blockchain);
+        let mut blockchain = Blockchain::open(&genesis(), test_params(), &path).unwrap();
+        assert_eq!(blockchain.state_hash(), state_hash);
+        blockchain.apply_block(&next).unwrap();
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(alice), 20);
+        blockchain.revert_block().unwrap();
+        assert_eq!(blockchain.state_hash(), state_hash);
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn reopen() {
+        use blockchain::test_helpers::{ test_params, next_block };
+        use std::{ env, fs };
+        use std::io::Write;
+
+        let path = env::temp_dir().join(format!("treecoin-chainstate-{}", Hash::random().to_hex()));
+        let alice = address(&key(1));
+        let (state_hash, block_count, difficulty_target) = {
+            let mut blockchain = Blockchain::open(&genesis(), test_params(), &path).unwrap();
+            for seed in 0..6 {
+                let reward = Transaction::new_coinbase(TransactionOutput::new(Address::new([seed as u8; 32]), 10));
+                let block = next_block(&blockchain, reward, vec![], seed);
+                blockchain.apply_block(&block).unwrap();
+            }
+            blockchain.revert_block().unwrap();
+            let reward = Transaction::new_coinbase(TransactionOutput::new(alice, 10));
+            let block = next_block(&blockchain, reward, vec![], 7);
+            blockchain.apply_block(&block).unwrap();
+            (blockchain.state_hash(), blockchain.block_count(), blockchain.difficulty_target())
+        };
+
+        let check = |blockchain: &Blockchain| {
+            assert_eq!(blockchain.state_hash(), state_hash);
+            assert_eq!(blockchain.block_count(), block_count);
+            assert_eq!(blockchain.difficulty_target(), difficulty_target);
+            assert_eq!(blockchain.unspent_outputs.count(), 6);
+            assert_eq!(blockchain.unspent_outputs.get_balance_by_address(alice), 10);
+        };
+        check(&Blockchain::open(&genesis(), test_params(), &path).unwrap());
+
+        // a crash after committing the state tree of the next block, and while appending its chainstate update
+        {
+            let mut blockchain = Blockchain::open(&genesis(), test_params(), &path).unwrap();
+            blockchain.state_tree.insert(Hash::random(), 0).unwrap();
+            let mut log = fs::OpenOptions::new().append(true).open(path.join("chainstate").join("log")).unwrap();
+            log.write_all(&[200, 0, 0, 0, 1, 2, 3]).unwrap();
+        }
+        check(&Blockchain::open(&genesis(), test_params(), &path).unwrap());
+
+        // a log without its snapshot is not replayed from an empty state
+        fs::rename(path.join("chainstate").join("snapshot"), path.join("snapshot")).unwrap();
+        assert!(Blockchain::open(&genesis(), test_params(), &path).is_err());
+        assert!(fs::metadata(path.join("chainstate").join("log")).unwrap().len() > 0);
+        fs::rename(path.join("snapshot"), path.join("chainstate").join("snapshot")).unwrap();
+        check(&Blockchain::open(&genesis(), test_params(), &path).unwrap());
+
+        // a state tree that does not match the chainstate
+        fs::remove_dir_all(path.join("state_tree")).unwrap();
+        assert!(matches!(Blockchain::open(&genesis(), test_params(), &path), Err(Error::StateMissMatch)));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn address_index() {
+        let alice = address(&key(1));
+        let bob = address(&key(2));
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let block = reward_block(&blockchain, alice, 10);
+        apply(&mut blockchain, &block);
+        let alice_id = blockchain.unspent_outputs.get_id_by_address(alice).unwrap();
+
+        // a new output of alice replaces her old one
+        let mut block = reward_block(&blockchain, alice, 10);
+        block.reward.outputs.push(TransactionOutput::new(bob, 5));
+        block.reward.outputs[0].balance = 20;
+        apply(&mut blockchain, &block);
+        let (new_id, output) = blockchain.unspent_outputs.get_by_address(alice).unwrap();
+        assert!(new_id != alice_id);
+        assert_eq!(output.balance, 20);
+        assert_eq!(blockchain.unspent_outputs.count(), 2);
+        let root = blockchain.address_index_root();
+        assert_eq!(blockchain.address_proof(bob).verify(bob, root).unwrap(), blockchain.unspent_outputs.get_id_by_address(bob));
+
+        blockchain.revert_block().unwrap();
+        let root = blockchain.address_index_root();
+        assert_eq!(blockchain.address_proof(bob).verify(bob, root).unwrap(), None);
+        assert_eq!(blockchain.address_proof(alice).verify(alice, root).unwrap(), Some(alice_id));
+        assert_eq!(blockchain.unspent_outputs.get_id_by_address(alice), Some(alice_id));
+        assert_eq!(blockchain.unspent_outputs.get_id_by_address(bob), None);
+        assert_eq!(blockchain.unspent_outputs.get_balance_by_address(alice), 10);
+    }
+
+    #[test]
+    fn address_index_root() {
+        use blockchain::test_helpers::{ test_params, mine, next_block };
+
+        let mut blockchain = Blockchain::new(&genesis(), test_params());
+        let reward = Transaction::new_coinbase(TransactionOutput::new(address(&key(1)), 10));
+        let block = next_block(&blockchain, reward.clone(), vec![], 1);
+
+        // the header must commit to the address index after the block
+        let mut invalid = block.clone();
+        invalid.header.address_index_root = blockchain.address_index_root();
+        mine(&mut invalid, 2, &blockchain.params);
+        assert!(matches!(blockchain.verify_block(&invalid), Err(Error::InvalidAddressIndexRoot)));
+
+        blockchain.apply_block(&block).unwrap();
+        assert_eq!(blockchain.address_index_root(), block.header.address_index_root);
+    }
+
+    // cargo +nightly bench --features bench apply_block_to_a_million_addresses
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn apply_block_to_a_million_addresses(bencher: &mut ::test::Bencher) {
+        use blockchain::transaction::MAX_OUTPUTS_COUNT;
+
+        let mut blockchain = Blockchain::new(&genesis(), ChainParams::default());
+        let address = |index: u32| {
+            let mut bytes = [0u8; 32];
+            bytes[..4].copy_from_slice(&[index as u8, (index >> 8) as u8, (index >> 16) as u8, (index >> 24) as u8]);
+            Address::new(bytes)
+        };
+        // outputs of old blocks, beyond the ids of the blocks applied below
+        let outputs = (0..1_000_000u32).map(|index| {
+            let id = TransactionInput{ block_id: (1 << 30) + index / (1 << 16), transaction_id: (index >> 8) & 0xff, output_id: index & 0xff };
+            (id, TransactionOutput::new(address(index), 1))
+        }).collect();
+        blockchain.unspent_outputs = UnspentOutputs::from_outputs(outputs);
+
+        // every block's reward pays existing addresses, each output replaces an old one
+        let mut block_index = 0;
+        bencher.iter(|| {
+            let header = BlockHeader::new(blockchain.state_hash(), 0, 1);
+            let outputs = (0..MAX_OUTPUTS_COUNT as u32).map(|index| {
+                let mut output = TransactionOutput::new(address((block_index * 7919 + index * 3889) % 1_000_000), 0);
+                output.balance = 1;
+                output
+            }).collect();
+            let block = Block::new(header, Transaction::new(vec![TransactionInput::new_coinbase()], outputs));
+            block_index += 1;
+
+            // the header's commitment to the address index is checked like in `verify_block`
+            let context = blockchain.verify_transactions(&block).unwrap();
+            ::test::black_box(context.address_index_root());
+            let changes = context.into_changes();
+            blockchain.commit_block(&block, changes).unwrap();
+        });
+        assert_eq!(blockchain.unspent_outputs.count(), 1_000_000);
+    }
+}
@@ -0,0 +1,257 @@
+use blockchain::block::BlockHeader;
+use blockchain::block_tree::BlockStore;
+use blockchain::state_tree::{ StateTree, StateTreeProof };
+use protocol::event::Error as ProtocolError;
+use protocol::protocol_config::ChainParams;
+use utils::Hash;
+use utils::hash::Hashable;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::cmp::Ordering;
+use std::collections::{ BTreeSet, HashMap };
+use std::io::{ Error, ErrorKind };
+
+// the number of blocks of a level which outweigh the lower levels before them
+pub const SECURITY_PARAMETER: usize = 6;
+
+// the number of most recent blocks which are all part of a proof
+pub const SUFFIX_LENGTH: u32 = 6;
+
+// proofs of more blocks are rejected while reading
+const MAX_PROOF_BLOCKS: u32 = 1 << 16;
+
+/// A block of a chain proof and its path in the state tree.
+#[derive(Debug, Clone)]
+pub struct ChainProofBlock {
+    pub header: BlockHeader,
+    pub proof: StateTreeProof
+}
+
+/// A non-interactive proof of proofs of work (NiPoPoW) of a chain. It samples the
+/// superblocks, the blocks whose proof of work exceeds their target by a power of two,
+/// such that the superblocks of a level represent the work of all blocks below them.
+/// The proof contains the most recent blocks of every level and all blocks of the suffix,
+/// so its size is logarithmic in the length of the chain.
+///
+/// All blocks are proven by their path in the state tree of the head, the last block.
+/// The head's header commits to the state tree before the head, so its proof of work
+/// secures all paths. The difficulty targets are taken as they are, a light client
+/// cannot check the retargeting without all headers.
+#[derive(Debug, Clone)]
+pub struct ChainProof {
+    pub genesis: StateTreeProof,
+    // ordered by block id, the head is the last block
+    pub blocks: Vec<ChainProofBlock>
+}
+
+impl ChainProof {
+
+    /// Proves the chain of the state tree, none if blocks are missing in the store.
+    pub fn new<S: BlockStore>( state_tree: &StateTree, blocks: &S ) -> Option<ChainProof> {
+        ChainProof::with_parameters(state_tree, blocks, SECURITY_PARAMETER, SUFFIX_LENGTH)
+    }
+
+    fn with_parameters<S: BlockStore>( state_tree: &StateTree, blocks: &S, security_parameter: usize, suffix_length: u32 ) -> Option<ChainProof> {
+        let head_id = state_tree.head_id;
+        if head_id == 0 { return None }
+        let suffix_start = if head_id > suffix_length { head_id - suffix_length + 1 } else { 1 };
+
+        // from the highest level down, the superblocks after the last few of the level above
+        let mut block_ids = BTreeSet::new();
+        let mut from = 1;
+        let max_level = state_tree.root_node().ok()?.level();
+        for level in (0..max_level + 1).rev() {
+            if from >= suffix_start { break }
+            let superblocks = state_tree.superblocks(level, from, suffix_start - 1).ok()?;
+            if superblocks.len() > security_parameter {
+                from = superblocks[superblocks.len() - security_parameter];
+            }
+            block_ids.extend(superblocks);
+        }
+        block_ids.extend(suffix_start .. head_id + 1);
+
+        let mut proof_blocks = Vec::new();
+        for block_id in block_ids {
+            proof_blocks.push(ChainProofBlock {
+                header: blocks.get_block(block_id)?.header.clone(),
+                proof: state_tree.prove(block_id)?
+            });
+        }
+        Some(ChainProof{ genesis: state_tree.prove(0)?, blocks: proof_blocks })
+    }
+
+    pub fn head( &self ) -> Option<&ChainProofBlock> {
+        self.blocks.last()
+    }
+
+    /// Verifies the proofs of work of all blocks and their paths in the state tree of the head.
+    pub fn verify( &self, genesis_hash: Hash, params: &ChainParams ) -> Result<(), ProtocolError> {
+        let head = self.head().ok_or(ProtocolError::InvalidChainProof)?;
+        if !head.proof.is_head() || head.proof.previous_root() != head.header.state { return Err(ProtocolError::InvalidChainProof) }
+        let state_root = head.proof.root();
+
+        if self.genesis.block_id != 0 || self.genesis.block_hash != genesis_hash || !self.genesis.verify(state_root) {
+            return Err(ProtocolError::InvalidChainProof);
+        }
+
+        let mut previous_id = 0;
+        for block in &self.blocks {
+            if block.proof.block_id <= previous_id { return Err(ProtocolError::InvalidChainProof) }
+            previous_id = block.proof.block_id;
+
+            if block.proof.block_hash != block.header.hash() { return Err(ProtocolError::InvalidChainProof) }
+            if block.proof.block_level != block.header.pow_level() { return Err(ProtocolError::InvalidChainProof) }
+            if !block.proof.verify(state_root) { return Err(ProtocolError::InvalidChainProof) }
+            block.header.verify_proof_of_work(params)?;
+        }
+        Ok(())
+    }
+
+    /// Compares the work of two verified proofs of the same genesis after their fork point.
+    /// The greater proof is the one of the heavier chain.
+    pub fn compare( &self, other: &ChainProof ) -> Ordering {
+        let fork_id = self.fork_id(other);
+        self.score(fork_id).cmp(&other.score(fork_id))
+    }
+
+    // the id of the last block in both proofs
+    fn fork_id( &self, other: &ChainProof ) -> u32 {
+        let other_blocks: HashMap<u32, Hash> = other.blocks.iter()
+            .map(|block| (block.proof.block_id, block.proof.block_hash))
+            .collect();
+        self.blocks.iter().rev()
+            .find(|block| other_blocks.get(&block.proof.block_id) == Some(&block.proof.block_hash))
+            .map_or(0, |block| block.proof.block_id)
+    }
+
+    // the best argument for the work after the fork point: the highest work represented
+    // by the superblocks of a level, each counting for 2^level blocks of its target
+    fn score( &self, fork_id: u32 ) -> u128 {
+        let blocks: Vec<&ChainProofBlock> = self.blocks.iter().filter(|block| block.proof.block_id > fork_id).collect();
+        let max_level = blocks.iter().map(|block| block.proof.block_level).max().unwrap_or(0);
+
+        let mut best = 0;
+        for level in 0..max_level + 1 {
+            let superblocks: Vec<&&ChainProofBlock> = blocks.iter().filter(|block| block.proof.block_level >= level).collect();
+            // too few superblocks of a level are not representative
+            if level > 0 && superblocks.len() < SECURITY_PARAMETER { break }
+            let work: u128 = superblocks.iter().map(|block| (block.header.difficulty_target as u128) << level).sum();
+            best = best.max(work);
+        }
+        best
+    }
+}
+
+impl Writeable for ChainProof {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.genesis.write(writer)?;
+        (self.blocks.len() as u32).write(writer)?;
+        for block in &self.blocks {
+            block.header.write(writer)?;
+            block.proof.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Readable for ChainProof {
+    fn read(reader: &mut dyn Reader) -> Result<ChainProof, Error>{
+        let genesis = StateTreeProof::read(reader)?;
+        let count = u32::read(reader)?;
+        if count > MAX_PROOF_BLOCKS {
+            return Err(Error::new(ErrorKind::InvalidData, "ChainProof too long"));
+        }
+        let mut blocks = Vec::new();
+        for _ in 0..count {
+            blocks.push(ChainProofBlock {
+                header: BlockHeader::read(reader)?,
+                proof: StateTreeProof::read(reader)?
+            });
+        }
+        Ok(ChainProof{ genesis, blocks })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::block::Block;
+    use blockchain::blockchain::Blockchain;
+    use blockchain::test_helpers::{ test_params, genesis, next_block };
+    use blockchain::transaction::{ Address, Transaction, TransactionOutput };
+
+    struct Blocks(Vec<Block>);
+
+    impl BlockStore for Blocks {
+        fn get_block(&self, block_id: u32) -> Option<&Block> {
+            self.0.get(block_id as usize)
+        }
+    }
+
+    // a chain of `length` blocks after the genesis, the seeds tell chains apart
+    fn chain( length: u32, seed: u64 ) -> ( Blockchain, Blocks ) {
+        let mut blockchain = Blockchain::new(&genesis(), test_params());
+        let mut blocks = Blocks(vec![genesis()]);
+        for index in 0..length {
+            let mut reward = Transaction::new_coinbase(TransactionOutput::new(Address::new([seed as u8; 32]), 1));
+            blockchain.validation_context().update_balances(&mut reward);
+            let block = next_block(&blockchain, reward, vec![], seed << 16 | index as u64);
+            blockchain.apply_block(&block).unwrap();
+            blocks.0.push(block);
+        }
+        ( blockchain, blocks )
+    }
+
+    #[test]
+    fn prove_chain() {
+        let params = test_params();
+        let genesis_hash = genesis().hash();
+        let ( blockchain, blocks ) = chain(300, 1);
+        let proof = blockchain.chain_proof(&blocks).unwrap();
+        proof.verify(genesis_hash, &params).unwrap();
+        assert_eq!(proof.head().unwrap().proof.block_id, 300);
+        assert!(proof.blocks.len() < 150);
+
+        // the proof contains the superblocks of the highest level
+        let max_level = blocks.0[1..].iter().map(|block| block.header.pow_level()).max().unwrap();
+        assert!(proof.blocks.iter().any(|block| block.proof.block_level == max_level));
+
+        let mut bytes = Vec::new();
+        proof.write(&mut bytes).unwrap();
+        ChainProof::read(&mut &bytes[..]).unwrap().verify(genesis_hash, &params).unwrap();
+
+        // another genesis
+        assert!(proof.verify(Hash::random(), &params).is_err());
+
+        // a block's level must match its proof of work
+        let mut tampered = proof.clone();
+        tampered.blocks[0].proof.block_level += 1;
+        assert!(tampered.verify(genesis_hash, &params).is_err());
+
+        // the head must be the last block of the state tree
+        let mut tampered = proof.clone();
+        tampered.blocks.pop();
+        assert!(tampered.verify(genesis_hash, &params).is_err());
+
+        // blocks must be in order
+        let mut tampered = proof.clone();
+        tampered.blocks.swap(0, 1);
+        assert!(tampered.verify(genesis_hash, &params).is_err());
+    }
+
+    #[test]
+    fn compare_chains() {
+        let params = test_params();
+        let genesis_hash = genesis().hash();
+        let ( short_chain, short_blocks ) = chain(60, 2);
+        let ( long_chain, long_blocks ) = chain(120, 3);
+        let short_proof = short_chain.chain_proof(&short_blocks).unwrap();
+        let long_proof = long_chain.chain_proof(&long_blocks).unwrap();
+        short_proof.verify(genesis_hash, &params).unwrap();
+        long_proof.verify(genesis_hash, &params).unwrap();
+
+        assert_eq!(long_proof.compare(&short_proof), Ordering::Greater);
+        assert_eq!(short_proof.compare(&long_proof), Ordering::Less);
+        assert_eq!(long_proof.compare(&long_proof), Ordering::Equal);
+    }
+}
@@ -0,0 +1,269 @@
+use blockchain::difficulty::DifficultyData;
+use blockchain::transaction::{ TransactionInput, TransactionOutput };
+use utils::Hash;
+use utils::hash::HashWriter;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::collections::HashMap;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ Error, ErrorKind, Read, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+
+// a snapshot is written once the log of updates grows beyond this size
+const MAX_LOG_SIZE: u64 = 1 << 24;
+
+/// The chain's unspent outputs and metadata as of its tip, as read from a snapshot.
+pub struct ChainState {
+    pub tip: u32,
+    pub state_root: Hash,
+    pub difficulty_history: Vec<DifficultyData>,
+    pub unspent_outputs: HashMap<TransactionInput, TransactionOutput>
+}
+
+/// The changes of the chain state by applying or reverting a block.
+pub struct ChainStateUpdate {
+    pub tip: u32,
+    pub state_root: Hash,
+    // the difficulty data of an applied block, none if the block was reverted
+    pub difficulty: Option<DifficultyData>,
+    pub removed: Vec<TransactionInput>,
+    pub added: Vec<(TransactionInput, TransactionOutput)>
+}
+
+impl ChainState {
+
+    fn apply( &mut self, update: ChainStateUpdate ) {
+        self.tip = update.tip;
+        self.state_root = update.state_root;
+        match update.difficulty {
+            Some(data) => self.difficulty_history.push(data),
+            None => { self.difficulty_history.pop(); },
+        }
+        for id in &update.removed {
+            self.unspent_outputs.remove(id);
+        }
+        for (id, output) in update.added {
+            self.unspent_outputs.insert(id, output);
+        }
+    }
+}
+
+/// Persists the chain state in a directory as a snapshot and a log of the updates since.
+/// Every update is appended as one checksummed record and synced, so a block's changes
+/// are durable all at once or not at all: a record torn by a crash fails its checksum
+/// and is discarded when reopening. Snapshots replace the previous one atomically,
+/// and the log's records carry a sequence number such that records already contained
+/// in the snapshot are skipped.
+pub struct ChainStateStore {
+    path: PathBuf,
+    log: File,
+    log_length: u64,
+    // the sequence number of the last update
+    sequence: u64
+}
+
+impl ChainStateStore {
+
+    /// Opens the store in the directory `path`, creating it if necessary.
+    /// Returns the stored chain state, none if no snapshot was written yet.
+    pub fn open<P: AsRef<Path>>( path: P ) -> Result<( ChainStateStore, Option<ChainState> ), Error> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let ( mut sequence, mut state ) = match File::open(path.join("snapshot")) {
+            Ok(mut file) => {
+                let mut bytes = Vec::new();
+                file.read_to_end(&mut bytes)?;
+                let payload = verify_checksum(&bytes).ok_or(Error::new(ErrorKind::InvalidData, "invalid chainstate snapshot"))?;
+                let reader = &mut &payload[..];
+                ( u64::read(reader)?, Some(read_state(reader)?) )
+            },
+            Err(ref err) if err.kind() == ErrorKind::NotFound => ( 0, None ),
+            Err(err) => return Err(err),
+        };
+
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path.join("log"))?;
+        let mut bytes = Vec::new();
+        log.read_to_end(&mut bytes)?;
+
+        // replay the complete records, the first incomplete one was torn by a crash
+        let mut log_length = 0;
+        while let Some(( payload, length )) = read_record(&bytes[log_length..]) {
+            let reader = &mut &payload[..];
+            let record_sequence = u64::read(reader)?;
+            let update = read_update(reader)?;
+            if record_sequence > sequence {
+                match state {
+                    Some(ref mut state) => state.apply(update),
+                    // updates are appended only after the first snapshot
+                    None => return Err(Error::new(ErrorKind::InvalidData, "chainstate log without a snapshot")),
+                }
+                sequence = record_sequence;
+            }
+            log_length += length;
+        }
+        log.set_len(log_length as u64)?;
+        log.sync_all()?;
+
+        Ok(( ChainStateStore{ path, log, log_length: log_length as u64, sequence }, state ))
+    }
+
+    /// Durably appends the update of a block.
+    pub fn append( &mut self, update: &ChainStateUpdate ) -> Result<(), Error> {
+        let mut payload = Vec::new();
+        (self.sequence + 1).write(&mut payload)?;
+        write_update(update, &mut payload)?;
+        let record = with_checksum(payload)?;
+
+        self.log.seek(SeekFrom::Start(self.log_length))?;
+        self.log.write_all(&record)?;
+        self.log.sync_data()?;
+        self.log_length += record.len() as u64;
+        self.sequence += 1;
+        Ok(())
+    }
+
+    // makes all further appends fail, as on a full disc
+    #[cfg(test)]
+    pub fn fail_appends( &mut self ) -> Result<(), Error> {
+        self.log = File::open(self.path.join("log"))?;
+        Ok(())
+    }
+
+    // whether the log should be folded into a new snapshot
+    pub fn needs_snapshot( &self ) -> bool {
+        self.log_length > MAX_LOG_SIZE
+    }
+
+    /// Atomically replaces the snapshot by the given state, which must include all updates so far.
+    pub fn write_snapshot( &mut self, tip: u32, state_root: Hash, difficulty_history: &[DifficultyData], unspent_outputs: &HashMap<TransactionInput, TransactionOutput> ) -> Result<(), Error> {
+        let mut payload = Vec::new();
+        self.sequence.write(&mut payload)?;
+        tip.write(&mut payload)?;
+        state_root.write(&mut payload)?;
+        (difficulty_history.len() as u32).write(&mut payload)?;
+        for data in difficulty_history {
+            write_difficulty(data, &mut payload)?;
+        }
+        write_outputs(unspent_outputs.len(), unspent_outputs.iter(), &mut payload)?;
+        let snapshot = with_checksum(payload)?;
+
+        let temporary_path = self.path.join("snapshot.tmp");
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&snapshot)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, self.path.join("snapshot"))?;
+        File::open(&self.path)?.sync_all()?;
+
+        // the snapshot contains all records of the log
+        self.log.set_len(0)?;
+        self.log.sync_all()?;
+        self.log_length = 0;
+        Ok(())
+    }
+}
+
+// prefixes the payload by its length and appends its hash
+pub fn with_checksum( payload: Vec<u8> ) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    (payload.len() as u32).write(&mut bytes)?;
+    bytes.extend_from_slice(&payload);
+    checksum(&payload).write(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn checksum( payload: &[u8] ) -> Hash {
+    let mut writer = HashWriter::new();
+    writer.write_fixed_size(payload).unwrap();
+    writer.finalize()
+}
+
+// the payload of a complete record at the start of `bytes` and the record's length
+fn read_record( bytes: &[u8] ) -> Option<( &[u8], usize )> {
+    if bytes.len() < 4 { return None }
+    let length = u32::read(&mut &bytes[..4]).ok()? as usize;
+    let payload = bytes.get(4 .. 4 + length)?;
+    let hash = Hash::read(&mut bytes.get(4 + length .. 4 + length + 32)?).ok()?;
+    if checksum(payload) != hash { return None }
+    Some(( payload, 4 + length + 32 ))
+}
+
+pub fn verify_checksum( bytes: &[u8] ) -> Option<&[u8]> {
+    let ( payload, length ) = read_record(bytes)?;
+    if length != bytes.len() { return None }
+    Some(payload)
+}
+
+fn write_difficulty( data: &DifficultyData, writer: &mut dyn Writer ) -> Result<(), Error> {
+    data.timestamp.write(writer)?;
+    data.difficulty_target.write(writer)
+}
+
+fn read_difficulty( reader: &mut dyn Reader ) -> Result<DifficultyData, Error> {
+    Ok(DifficultyData {
+        timestamp: u32::read(reader)?,
+        difficulty_target: u32::read(reader)?
+    })
+}
+
+fn write_outputs<'a, I: Iterator<Item = ( &'a TransactionInput, &'a TransactionOutput )>>( count: usize, outputs: I, writer: &mut dyn Writer ) -> Result<(), Error> {
+    (count as u32).write(writer)?;
+    for ( id, output ) in outputs {
+        id.write(writer)?;
+        output.write(writer)?;
+    }
+    Ok(())
+}
+
+fn read_outputs( reader: &mut dyn Reader ) -> Result<Vec<( TransactionInput, TransactionOutput )>, Error> {
+    let count = u32::read(reader)?;
+    let mut outputs = Vec::new();
+    for _ in 0..count {
+        outputs.push(( TransactionInput::read(reader)?, TransactionOutput::read(reader)? ));
+    }
+    Ok(outputs)
+}
+
+fn write_update( update: &ChainStateUpdate, writer: &mut dyn Writer ) -> Result<(), Error> {
+    update.tip.write(writer)?;
+    update.state_root.write(writer)?;
+    match update.difficulty {
+        Some(ref data) => {
+            1u8.write(writer)?;
+            write_difficulty(data, writer)?;
+        },
+        None => 0u8.write(writer)?,
+    }
+    (update.removed.len() as u32).write(writer)?;
+    for id in &update.removed {
+        id.write(writer)?;
+    }
+    write_outputs(update.added.len(), update.added.iter().map(|( id, output )| ( id, output )), writer)
+}
+
+fn read_update( reader: &mut dyn Reader ) -> Result<ChainStateUpdate, Error> {
+    let tip = u32::read(reader)?;
+    let state_root = Hash::read(reader)?;
+    let difficulty = match u8::read(reader)? {
+        0 => None,
+        _ => Some(read_difficulty(reader)?),
+    };
+    let removed_count = u32::read(reader)?;
+    let mut removed = Vec::new();
+    for _ in 0..removed_count {
+        removed.push(TransactionInput::read(reader)?);
+    }
+    let added = read_outputs(reader)?;
+    Ok(ChainStateUpdate{ tip, state_root, difficulty, removed, added })
+}
+
+fn read_state( reader: &mut dyn Reader ) -> Result<ChainState, Error> {
+    let tip = u32::read(reader)?;
+    let state_root = Hash::read(reader)?;
+    let history_count = u32::read(reader)?;
+    let mut difficulty_history = Vec::new();
+    for _ in 0..history_count {
+        difficulty_history.push(read_difficulty(reader)?);
+    }
+    let unspent_outputs = read_outputs(reader)?.into_iter().collect();
+    Ok(ChainState{ tip, state_root, difficulty_history, unspent_outputs })
+}
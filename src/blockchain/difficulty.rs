@@ -0,0 +1,124 @@
+use protocol::protocol_config::ChainParams;
+
+/// The fields of a block header the retargeting depends on.
+#[derive(Debug, Clone, Copy)]
+pub struct DifficultyData {
+    pub timestamp: u32,
+    pub difficulty_target: u32
+}
+
+// the adjustment over a window is bounded by this factor
+const MAX_ADJUSTMENT_FACTOR: u64 = 4;
+
+// the target moves by at most this fraction of the last target per block
+const MAX_STEP_DIVISOR: u64 = 16;
+
+/// Computes the difficulty target of the block following `history`.
+/// The target is the average difficulty of the last `retarget_window` blocks,
+/// scaled by how far their timespan missed the expected block time.
+/// A single block moves the target by at most 1/16, so miners faking a run of
+/// timestamps lower the difficulty only gradually while honest blocks follow.
+pub fn next_difficulty( history: &[DifficultyData], params: &ChainParams ) -> u32 {
+    let last = history[history.len() - 1];
+    if history.len() < 2 { return last.difficulty_target }
+
+    let window = ::std::cmp::min( params.retarget_window as usize, history.len() - 1 );
+    let first = history[history.len() - 1 - window];
+
+    // average difficulty over the window
+    let mut difficulty_sum = 0u64;
+    for data in &history[history.len() - window ..] {
+        difficulty_sum += data.difficulty_target as u64;
+    }
+    let average_difficulty = difficulty_sum / window as u64;
+
+    // actual timespan, damped to the bounds of the adjustment
+    let expected_timespan = params.block_time as u64 * window as u64;
+    let mut timespan = last.timestamp.saturating_sub(first.timestamp) as u64;
+    if timespan < expected_timespan / MAX_ADJUSTMENT_FACTOR {
+        timespan = expected_timespan / MAX_ADJUSTMENT_FACTOR;
+    }
+    if timespan > expected_timespan * MAX_ADJUSTMENT_FACTOR {
+        timespan = expected_timespan * MAX_ADJUSTMENT_FACTOR;
+    }
+    if timespan == 0 { timespan = 1 }
+
+    let mut next = average_difficulty * expected_timespan / timespan;
+
+    // bound the step from the last target
+    let last_target = last.difficulty_target as u64;
+    let max_step = ::std::cmp::max( last_target / MAX_STEP_DIVISOR, 1 );
+    if next < last_target.saturating_sub(max_step) { next = last_target - max_step }
+    if next > last_target + max_step { next = last_target + max_step }
+
+    if next < 1 { return 1 }
+    if next > u32::MAX as u64 { return u32::MAX }
+    next as u32
+}
+
+/// The median timestamp of the last `median_time_window` blocks.
+/// The timestamp of the next block must be later than this.
+pub fn median_time_past( history: &[DifficultyData], params: &ChainParams ) -> u32 {
+    let window = ::std::cmp::min( params.median_time_window as usize, history.len() );
+    let mut timestamps: Vec<u32> = history[history.len() - window ..].iter().map(|data| data.timestamp).collect();
+    timestamps.sort();
+    timestamps[timestamps.len() / 2]
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history( block_time: u32, difficulty_target: u32, count: u32 ) -> Vec<DifficultyData> {
+        (0..count).map(|i| DifficultyData{ timestamp: i * block_time, difficulty_target }).collect()
+    }
+
+    #[test]
+    fn retarget() {
+        let params = ChainParams::default();
+
+        // on schedule
+        assert_eq!(next_difficulty(&history(params.block_time, 1000, 100), &params), 1000);
+        // blocks slightly too fast
+        assert_eq!(next_difficulty(&history(params.block_time * 20 / 21, 1000, 100), &params), 1052);
+        // blocks twice too fast, the step is bounded
+        assert_eq!(next_difficulty(&history(params.block_time / 2, 1000, 100), &params), 1062);
+        // blocks twice too slow
+        assert_eq!(next_difficulty(&history(params.block_time * 2, 1000, 100), &params), 938);
+        // a small target still moves
+        assert_eq!(next_difficulty(&history(params.block_time * 2, 10, 100), &params), 9);
+        // genesis only
+        assert_eq!(next_difficulty(&history(params.block_time, 1000, 1), &params), 1000);
+    }
+
+    #[test]
+    fn manipulated_timestamps() {
+        let params = ChainParams::default();
+        let mut chain = history(params.block_time, 1000, 100);
+
+        // a miner claims each block took a hundred block times
+        for _ in 0..10 {
+            let last = chain[chain.len() - 1];
+            let next = next_difficulty(&chain, &params);
+            assert!(next as u64 * MAX_STEP_DIVISOR >= last.difficulty_target as u64 * (MAX_STEP_DIVISOR - 1));
+            chain.push(DifficultyData{ timestamp: last.timestamp + params.block_time * 100, difficulty_target: next });
+        }
+        // instead of a quarter after the first block
+        assert!(chain[chain.len() - 1].difficulty_target > 500);
+    }
+
+    #[test]
+    fn median_time() {
+        let params = ChainParams::default();
+        assert_eq!(median_time_past(&history(10, 1000, 1), &params), 0);
+        assert_eq!(median_time_past(&history(10, 1000, 3), &params), 10);
+        // only the last 11 blocks count
+        assert_eq!(median_time_past(&history(10, 1000, 100), &params), 940);
+
+        // the order does not matter
+        let mut shuffled = history(10, 1000, 5);
+        shuffled.swap(0, 4);
+        assert_eq!(median_time_past(&shuffled, &params), 20);
+    }
+}
@@ -0,0 +1,56 @@
+use blockchain::transaction::Value;
+use protocol::protocol_config::ChainParams;
+
+/// The reward of the block at `height`. The reward halves every
+/// `halving_interval` blocks and stops once `max_supply` is reached.
+pub fn block_reward( height: u32, params: &ChainParams ) -> Value {
+    // the genesis block has no reward
+    if height == 0 { return 0 }
+    emitted_supply(height, params) - emitted_supply(height - 1, params)
+}
+
+/// The sum of all block rewards up to and including the block at `height`.
+pub fn emitted_supply( height: u32, params: &ChainParams ) -> Value {
+    let interval = params.halving_interval as u64;
+    let mut remaining_blocks = height as u64;
+    let mut supply: Value = 0;
+    let mut era = 0;
+
+    while remaining_blocks > 0 && era < 64 {
+        let reward = params.initial_reward >> era;
+        if reward == 0 { break }
+
+        let blocks = if remaining_blocks < interval { remaining_blocks } else { interval };
+        supply = supply.saturating_add(reward.saturating_mul(blocks));
+        if supply >= params.max_supply { return params.max_supply }
+
+        remaining_blocks -= blocks;
+        era += 1;
+    }
+    supply
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedule() {
+        let params = ChainParams{ initial_reward: 100, halving_interval: 10, max_supply: 1610, ..ChainParams::default() };
+
+        assert_eq!(block_reward(0, &params), 0);
+        assert_eq!(block_reward(1, &params), 100);
+        assert_eq!(block_reward(10, &params), 100);
+        assert_eq!(block_reward(11, &params), 50);
+        assert_eq!(block_reward(21, &params), 25);
+
+        assert_eq!(emitted_supply(10, &params), 1000);
+        assert_eq!(emitted_supply(20, &params), 1500);
+
+        // the supply is capped
+        assert_eq!(block_reward(25, &params), 10);
+        assert_eq!(block_reward(26, &params), 0);
+        assert_eq!(emitted_supply(1000, &params), 1610);
+    }
+}
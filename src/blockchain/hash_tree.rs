@@ -1,25 +1,171 @@
-// use utils::Hash;
-
-// struct HashTree;
-
-// impl HashTree {
-    
-//     pub fn hash( set: Vec<Hash> ) -> Hash {
-//     	let mut curr_set = set;
-//     	let mut next_set = Vec::new();
-//     	while curr_set.len() > 2{
-//     		for _i in 0..curr_set.len() / 2 {
-    		  	
-//     		}
-//     		curr_set = next_set;
-//     		next_set = Vec::new();
-//     	}
-//     	Hash::zeros()
-
-//     }
-
-//     fn combine(hash_1:Hash, hash_2: Hash) -> Hash{
-//     	Hash::zeros()
-//     }
-
-// }
+use utils::Hash;
+use utils::hash::HashWriter;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::io::{ Error, ErrorKind };
+
+// proofs of trees deeper than this are rejected while reading
+const MAX_PROOF_LENGTH: u8 = 32;
+
+// leaves and inner nodes are hashed with distinct prefixes,
+// such that an inner node cannot be passed off as a leaf
+const LEAF_TAG: u8 = 0;
+const NODE_TAG: u8 = 1;
+
+/// A binary Merkle tree over a block's transactions. The path of a leaf is
+/// the binary representation of its index, which is the transaction id.
+/// Missing leaves of the last level are filled up with zeros.
+/// The leaves are hashed once more with a prefix that inner nodes do not have.
+pub struct HashTree {
+    // levels[0] are the leaves, the last level is the root
+    levels: Vec<Vec<Hash>>
+}
+
+impl HashTree {
+
+    pub fn new( leaves: Vec<Hash> ) -> HashTree {
+        let mut levels = vec![leaves.into_iter().map(hash_leaf).collect::<Vec<Hash>>()];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = {
+                let curr = &levels[levels.len() - 1];
+                curr.chunks(2).map(|pair| {
+                    let right = if pair.len() == 2 { pair[1] } else { Hash::zeros() };
+                    combine(pair[0], right)
+                }).collect()
+            };
+            levels.push(next);
+        }
+        HashTree { levels }
+    }
+
+    pub fn root( &self ) -> Hash {
+        match self.levels[self.levels.len() - 1].first() {
+            Some(root) => *root,
+            None => Hash::zeros(),
+        }
+    }
+
+    /// The inclusion proof of the leaf at `index`.
+    pub fn proof( &self, index: u32 ) -> Option<MerkleProof> {
+        if index as usize >= self.levels[0].len() { return None }
+
+        let mut siblings = Vec::new();
+        let mut curr = index as usize;
+        for level in &self.levels[.. self.levels.len() - 1] {
+            let sibling = curr ^ 1;
+            siblings.push(if sibling < level.len() { level[sibling] } else { Hash::zeros() });
+            curr >>= 1;
+        }
+        Some(MerkleProof{ index, siblings })
+    }
+}
+
+fn hash_leaf( leaf: Hash ) -> Hash {
+    let mut writer = HashWriter::new();
+    LEAF_TAG.write(&mut writer).unwrap();
+    leaf.write(&mut writer).unwrap();
+    writer.finalize()
+}
+
+fn combine( left: Hash, right: Hash ) -> Hash {
+    let mut writer = HashWriter::new();
+    NODE_TAG.write(&mut writer).unwrap();
+    left.write(&mut writer).unwrap();
+    right.write(&mut writer).unwrap();
+    writer.finalize()
+}
+
+/// The siblings on the path from a leaf up to the root, starting at the leaf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: u32,
+    pub siblings: Vec<Hash>
+}
+
+impl MerkleProof {
+
+    /// The root of the tree if `leaf` is at `index`.
+    pub fn root( &self, leaf: Hash ) -> Hash {
+        let mut curr = hash_leaf(leaf);
+        for (height, sibling) in self.siblings.iter().enumerate() {
+            curr = if self.index & (1 << height) != 0 {
+                combine(*sibling, curr)
+            } else {
+                combine(curr, *sibling)
+            };
+        }
+        curr
+    }
+
+    pub fn verify( &self, leaf: Hash, root: Hash ) -> bool {
+        // the index must fit into the tree
+        if self.siblings.len() < 32 && self.index >> self.siblings.len() != 0 { return false }
+        self.root(leaf) == root
+    }
+}
+
+impl Writeable for MerkleProof {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.index.write(writer)?;
+        let length = self.siblings.len() as u8;
+        length.write(writer)?;
+        for sibling in &self.siblings {
+            sibling.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Readable for MerkleProof {
+    fn read(reader: &mut dyn Reader) -> Result<MerkleProof, Error>{
+        let index = u32::read(reader)?;
+        let length = u8::read(reader)?;
+        if length > MAX_PROOF_LENGTH {
+            return Err(Error::new(ErrorKind::InvalidData, "MerkleProof too long"));
+        }
+        let mut siblings = Vec::new();
+        for _ in 0..length {
+            siblings.push(Hash::read(reader)?);
+        }
+        Ok(MerkleProof{ index, siblings })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proofs() {
+        for count in 1..10 {
+            let leaves: Vec<Hash> = (0..count).map(|_| Hash::random()).collect();
+            let tree = HashTree::new(leaves.clone());
+            let root = tree.root();
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(index as u32).unwrap();
+                assert!(proof.verify(*leaf, root));
+                assert!(!proof.verify(Hash::random(), root));
+            }
+            assert!(tree.proof(count).is_none());
+        }
+    }
+
+    #[test]
+    fn shape() {
+        let leaves: Vec<Hash> = (0..3).map(|_| Hash::random()).collect();
+        let tree = HashTree::new(leaves.clone());
+        let left = combine(hash_leaf(leaves[0]), hash_leaf(leaves[1]));
+        let right = combine(hash_leaf(leaves[2]), Hash::zeros());
+        assert_eq!(tree.root(), combine(left, right));
+
+        // a single leaf is the root
+        assert_eq!(HashTree::new(vec![leaves[0]]).root(), hash_leaf(leaves[0]));
+        assert_eq!(HashTree::new(vec![]).root(), Hash::zeros());
+
+        // an inner node does not verify as a leaf one level up
+        let proof = MerkleProof{ index: 0, siblings: vec![right] };
+        assert_eq!(proof.root(left), combine(hash_leaf(left), right));
+        assert!(!proof.verify(left, tree.root()));
+    }
+}
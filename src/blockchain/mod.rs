@@ -1,5 +1,18 @@
 pub mod state_tree;
+pub mod state_tree_store;
 pub mod hash_tree;
 pub mod transaction;
 pub mod block;
+pub mod difficulty;
+pub mod emission;
+pub mod undo;
+pub mod validation;
+pub mod chainstate;
 pub mod blockchain;
+pub mod block_tree;
+pub mod balance_proof;
+pub mod chain_proof;
+pub mod address_index;
+
+#[cfg(test)]
+pub mod test_helpers;
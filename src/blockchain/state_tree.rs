@@ -2,52 +2,83 @@ use std::collections::HashMap;
 use utils::Hash;
 use std::io::Error;
 use utils::hash::Hashable;
-use utils::serializer::{ Writeable, Writer };
+use utils::serializer::{ Reader, Readable, Writeable, Writer };
+use std::io::ErrorKind;
 
 pub struct StateTree {
     pub root_hash: Hash,
     pub head_id: u32,
-    store: Box<StateTreeStore>
+    store: Box<dyn StateTreeStore>
 }
 
 impl StateTree {
     
     pub fn new( genesis_hash: Hash ) -> StateTree {
+        // the memory store never fails
+        StateTree::open(Box::new(DummyStore::new()), genesis_hash).unwrap()
+    }
+
+    /// Opens the tree at the last root committed to the store,
+    /// or at the genesis block if the store is empty.
+    pub fn open( mut store: Box<dyn StateTreeStore>, genesis_hash: Hash ) -> Result<StateTree, Error> {
+        if let Some((root_hash, head_id)) = store.head() {
+            return Ok(StateTree{ root_hash, head_id, store });
+        }
 
         // create a root_node
         let mut root_node = StateTreeNode::new(1);
         // insert genesis hash into root_node
         root_node.left = genesis_hash;
-        
-        // create a store
-        let mut store = DummyStore::new();
-        
+
         // insert root_node into store 
         let root_hash = root_node.hash();
-        store.insert(root_hash, root_node);
+        store.insert(root_hash, root_node)?;
+        store.commit(root_hash, 0)?;
 
         // return a new StateTree instance
-        StateTree {
+        Ok(StateTree {
             head_id : 0,
             root_hash : root_hash,
+            store
+        })
+    }
+
+    /// Rebuilds the paths of the given proofs, which is enough to insert
+    /// further blocks after the head and to prove the proven blocks again.
+    /// `head` must be the proof of the head block.
+    pub fn from_proofs( head: &StateTreeProof, proofs: &[&StateTreeProof] ) -> StateTree {
+        let mut store = DummyStore::new();
+        for proof in proofs.iter().chain([head].iter()) {
+            for (hash, node) in proof.nodes() {
+                store.0.insert(hash, node);
+            }
+        }
+        store.1 = Some((head.root(), head.block_id));
+
+        StateTree {
+            head_id : head.block_id,
+            root_hash : head.root(),
             store: Box::new(store)
         }
     }
 
-    pub fn insert(&mut self, hash: Hash){
+    /// Appends the block hash with the block's PoW level and commits the new root to the store.
+    pub fn insert(&mut self, hash: Hash, level: u8) -> Result<(), Error> {
         
         // insert next hash to the right 
         let insert_id = self.head_id + 1;
 
-        let mut curr_node = self.store.get(self.root_hash);
+        let mut curr_node = self.store.get(self.root_hash)?;
         let mut insert_path = Vec::new();
         
         // check if we need to extend the height
         if is_power_of_two(insert_id){
+            let root_level = curr_node.level();
             curr_node = StateTreeNode::new(curr_node.height + 1);
             curr_node.left = self.root_hash;
+            curr_node.left_level = root_level;
         } else {
-            self.store.delete(self.root_hash);
+            self.store.delete(self.root_hash)?;
         }
 
         insert_path.push(curr_node);
@@ -59,14 +90,14 @@ impl StateTree {
                 if curr_node.right_is_empty(){
                     curr_node = StateTreeNode::new(curr_node.height - 1);
                 } else {
-                    curr_node = self.store.get_and_delete(curr_node.right);
+                    curr_node = self.store.get_and_delete(curr_node.right)?;
                 }
             } else {
                 // go left 
                 if curr_node.left_is_empty(){
                     curr_node = StateTreeNode::new(curr_node.height - 1);
                 } else {
-                    curr_node = self.store.get_and_delete(curr_node.left);
+                    curr_node = self.store.get_and_delete(curr_node.left)?;
                 }
             }
             insert_path.push(curr_node);
@@ -74,31 +105,38 @@ impl StateTree {
         
         // hash the new path from the leaf up to the root
         let mut curr_hash = hash;
+        let mut curr_level = level;
         loop {
             match insert_path.pop() {
                 Some(mut curr_node) => {
                     if get_bit_at(insert_id, curr_node.height - 1){
                         // go right
                         curr_node.right = curr_hash;
+                        curr_node.right_level = curr_level;
                     } else {
                         // go left
                         curr_node.left = curr_hash;
+                        curr_node.left_level = curr_level;
                     }
                     curr_hash = curr_node.hash();
-                    self.store.insert(curr_hash, curr_node);
+                    curr_level = curr_node.level();
+                    self.store.insert(curr_hash, curr_node)?;
                 },
                 None => break,
             }
         }
 
         // update head 
+        self.store.commit(curr_hash, insert_id)?;
         self.root_hash = curr_hash;
         self.head_id = insert_id;
+        Ok(())
     }
 
-    pub fn revert(&mut self){
+    /// Removes the head block and commits the new root to the store.
+    pub fn revert(&mut self) -> Result<(), Error> {
         
-        let mut curr_node = self.store.get_and_delete(self.root_hash);
+        let mut curr_node = self.store.get_and_delete(self.root_hash)?;
         let mut insert_path = Vec::new();
         insert_path.push(curr_node);
 
@@ -108,34 +146,38 @@ impl StateTree {
         while curr_node.height > 1 {
             if get_bit_at(delete_id, curr_node.height - 1){
                 // go right
-                curr_node = self.store.get_and_delete(curr_node.right);
+                curr_node = self.store.get_and_delete(curr_node.right)?;
             } else {
                 // go left 
-                curr_node = self.store.get_and_delete(curr_node.left);
+                curr_node = self.store.get_and_delete(curr_node.left)?;
             }
             insert_path.push(curr_node);
         }
 
         // hash the new path from the leaf up to the root
         let mut curr_hash = Hash::zeros();
+        let mut curr_level = 0;
         loop {
             match insert_path.pop() {
                 Some(mut curr_node) => {
                     if get_bit_at(delete_id, curr_node.height - 1){
                         // go right
                         curr_node.right = curr_hash;
+                        curr_node.right_level = curr_level;
                     } else {
                         // go left
                         curr_node.left = curr_hash;
+                        curr_node.left_level = curr_level;
                     }
                     // insert only non-zero nodes
                     if !curr_node.left_is_empty() {
                         // check if we need to decrease height 
-                        if insert_path.len() == 0 && curr_node.right_is_empty() {
+                        if insert_path.is_empty() && curr_node.right_is_empty() && curr_node.height > 1 {
                             curr_hash = curr_node.left;
                         } else {
                             curr_hash = curr_node.hash();
-                            self.store.insert(curr_hash, curr_node);
+                            curr_level = curr_node.level();
+                            self.store.insert(curr_hash, curr_node)?;
                         }
                     }
                 },
@@ -144,15 +186,215 @@ impl StateTree {
         }
 
         // update head 
+        self.store.commit(curr_hash, delete_id - 1)?;
         self.root_hash = curr_hash;
         self.head_id = delete_id - 1 ;
+        Ok(())
     }
 
-    pub fn root_node(&self) -> StateTreeNode {
+    pub fn root_node(&self) -> Result<StateTreeNode, Error> {
         self.store.get(self.root_hash)
     }
+
+    /// Proves that the block at `block_id` is included under the current root.
+    pub fn prove(&self, block_id: u32) -> Option<StateTreeProof> {
+        if block_id > self.head_id { return None }
+
+        // traverse the path down to the leaf
+        let mut siblings = Vec::new();
+        let mut sibling_levels = Vec::new();
+        let mut curr_node = self.root_node().ok()?;
+        loop {
+            let (next, next_level, sibling, sibling_level) = if get_bit_at(block_id, curr_node.height - 1) {
+                (curr_node.right, curr_node.right_level, curr_node.left, curr_node.left_level)
+            } else {
+                (curr_node.left, curr_node.left_level, curr_node.right, curr_node.right_level)
+            };
+            siblings.push(sibling);
+            sibling_levels.push(sibling_level);
+            if curr_node.height == 1 {
+                siblings.reverse();
+                sibling_levels.reverse();
+                return Some(StateTreeProof{ block_id, block_hash: next, block_level: next_level, siblings, sibling_levels });
+            }
+            curr_node = self.store.get(next).ok()?;
+        }
+    }
+
+    /// The ids of the blocks from `from` to `to` whose PoW level is at least `level`.
+    /// Subtrees below the level are skipped, so the cost is logarithmic per block found.
+    pub fn superblocks(&self, level: u8, from: u32, to: u32) -> Result<Vec<u32>, Error> {
+        let mut block_ids = Vec::new();
+        let root_node = self.root_node()?;
+        self.collect_superblocks(root_node, 0, level, from, to.min(self.head_id), &mut block_ids)?;
+        Ok(block_ids)
+    }
+
+    // appends the superblocks in the subtree of `node`, whose first block is `first_id`
+    fn collect_superblocks(&self, node: StateTreeNode, first_id: u32, level: u8, from: u32, to: u32, block_ids: &mut Vec<u32>) -> Result<(), Error> {
+        let half = 1u64 << (node.height - 1);
+        let children = [(node.left, node.left_level, first_id as u64), (node.right, node.right_level, first_id as u64 + half)];
+        for &(hash, child_level, child_first) in children.iter() {
+            let child_last = child_first + half - 1;
+            if hash == Hash::zeros() || child_level < level || child_last < from as u64 || child_first > to as u64 { continue }
+            if node.height == 1 {
+                block_ids.push(child_first as u32);
+            } else {
+                let child = self.store.get(hash)?;
+                self.collect_superblocks(child, child_first as u32, level, from, to, block_ids)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The proof of the head block at the time `head_id` was the head.
+    pub fn prove_head_at(&self, head_id: u32) -> Option<StateTreeProof> {
+        let mut proof = self.prove(head_id)?;
+
+        // complete subtrees to the left are unchanged, the right was empty
+        proof.siblings.truncate(tree_height(head_id) as usize);
+        proof.sibling_levels.truncate(tree_height(head_id) as usize);
+        for index in 0..proof.siblings.len() {
+            if !get_bit_at(head_id, index as u8) {
+                proof.siblings[index] = Hash::zeros();
+                proof.sibling_levels[index] = 0;
+            }
+        }
+        Some(proof)
+    }
 }
 
+/// A path from a block hash up to a state tree root. The bits of the
+/// block id select the sides of the path, the siblings are ordered from the leaf up.
+/// The PoW levels of the block and of the siblings' subtrees are part of the path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateTreeProof {
+    pub block_id: u32,
+    pub block_hash: Hash,
+    pub block_level: u8,
+    pub siblings: Vec<Hash>,
+    pub sibling_levels: Vec<u8>
+}
+
+impl StateTreeProof {
+
+    // the nodes on the path from the leaf up to the root
+    fn nodes(&self) -> Vec<(Hash, StateTreeNode)> {
+        let mut nodes = Vec::new();
+        let mut curr_hash = self.block_hash;
+        let mut curr_level = self.block_level;
+        for (index, (sibling, sibling_level)) in self.siblings.iter().zip(&self.sibling_levels).enumerate() {
+            let node = path_node(self.block_id, index as u8 + 1, (curr_hash, curr_level), (*sibling, *sibling_level));
+            curr_hash = node.hash();
+            curr_level = node.level();
+            nodes.push((curr_hash, node));
+        }
+        nodes
+    }
+
+    /// Whether the proven block is the last one in the tree.
+    pub fn is_head(&self) -> bool {
+        if self.siblings.len() != tree_height(self.block_id) as usize { return false }
+        // nothing on the right of the path
+        for (index, sibling) in self.siblings.iter().enumerate() {
+            if !get_bit_at(self.block_id, index as u8) && (*sibling != Hash::zeros() || self.sibling_levels[index] != 0) { return false }
+        }
+        true
+    }
+
+    /// The root of the state tree before the proven head block was appended.
+    /// Reverts the path like `StateTree::revert`.
+    pub fn previous_root(&self) -> Hash {
+        let mut curr_hash = Hash::zeros();
+        let mut curr_level = 0;
+        for (index, (sibling, sibling_level)) in self.siblings.iter().zip(&self.sibling_levels).enumerate() {
+            let node = path_node(self.block_id, index as u8 + 1, (curr_hash, curr_level), (*sibling, *sibling_level));
+            if node.left_is_empty() { continue }
+            if index + 1 == self.siblings.len() && node.right_is_empty() && node.height > 1 {
+                curr_hash = node.left;
+            } else {
+                curr_hash = node.hash();
+                curr_level = node.level();
+            }
+        }
+        curr_hash
+    }
+
+    /// The root of the state tree if the proof is valid.
+    pub fn root(&self) -> Hash {
+        match self.nodes().last() {
+            Some(&(root_hash, _)) => root_hash,
+            None => self.block_hash,
+        }
+    }
+
+    pub fn verify(&self, root_hash: Hash) -> bool {
+        // the block id must fit into a tree of this height
+        if self.siblings.is_empty() || self.siblings.len() > 32 { return false }
+        if self.sibling_levels.len() != self.siblings.len() { return false }
+        if self.siblings.len() < 32 && self.block_id >> self.siblings.len() != 0 { return false }
+        self.root() == root_hash
+    }
+}
+
+impl Writeable for StateTreeProof {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.block_id.write(writer)?;
+        self.block_hash.write(writer)?;
+        self.block_level.write(writer)?;
+        let height = self.siblings.len() as u8;
+        height.write(writer)?;
+        for (sibling, sibling_level) in self.siblings.iter().zip(&self.sibling_levels) {
+            sibling.write(writer)?;
+            sibling_level.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Readable for StateTreeProof {
+    fn read(reader: &mut dyn Reader) -> Result<StateTreeProof, Error>{
+        let block_id = u32::read(reader)?;
+        let block_hash = Hash::read(reader)?;
+        let block_level = u8::read(reader)?;
+        let height = u8::read(reader)?;
+        if height > 32 {
+            return Err(Error::new(ErrorKind::InvalidData, "StateTreeProof too long"));
+        }
+        let mut siblings = Vec::new();
+        let mut sibling_levels = Vec::new();
+        for _ in 0..height {
+            siblings.push(Hash::read(reader)?);
+            sibling_levels.push(u8::read(reader)?);
+        }
+        Ok(StateTreeProof{ block_id, block_hash, block_level, siblings, sibling_levels })
+    }
+}
+
+
+// the node at `height` on the path to `block_id`, with `child` on the path and `sibling` beside it,
+// each given by its hash and PoW level
+fn path_node(block_id: u32, height: u8, child: (Hash, u8), sibling: (Hash, u8)) -> StateTreeNode {
+    let mut node = StateTreeNode::new(height);
+    if get_bit_at(block_id, height - 1) {
+        node.left = sibling.0;
+        node.left_level = sibling.1;
+        node.right = child.0;
+        node.right_level = child.1;
+    } else {
+        node.left = child.0;
+        node.left_level = child.1;
+        node.right = sibling.0;
+        node.right_level = sibling.1;
+    }
+    node
+}
+
+// the height of the tree while `head_id` is the head
+fn tree_height(head_id: u32) -> u8 {
+    if head_id < 2 { return 1 }
+    32 - head_id.leading_zeros() as u8
+}
 
 fn is_power_of_two(input: u32) -> bool {
     let mut test = 1;
@@ -172,11 +414,15 @@ fn get_bit_at(input: u32, index: u8) -> bool {
 }
 
 
+/// A node of the state tree. It commits to the maximum PoW level in each of its
+/// subtrees, which is enough to find the superblocks of a level without the headers.
 #[derive(Debug, Copy, Clone)]
 pub struct StateTreeNode {
     left: Hash,
     right: Hash,
-    height: u8
+    height: u8,
+    left_level: u8,
+    right_level: u8
 }
 
 impl StateTreeNode {
@@ -186,9 +432,16 @@ impl StateTreeNode {
             height: height,
             left: Hash::zeros(),
             right: Hash::zeros(),
+            left_level: 0,
+            right_level: 0
         }
     }
 
+    /// The maximum PoW level of the blocks in the node's subtree.
+    pub fn level(&self) -> u8 {
+        self.left_level.max(self.right_level)
+    }
+
     fn left_is_empty(&self) -> bool{
         self.left == Hash::zeros()
     }
@@ -201,55 +454,86 @@ impl StateTreeNode {
 impl Hashable for StateTreeNode {}
 
 impl Writeable for StateTreeNode {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         self.left.write(writer)?;
         self.right.write(writer)?;
-        self.height.write(writer)
+        self.height.write(writer)?;
+        self.left_level.write(writer)?;
+        self.right_level.write(writer)
     }
 }
 
-trait StateTreeStore {
+impl Readable for StateTreeNode {
+    fn read(reader: &mut dyn Reader) -> Result<StateTreeNode, Error>{
+        Ok(StateTreeNode {
+            left: Hash::read(reader)?,
+            right: Hash::read(reader)?,
+            height: u8::read(reader)?,
+            left_level: u8::read(reader)?,
+            right_level: u8::read(reader)?
+        })
+    }
+}
+
+/// The nodes of a state tree by their hash, and the tree's committed root.
+pub trait StateTreeStore {
 
-	fn get(&self, hash: Hash) -> StateTreeNode;
+	fn get(&self, hash: Hash) -> Result<StateTreeNode, Error>;
 
-    fn get_and_delete(&mut self, hash: Hash) -> StateTreeNode;
+    fn get_and_delete(&mut self, hash: Hash) -> Result<StateTreeNode, Error> {
+        let node = self.get(hash)?;
+        self.delete(hash)?;
+        Ok(node)
+    }
+
+	fn insert(&mut self, hash: Hash, node: StateTreeNode) -> Result<(), Error>;
 
-	fn insert(&mut self, hash: Hash, node: StateTreeNode);
+	fn delete(&mut self, hash: Hash) -> Result<(), Error>;
 
-	fn delete(&mut self, hash: Hash);
+    // makes all changes since the last commit durable, with `root_hash` as the new root
+    fn commit(&mut self, root_hash: Hash, head_id: u32) -> Result<(), Error>;
 
+    // the last committed root and head id
+    fn head(&self) -> Option<(Hash, u32)>;
 }
 
+// the error of a node missing in a store
+pub fn missing_node() -> Error {
+    Error::new(ErrorKind::NotFound, "missing state tree node")
+}
 
 
-struct DummyStore(HashMap<Hash,StateTreeNode>);
+struct DummyStore(HashMap<Hash,StateTreeNode>, Option<(Hash, u32)>);
 
 impl DummyStore {
     pub fn new()->DummyStore{
-        DummyStore(HashMap::new())
+        DummyStore(HashMap::new(), None)
     }
 }
 
 impl StateTreeStore for DummyStore{
 
-    fn get(&self, hash: Hash) -> StateTreeNode{
-        *self.0.get(&hash).unwrap()
+    fn get(&self, hash: Hash) -> Result<StateTreeNode, Error>{
+        self.0.get(&hash).cloned().ok_or_else(missing_node)
     }
 
-    fn get_and_delete(&mut self, hash: Hash) -> StateTreeNode{
-        let node = self.get(hash);
-        self.delete(hash);
-        node
-    }
-
-    fn insert(&mut self, hash: Hash, node: StateTreeNode){
-        // println!("Insert Key: {:?}, Value: {:?} ", hash, node );
+    fn insert(&mut self, hash: Hash, node: StateTreeNode) -> Result<(), Error>{
         self.0.insert(hash, node);
+        Ok(())
     }
 
-    fn delete(&mut self, hash: Hash){
-        // println!("Delete Key: {:?} ", hash );
+    fn delete(&mut self, hash: Hash) -> Result<(), Error>{
         self.0.remove(&hash);
+        Ok(())
+    }
+
+    fn commit(&mut self, root_hash: Hash, head_id: u32) -> Result<(), Error>{
+        self.1 = Some((root_hash, head_id));
+        Ok(())
+    }
+
+    fn head(&self) -> Option<(Hash, u32)> {
+        self.1
     }
 } 
 
@@ -275,24 +559,101 @@ mod tests {
         let mut state_tree = StateTree::new(Hash::random());
 
         for _i in [0u8;20].iter(){
-            state_tree.insert(Hash::random());
+            state_tree.insert(Hash::random(), 0).unwrap();
         }
         let hash1 = state_tree.root_hash;
-        println!("root_hash {:?}, head_id {:?}, \nroot_node: {:?}\n", state_tree.root_hash, state_tree.head_id, state_tree.root_node());
+        println!("root_hash {:?}, head_id {:?}, \nroot_node: {:?}\n", state_tree.root_hash, state_tree.head_id, state_tree.root_node().unwrap());
 
         for _i in [0u8;80].iter(){
-            state_tree.insert(Hash::random());
+            state_tree.insert(Hash::random(), 0).unwrap();
         }
-        println!("root_hash {:?}, head_id {:?}, \nroot_node: {:?}\n", state_tree.root_hash, state_tree.head_id, state_tree.root_node());
+        println!("root_hash {:?}, head_id {:?}, \nroot_node: {:?}\n", state_tree.root_hash, state_tree.head_id, state_tree.root_node().unwrap());
 
         for _i in [0u8;80].iter(){
-            state_tree.revert();
+            state_tree.revert().unwrap();
         }
         let hash2 = state_tree.root_hash;
-        println!("root_hash {:?}, head_id {:?}, \nroot_node: {:?}\n", state_tree.root_hash, state_tree.head_id, state_tree.root_node());
+        println!("root_hash {:?}, head_id {:?}, \nroot_node: {:?}\n", state_tree.root_hash, state_tree.head_id, state_tree.root_node().unwrap());
         
         assert_eq!(hash1, hash2);
     }
-}
+    #[test]
+    fn prove() {
+        let mut hashes = vec![Hash::random()];
+        let mut state_tree = StateTree::new(hashes[0]);
+
+        for block_id in 0..50u32 {
+            let root_hash = state_tree.root_hash;
+            for (id, hash) in hashes.iter().enumerate() {
+                let proof = state_tree.prove(id as u32).unwrap();
+                assert_eq!(proof.block_hash, *hash);
+                assert!(proof.verify(root_hash));
+            }
+            assert!(state_tree.prove(block_id + 1).is_none());
 
+            hashes.push(Hash::random());
+            state_tree.insert(hashes[hashes.len() - 1], 0).unwrap();
+        }
 
+        // a proof is bound to its block id and root
+        let mut proof = state_tree.prove(7).unwrap();
+        assert!(!proof.verify(Hash::random()));
+        proof.block_id = 6;
+        assert!(!proof.verify(state_tree.root_hash));
+    }
+    #[test]
+    fn extend_from_proofs() {
+        let mut state_tree = StateTree::new(Hash::random());
+        for _i in 0..20 {
+            state_tree.insert(Hash::random(), 0).unwrap();
+        }
+        let old_root = state_tree.root_hash;
+        let proof = state_tree.prove(5).unwrap();
+        let head = state_tree.prove(20).unwrap();
+        assert!(head.is_head());
+        assert!(!proof.is_head());
+
+        // extend both trees by the same blocks
+        let mut partial_tree = StateTree::from_proofs(&head, &[&proof]);
+        assert_eq!(partial_tree.root_hash, old_root);
+        for _i in 0..30 {
+            let hash = Hash::random();
+            state_tree.insert(hash, 0).unwrap();
+            partial_tree.insert(hash, 0).unwrap();
+        }
+        assert_eq!(partial_tree.root_hash, state_tree.root_hash);
+        assert_eq!(partial_tree.prove(5), state_tree.prove(5));
+
+        // the old head's proof can be recovered from the new tree
+        assert_eq!(state_tree.prove_head_at(20), Some(head));
+    }
+    #[test]
+    fn levels() {
+        let mut state_tree = StateTree::new(Hash::random());
+        let mut levels = vec![0u8];
+        for block_id in 1..100u32 {
+            let previous_root = state_tree.root_hash;
+            let level = (block_id * 7 % 13 % 5) as u8;
+            state_tree.insert(Hash::random(), level).unwrap();
+            levels.push(level);
+
+            let head = state_tree.prove(block_id).unwrap();
+            assert_eq!(head.block_level, level);
+            assert_eq!(head.previous_root(), previous_root);
+        }
+        assert_eq!(state_tree.root_node().unwrap().level(), 4);
+
+        // the superblocks are found by the levels of the subtrees
+        for level in 0..6 {
+            let expected: Vec<u32> = (10..80).filter(|id| levels[*id as usize] >= level).collect();
+            assert_eq!(state_tree.superblocks(level, 10, 79).unwrap(), expected);
+        }
+
+        // reverting restores the levels of the remaining blocks
+        let root_hash = state_tree.root_hash;
+        state_tree.insert(Hash::random(), 9).unwrap();
+        assert_eq!(state_tree.root_node().unwrap().level(), 9);
+        state_tree.revert().unwrap();
+        assert_eq!(state_tree.root_hash, root_hash);
+    }
+}
@@ -0,0 +1,302 @@
+use blockchain::state_tree::{ StateTreeNode, StateTreeStore, missing_node };
+use utils::Hash;
+use utils::serializer::{ Readable, Writeable };
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ Error, ErrorKind, Read, Seek, SeekFrom, Write };
+use std::path::{ Path, PathBuf };
+
+// the kinds of log records
+const INSERT: u8 = 1;
+const DELETE: u8 = 2;
+
+// a record's tag and hash, followed by the node for insertions
+const RECORD_HEADER_SIZE: u64 = 1 + 32;
+const NODE_SIZE: u64 = 32 + 32 + 1 + 2;
+
+// the head file: log generation, committed log length, root hash, head id
+const HEAD_SIZE: usize = 4 + 8 + 32 + 4;
+
+/// A state tree store backed by a directory. Insertions and deletions are appended
+/// to a node log, and an in-memory index maps every live node to its record.
+/// A commit syncs the log and then atomically replaces the head file, which names
+/// the committed root and the committed length of the log. Records after that length
+/// are discarded when reopening, so a crash loses at most the uncommitted changes.
+/// Once the log holds more dead records than live nodes, a commit compacts it into
+/// a new generation of the log.
+pub struct FileStore {
+    path: PathBuf,
+    // the generation of the current log file
+    generation: u32,
+    log: RefCell<File>,
+    log_length: u64,
+    // the offset of every live node's record
+    index: HashMap<Hash, u64>,
+    // the records of deleted nodes and of deletions
+    garbage: u64,
+    head: Option<(Hash, u32)>
+}
+
+impl FileStore {
+
+    /// Opens the store in the directory `path`, creating it if necessary.
+    pub fn open<P: AsRef<Path>>( path: P ) -> Result<FileStore, Error> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        let (generation, log_length, head) = match File::open(path.join("head")) {
+            Ok(mut file) => {
+                let mut bytes = [0u8; HEAD_SIZE];
+                file.read_exact(&mut bytes)?;
+                let reader = &mut &bytes[..];
+                let generation = u32::read(reader)?;
+                let log_length = u64::read(reader)?;
+                let root_hash = Hash::read(reader)?;
+                let head_id = u32::read(reader)?;
+                (generation, log_length, Some((root_hash, head_id)))
+            },
+            Err(ref err) if err.kind() == ErrorKind::NotFound => (0, 0, None),
+            Err(err) => return Err(err),
+        };
+
+        let log = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(log_path(&path, generation))?;
+        // discard the uncommitted records
+        if log.metadata()?.len() < log_length { return Err(Error::new(ErrorKind::InvalidData, "truncated state tree log")) }
+        log.set_len(log_length)?;
+
+        let mut store = FileStore {
+            path,
+            generation,
+            log: RefCell::new(log),
+            log_length,
+            index: HashMap::new(),
+            garbage: 0,
+            head
+        };
+        store.replay()?;
+        Ok(store)
+    }
+
+    // rebuilds the index from the log
+    fn replay( &mut self ) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        {
+            let mut log = self.log.borrow_mut();
+            log.seek(SeekFrom::Start(0))?;
+            log.read_to_end(&mut bytes)?;
+        }
+
+        let reader = &mut &bytes[..];
+        let mut offset = 0;
+        while offset < self.log_length {
+            let tag = u8::read(reader)?;
+            let hash = Hash::read(reader)?;
+            match tag {
+                INSERT => {
+                    StateTreeNode::read(reader)?;
+                    if self.index.insert(hash, offset).is_some() { self.garbage += 1 }
+                    offset += RECORD_HEADER_SIZE + NODE_SIZE;
+                },
+                DELETE => {
+                    if self.index.remove(&hash).is_some() { self.garbage += 1 }
+                    self.garbage += 1;
+                    offset += RECORD_HEADER_SIZE;
+                },
+                _ => return Err(Error::new(ErrorKind::InvalidData, "invalid state tree log record")),
+            }
+        }
+        Ok(())
+    }
+
+    fn append( &mut self, tag: u8, hash: Hash, node: Option<StateTreeNode> ) -> Result<u64, Error> {
+        let mut bytes = Vec::new();
+        tag.write(&mut bytes)?;
+        hash.write(&mut bytes)?;
+        if let Some(node) = node {
+            node.write(&mut bytes)?;
+        }
+
+        let offset = self.log_length;
+        let mut log = self.log.borrow_mut();
+        log.seek(SeekFrom::Start(offset))?;
+        log.write_all(&bytes)?;
+        self.log_length += bytes.len() as u64;
+        Ok(offset)
+    }
+
+    // the node of the insertion record at `offset` in `log`
+    fn read_node( log: &mut File, offset: u64 ) -> Result<StateTreeNode, Error> {
+        let mut bytes = [0u8; NODE_SIZE as usize];
+        log.seek(SeekFrom::Start(offset + RECORD_HEADER_SIZE))?;
+        log.read_exact(&mut bytes)?;
+        StateTreeNode::read(&mut &bytes[..])
+    }
+
+    // atomically replaces the head file
+    fn write_head( &self, root_hash: Hash, head_id: u32 ) -> Result<(), Error> {
+        let mut bytes = Vec::new();
+        self.generation.write(&mut bytes)?;
+        self.log_length.write(&mut bytes)?;
+        root_hash.write(&mut bytes)?;
+        head_id.write(&mut bytes)?;
+
+        let temporary_path = self.path.join("head.tmp");
+        let mut file = File::create(&temporary_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&temporary_path, self.path.join("head"))?;
+        File::open(&self.path)?.sync_all()
+    }
+
+    // rewrites the live nodes into a new generation of the log, right after a commit
+    fn compact( &mut self ) -> Result<(), Error> {
+        let (root_hash, head_id) = match self.head {
+            Some(head) => head,
+            None => return Ok(()),
+        };
+
+        let generation = self.generation + 1;
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(log_path(&self.path, generation))?;
+        let mut bytes = Vec::new();
+        let mut index = HashMap::new();
+        {
+            let mut old_log = self.log.borrow_mut();
+            for (hash, offset) in &self.index {
+                let node = FileStore::read_node(&mut old_log, *offset)?;
+                index.insert(*hash, bytes.len() as u64);
+                INSERT.write(&mut bytes)?;
+                hash.write(&mut bytes)?;
+                node.write(&mut bytes)?;
+            }
+        }
+        log.write_all(&bytes)?;
+        log.sync_data()?;
+
+        // the new generation is committed once the head file names it
+        let old_generation = self.generation;
+        self.generation = generation;
+        self.log_length = bytes.len() as u64;
+        self.log = RefCell::new(log);
+        self.index = index;
+        self.garbage = 0;
+        self.write_head(root_hash, head_id)?;
+        fs::remove_file(log_path(&self.path, old_generation))
+    }
+}
+
+fn log_path( path: &Path, generation: u32 ) -> PathBuf {
+    path.join(format!("nodes.{:08}.log", generation))
+}
+
+impl StateTreeStore for FileStore {
+
+    fn get(&self, hash: Hash) -> Result<StateTreeNode, Error>{
+        let offset = *self.index.get(&hash).ok_or_else(missing_node)?;
+        FileStore::read_node(&mut self.log.borrow_mut(), offset)
+    }
+
+    fn insert(&mut self, hash: Hash, node: StateTreeNode) -> Result<(), Error>{
+        let offset = self.append(INSERT, hash, Some(node))?;
+        if self.index.insert(hash, offset).is_some() { self.garbage += 1 }
+        Ok(())
+    }
+
+    fn delete(&mut self, hash: Hash) -> Result<(), Error>{
+        if !self.index.contains_key(&hash) { return Ok(()) }
+        self.append(DELETE, hash, None)?;
+        self.index.remove(&hash);
+        self.garbage += 2;
+        Ok(())
+    }
+
+    fn commit(&mut self, root_hash: Hash, head_id: u32) -> Result<(), Error>{
+        self.log.borrow_mut().sync_data()?;
+        self.write_head(root_hash, head_id)?;
+        self.head = Some((root_hash, head_id));
+        if self.garbage > self.index.len() as u64 {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn head(&self) -> Option<(Hash, u32)> {
+        self.head
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::state_tree::StateTree;
+    use std::env;
+
+    fn temporary_path() -> PathBuf {
+        env::temp_dir().join(format!("treecoin-state-tree-{}", Hash::random().to_hex()))
+    }
+
+    #[test]
+    fn reopen() {
+        let path = temporary_path();
+        let genesis_hash = Hash::random();
+        let hashes: Vec<Hash> = (0..100).map(|_| Hash::random()).collect();
+        let mut memory_tree = StateTree::new(genesis_hash);
+        {
+            let mut state_tree = StateTree::open(Box::new(FileStore::open(&path).unwrap()), genesis_hash).unwrap();
+            for hash in &hashes[..60] {
+                state_tree.insert(*hash, 0).unwrap();
+                memory_tree.insert(*hash, 0).unwrap();
+            }
+            assert_eq!(state_tree.root_hash, memory_tree.root_hash);
+        }
+
+        // reopened at the committed root, the genesis hash is ignored
+        let mut state_tree = StateTree::open(Box::new(FileStore::open(&path).unwrap()), Hash::random()).unwrap();
+        assert_eq!(state_tree.head_id, 60);
+        assert_eq!(state_tree.root_hash, memory_tree.root_hash);
+        assert_eq!(state_tree.prove(17), memory_tree.prove(17));
+        for hash in &hashes[60..] {
+            state_tree.insert(*hash, 0).unwrap();
+            memory_tree.insert(*hash, 0).unwrap();
+        }
+        for _ in 0..30 {
+            state_tree.revert().unwrap();
+            memory_tree.revert().unwrap();
+        }
+        assert_eq!(state_tree.root_hash, memory_tree.root_hash);
+        assert_eq!(state_tree.prove(70), memory_tree.prove(70));
+
+        // compaction keeps the log small, only the current generation remains
+        let files: Vec<fs::DirEntry> = fs::read_dir(&path).unwrap().map(|entry| entry.unwrap()).collect();
+        assert_eq!(files.len(), 2);
+        let log_length: u64 = files.iter().map(|file| file.metadata().unwrap().len()).sum();
+        assert!(log_length < 4 * 70 * (RECORD_HEADER_SIZE + NODE_SIZE));
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn discard_uncommitted() {
+        let path = temporary_path();
+        let genesis_hash = Hash::random();
+        let mut store = FileStore::open(&path).unwrap();
+        let mut node = StateTreeNode::read(&mut &[0u8; NODE_SIZE as usize][..]).unwrap();
+        let committed = Hash::random();
+        store.insert(committed, node).unwrap();
+        store.commit(committed, 0).unwrap();
+
+        // a crash before the commit, the record is lost
+        let uncommitted = Hash::random();
+        node = StateTreeNode::read(&mut &[1u8; NODE_SIZE as usize][..]).unwrap();
+        store.insert(uncommitted, node).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.head(), Some((committed, 0)));
+        assert!(store.get(committed).is_ok());
+        assert_eq!(store.get(uncommitted).unwrap_err().kind(), ErrorKind::NotFound);
+        let state_tree = StateTree::open(Box::new(store), genesis_hash).unwrap();
+        assert_eq!(state_tree.root_hash, committed);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
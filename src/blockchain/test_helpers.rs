@@ -0,0 +1,54 @@
+use blockchain::block::{ Block, BlockHeader };
+use blockchain::blockchain::Blockchain;
+use blockchain::transaction::Transaction;
+use pow::cuckoo::Cuckoo;
+use protocol::protocol_config::ChainParams;
+use utils::Hash;
+
+// small graphs, such that blocks are mined in milliseconds
+pub fn test_params() -> ChainParams {
+    ChainParams{ edge_bits: 10, proof_size: 8, ..ChainParams::default() }
+}
+
+pub fn genesis() -> Block {
+    Block::new(BlockHeader::new(Hash::zeros(), 0, 1), Transaction::new(vec![], vec![]))
+}
+
+// commits to the block's transactions and searches a proof of work starting at `seed`
+pub fn mine(block: &mut Block, seed: u64, params: &ChainParams) {
+    block.update_transactions_root();
+    block.header.nonce = seed << 32;
+    loop {
+        block.header.nonce += 1;
+        let cuckoo = Cuckoo::new(block.header.pre_pow_hash(), params.edge_bits, params.proof_size);
+        if let Some(proof) = cuckoo.find_cycle() {
+            if proof.difficulty() >= block.header.difficulty_target as u64 {
+                block.header.proof = proof;
+                return;
+            }
+        }
+    }
+}
+
+// commits to the address index after the block's changes, as far as they are valid
+pub fn commit_address_index(block: &mut Block, blockchain: &Blockchain) {
+    let mut context = blockchain.validation_context();
+    for (transaction_id, transaction) in block.transactions.iter().enumerate() {
+        if context.apply_transaction(transaction, transaction_id as u32 + 1).is_err() { break }
+    }
+    context.apply_outputs(&block.reward, 0).ok();
+    block.header.address_index_root = context.address_index_root();
+}
+
+// a mined block on top of the blockchain's head, on schedule with the block time
+pub fn next_block(blockchain: &Blockchain, reward: Transaction, transactions: Vec<Transaction>, seed: u64) -> Block {
+    let timestamp = (blockchain.block_count() + 1) * blockchain.params.block_time;
+    let header = BlockHeader::new(blockchain.state_hash(), timestamp, blockchain.difficulty_target());
+    let mut block = Block::new(header, reward);
+    for transaction in transactions {
+        block.add_transaction(transaction);
+    }
+    commit_address_index(&mut block, blockchain);
+    mine(&mut block, seed, &blockchain.params);
+    block
+}
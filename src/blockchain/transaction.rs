@@ -6,9 +6,29 @@ pub use utils::serializer::{ Reader, Readable, Writer, Writeable };
 use std::fmt;
 use std::io::{ Error };
 use utils::hex;
+use utils::Hash;
+use utils::hash::HashWriter;
+use crypto::schnorr::{ self, PublicKey, SecretKey };
+use protocol::event::Error as ProtocolError;
+use blockchain::block::MAX_BLOCK_SIZE;
 
 pub type Value = u64;
 
+// output indexes have 8 bits
+pub const MAX_OUTPUTS_COUNT: usize = 1 << 8;
+
+// the bits of a packed `TransactionInput`, as in a path of the state tree
+const BLOCK_ID_BITS: u32 = 39;
+const TRANSACTION_ID_BITS: u32 = 17;
+const OUTPUT_ID_BITS: u32 = 8;
+
+// the serialized size of a `TransactionInput`
+const INPUT_SIZE: usize = 8;
+
+// domain separation of the hashes of a transaction's unsigned data
+const SIGNATURE_HASH_TAG: &[u8] = b"TreeCoin/sighash";
+const TRANSACTION_ID_TAG: &[u8] = b"TreeCoin/txid";
+
 #[derive(Eq, PartialEq, Hash, Clone, Copy)]
 pub struct TransactionInput {
     pub block_id : u32,
@@ -24,27 +44,41 @@ impl TransactionInput {
             output_id : 0
         }
     }
+
+    /// Packs the id into 64 bits: 39 for the block, 17 for the transaction and 8 for the output.
+    /// Fails if an index does not fit.
+    pub fn to_u64(self) -> Option<u64> {
+        if self.transaction_id as u64 >= 1 << TRANSACTION_ID_BITS || self.output_id as u64 >= 1 << OUTPUT_ID_BITS { return None }
+        Some( (self.block_id as u64) << (TRANSACTION_ID_BITS + OUTPUT_ID_BITS)
+            | (self.transaction_id as u64) << OUTPUT_ID_BITS
+            | self.output_id as u64 )
+    }
+
+    // fails for block indexes beyond our 32 bits
+    pub fn from_u64(id: u64) -> Option<TransactionInput> {
+        let block_id = id >> (TRANSACTION_ID_BITS + OUTPUT_ID_BITS);
+        if block_id >= 1 << BLOCK_ID_BITS || block_id > u32::MAX as u64 { return None }
+        Some( TransactionInput {
+            block_id: block_id as u32,
+            transaction_id: ( (id >> OUTPUT_ID_BITS) & ((1 << TRANSACTION_ID_BITS) - 1) ) as u32,
+            output_id: ( id & ((1 << OUTPUT_ID_BITS) - 1) ) as u32
+        })
+    }
 }
 
 impl Readable for TransactionInput {
-    fn read(reader: &mut Reader) -> Result<TransactionInput, Error>{
-        Ok( TransactionInput {
-                block_id : u32::read(reader)?,
-                transaction_id : u32::read(reader)?,
-                output_id : u32::read(reader)? 
-            })
+    fn read(reader: &mut dyn Reader) -> Result<TransactionInput, Error>{
+        TransactionInput::from_u64(u64::read(reader)?).ok_or(ProtocolError::InvalidOutputId.into_io())
     }
 }
 
 impl Writeable for TransactionInput {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
-        self.block_id.write(writer)?;
-        self.transaction_id.write(writer)?;
-        self.output_id.write(writer)
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.to_u64().ok_or(ProtocolError::InvalidOutputId.into_io())?.write(writer)
     }
 } 
 
-#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Address([u8;32]);
 
 impl Address{
@@ -53,18 +87,23 @@ impl Address{
         Address(bytes)
     }
 
-    pub fn from_hex(string : String) -> Address {
-        let byte_vec = hex::from_hex( string ).unwrap();
-        let mut bytes = [0u8;32];
-        for (place, element) in bytes.iter_mut().zip(byte_vec.iter()) {
-            *place = *element;
-        }
-        Address::new(bytes)
-    }
-
     pub fn to_hex(&self) -> String{
         hex::to_hex( self.0.to_vec() )
     }
+
+    pub fn as_bytes(&self) -> &[u8;32] {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0)
+    }
+}
+
+impl From<PublicKey> for Address {
+    fn from(public_key: PublicKey) -> Address {
+        Address(public_key.0)
+    }
 }
 
 impl fmt::Debug for Address {
@@ -74,14 +113,14 @@ impl fmt::Debug for Address {
 }
 
 impl Writeable for Address {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         writer.write_fixed_size(&self.0)?;
         Ok(())
     }
 } 
 
 impl Readable for Address {
-    fn read(reader: &mut Reader) -> Result<Address, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<Address, Error>{
         let mut buf = [0u8;32];
         reader.read_fixed_size(&mut buf)?;
         Ok(Address(buf))
@@ -106,7 +145,7 @@ impl TransactionOutput {
     }
 }
 impl Writeable for TransactionOutput {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         self.address.write(writer)?;
         self.value.write(writer)?;
         self.balance.write(writer)?;
@@ -115,7 +154,7 @@ impl Writeable for TransactionOutput {
 } 
 
 impl Readable for TransactionOutput {
-    fn read(reader: &mut Reader) -> Result<TransactionOutput, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<TransactionOutput, Error>{
         let address = Address::read(reader)?; 
         let value = Value::read(reader)?; 
         let balance = Value::read(reader)?; 
@@ -127,6 +166,7 @@ impl Readable for TransactionOutput {
 pub struct Signature([u8;64]);
 
 impl Signature{
+    #[cfg(test)]
     pub fn new(bytes: [u8;64]) -> Signature{
         Signature(bytes)
     }
@@ -135,8 +175,20 @@ impl Signature{
         hex::to_hex(self.0.to_vec())
     }
 
-    pub fn verify_multi_sig(&self, _addresses: Vec<Address>)  -> EventResult {
-        // Todo: implement multi signature verification...
+    #[cfg(test)]
+    pub fn as_bytes(&self) -> &[u8;64] {
+        &self.0
+    }
+
+    // a MuSig signature by all keys, see `schnorr::sign`
+    pub fn sign(secret_keys: &[SecretKey], message: &Hash) -> Signature {
+        Signature(schnorr::sign(secret_keys, message).unwrap())
+    }
+
+    // verifies the signature by the aggregation of all addresses
+    pub fn verify_multi_sig(&self, message: &Hash, addresses: Vec<Address>)  -> EventResult {
+        let public_keys: Vec<PublicKey> = addresses.iter().map(|address| address.public_key()).collect();
+        if !schnorr::verify(&self.0, &public_keys, message) { return Err(ProtocolError::InvalidSignature) }
         Ok(Event::Nothing)
     }
 }
@@ -148,14 +200,14 @@ impl fmt::Debug for Signature {
 }
 
 impl Writeable for Signature {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         writer.write_fixed_size(&self.0)?;
         Ok(())
     }
 } 
 
 impl Readable for Signature {
-    fn read(reader: &mut Reader) -> Result<Signature, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<Signature, Error>{
         let mut buf = [0u8;64];
         reader.read_fixed_size(&mut buf)?;
         Ok(Signature(buf))
@@ -167,6 +219,8 @@ impl Readable for Signature {
 pub struct Transaction {
     pub inputs: Vec<TransactionInput>,
     pub outputs: Vec<TransactionOutput>,
+    // the transaction is valid only in blocks with this id or later, zero for no lock
+    pub lock_height: u32,
     pub signature: Signature
 }
 
@@ -175,35 +229,73 @@ impl Transaction {
         Transaction{
             inputs:inputs,
             outputs:outputs,
+            lock_height: 0,
             signature: Signature([255u8; 64])
         }
     } 
 
-    // pub fn fee() -> Value {
-    //     unimplemented!();
-    // }
+    // the fee paid to the miner, given the sum of the spent outputs' balances
+    // none if the outputs exceed the inputs
+    pub fn fee(&self, inputs_sum: Value) -> Option<Value> {
+        inputs_sum.checked_sub(self.sum_outputs()?)
+    }
 
+    #[cfg(test)]
     pub fn add_signature(&mut self, signature:Signature) {
         self.signature = signature;
     }
 
-    pub fn sum_outputs(&self) -> Value {
-        let mut sum = 0;
+    /// The message signed by the owners of the inputs. It commits to the inputs, the
+    /// addresses and values of the outputs and the lock height, but neither to the
+    /// balances nor to the signature itself. The balances follow from the chain state
+    /// the transaction is applied to, so a deposit to one of its addresses does not
+    /// invalidate a signed transaction; its balances are updated instead.
+    pub fn signature_hash(&self) -> Hash {
+        self.unsigned_hash(SIGNATURE_HASH_TAG, false)
+    }
+
+    /// Identifies the transaction independent of its signature. It commits to
+    /// the balances, so updating them yields a transaction with another id.
+    /// The `hash` of a transaction commits to its signature, too.
+    pub fn id(&self) -> Hash {
+        self.unsigned_hash(TRANSACTION_ID_TAG, true)
+    }
+
+    fn unsigned_hash(&self, tag: &[u8], with_balances: bool) -> Hash {
+        let mut writer = HashWriter::new();
+        writer.write_fixed_size(tag).unwrap();
+        self.write_unsigned(&mut writer, with_balances).unwrap();
+        writer.finalize()
+    }
+
+    // signs the transaction with the keys of all inputs
+    pub fn sign(&mut self, secret_keys: &[SecretKey]) {
+        self.signature = Signature::sign(secret_keys, &self.signature_hash());
+    }
+
+    // none if the sum overflows
+    pub fn sum_outputs(&self) -> Option<Value> {
+        let mut sum: Value = 0;
         for output in &self.outputs{
-            sum += output.value;
+            sum = sum.checked_add(output.value)?;
         }
-        sum
+        Some(sum)
     }
 
     pub fn new_coinbase( output: TransactionOutput ) -> Transaction{
         let input = TransactionInput::new_coinbase();
         Transaction::new(vec![input], vec![output])
     }
+
+    // a reward spends nothing but the single coinbase input
+    pub fn is_coinbase(&self) -> bool {
+        self.inputs.len() == 1 && self.inputs[0] == TransactionInput::new_coinbase()
+    }
 }
 
 
-impl Writeable for Transaction {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+impl Transaction {
+    fn write_unsigned(&self, writer: &mut dyn Writer, with_balances: bool) -> Result<(), Error>{
         
         // write inputs_count
         let inputs_count: u32 = self.inputs.len() as u32;
@@ -218,9 +310,24 @@ impl Writeable for Transaction {
         // write all outputs
         outputs_count.write(writer)?; 
         for output in &self.outputs {
-            output.write(writer)?;
+            if with_balances {
+                output.write(writer)?;
+            } else {
+                output.address.write(writer)?;
+                output.value.write(writer)?;
+            }
         }
 
+        self.lock_height.write(writer)?;
+
+        Ok(())
+    }
+}
+
+impl Writeable for Transaction {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.write_unsigned(writer, true)?;
+
         // write signature
         self.signature.write(writer)?;
 
@@ -229,10 +336,11 @@ impl Writeable for Transaction {
 } 
 
 impl Readable for Transaction {
-    fn read(reader: &mut Reader) -> Result<Transaction, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<Transaction, Error>{
         
         // read inputs_count
         let inputs_count: u32 = u32::read(reader)?;
+        if inputs_count as usize > MAX_BLOCK_SIZE / INPUT_SIZE { return Err(ProtocolError::TooManyInputs.into_io()) }
         
         // read all inputs  
         let mut inputs = Vec::new();
@@ -242,6 +350,7 @@ impl Readable for Transaction {
 
         // read outputs_count
         let outputs_count: u32 = u32::read(reader)?;
+        if outputs_count as usize > MAX_OUTPUTS_COUNT { return Err(ProtocolError::TooManyOutputs.into_io()) }
         
         // read all outputs  
         let mut outputs = Vec::new();
@@ -249,12 +358,15 @@ impl Readable for Transaction {
             outputs.push(TransactionOutput::read(reader)?);
         }
 
+        let lock_height = u32::read(reader)?;
+
         // read signature
         let signature = Signature::read(reader)?;
         
         Ok(Transaction{
             inputs,
             outputs,
+            lock_height,
             signature
         })
     }
@@ -271,7 +383,7 @@ impl fmt::Debug for TransactionOutput {
 
 impl fmt::Debug for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "\nTransaction:\n\tInputs:{:?}\nOutputs:{:?}\nSignature:\n {:?}", self.inputs, self.outputs, self.signature)
+        write!(f, "\nTransaction:\n\tInputs:{:?}\nOutputs:{:?}\nLock height: {:?}\nSignature:\n {:?}", self.inputs, self.outputs, self.lock_height, self.signature)
     }
 }
 
@@ -279,4 +391,73 @@ impl fmt::Debug for TransactionInput {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:08},{:05},{:03}", self.block_id, self.transaction_id, self.output_id )
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_hash() {
+        let key = SecretKey::new([1u8; 32]);
+        let input = TransactionInput{ block_id: 1, transaction_id: 0, output_id: 0 };
+        let output = TransactionOutput::new(Address::new([2u8; 32]), 10);
+        let mut transaction = Transaction::new(vec![input], vec![output]);
+        let signature_hash = transaction.signature_hash();
+        let id = transaction.id();
+        let hash = transaction.hash();
+
+        // the signature changes neither the message nor the id, only the hash
+        transaction.sign(&[key]);
+        assert_eq!(transaction.signature_hash(), signature_hash);
+        assert_eq!(transaction.id(), id);
+        assert!(transaction.hash() != hash);
+
+        // domain separated
+        assert!(id != signature_hash);
+
+        // the balance is not signed, but identifies the transaction
+        let mut other = transaction.clone();
+        other.outputs[0].balance += 1;
+        assert_eq!(other.signature_hash(), signature_hash);
+        assert!(other.id() != id);
+
+        // the value is signed
+        let mut other = transaction.clone();
+        other.outputs[0].value += 1;
+        assert!(other.signature_hash() != signature_hash);
+
+        // the lock height is signed
+        let mut other = transaction.clone();
+        other.lock_height = 10;
+        assert!(other.signature_hash() != signature_hash);
+        let mut bytes = Vec::new();
+        other.write(&mut bytes).unwrap();
+        assert_eq!(Transaction::read(&mut &bytes[..]).unwrap().lock_height, 10);
+    }
+
+    #[test]
+    fn packed_input() {
+        let input = TransactionInput{ block_id: u32::MAX, transaction_id: (1 << 17) - 1, output_id: 255 };
+        let id = input.to_u64().unwrap();
+        assert_eq!(TransactionInput::from_u64(id), Some(input));
+        let input = TransactionInput{ block_id: 3, transaction_id: 2, output_id: 1 };
+        assert_eq!(input.to_u64(), Some(3 << 25 | 2 << 8 | 1));
+
+        // 8 bytes on the wire
+        let mut bytes = Vec::new();
+        input.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 8);
+        assert_eq!(TransactionInput::read(&mut &bytes[..]).unwrap(), input);
+
+        // indexes that do not fit
+        assert!(TransactionInput{ block_id: 0, transaction_id: 1 << 17, output_id: 0 }.to_u64().is_none());
+        assert!(TransactionInput{ block_id: 0, transaction_id: 0, output_id: 256 }.to_u64().is_none());
+        assert!(TransactionInput{ block_id: 0, transaction_id: 0, output_id: 256 }.write(&mut Vec::new()).is_err());
+        assert!(TransactionInput::from_u64(1 << 57).is_none());
+        let mut bytes = Vec::new();
+        (1u64 << 57).write(&mut bytes).unwrap();
+        assert!(TransactionInput::read(&mut &bytes[..]).is_err());
+    }
+}
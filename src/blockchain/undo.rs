@@ -0,0 +1,197 @@
+use blockchain::transaction::{ TransactionInput, TransactionOutput };
+use blockchain::chainstate::{ with_checksum, verify_checksum };
+use protocol::event::Error as ProtocolError;
+use utils::serializer::{ Reader, Readable, Writer, Writeable, archive_file_name };
+use std::collections::HashMap;
+use std::fs::{ self, File };
+use std::io::{ Error, ErrorKind, Write };
+use std::path::Path;
+
+// the number of most recent blocks whose undo records are kept in memory
+pub const MAX_REORG_DEPTH: u32 = 100;
+
+/// Everything `apply_block` changed in the UTXO set, such that the block can be reverted.
+#[derive(Debug)]
+pub struct BlockUndo {
+    // outputs spent by the block's inputs
+    pub removed: Vec<( TransactionInput, TransactionOutput )>,
+    // outputs aggregated into a new output of the same address
+    pub replaced: Vec<( TransactionInput, TransactionOutput )>,
+    // outputs created by the block
+    pub created: Vec<TransactionInput>
+}
+
+impl BlockUndo {
+    pub fn new() -> BlockUndo {
+        BlockUndo {
+            removed: vec![],
+            replaced: vec![],
+            created: vec![]
+        }
+    }
+}
+
+impl Writeable for BlockUndo {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        write_outputs(&self.removed, writer)?;
+        write_outputs(&self.replaced, writer)?;
+
+        let created_count = self.created.len() as u32;
+        created_count.write(writer)?;
+        for id in &self.created {
+            id.write(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Readable for BlockUndo {
+    fn read(reader: &mut dyn Reader) -> Result<BlockUndo, Error>{
+        let removed = read_outputs(reader)?;
+        let replaced = read_outputs(reader)?;
+
+        let created_count = u32::read(reader)?;
+        let mut created = Vec::new();
+        for _ in 0..created_count {
+            created.push(TransactionInput::read(reader)?);
+        }
+        Ok(BlockUndo{ removed, replaced, created })
+    }
+}
+
+fn write_outputs( outputs: &Vec<( TransactionInput, TransactionOutput )>, writer: &mut dyn Writer ) -> Result<(), Error>{
+    let count = outputs.len() as u32;
+    count.write(writer)?;
+    for &(id, output) in outputs {
+        id.write(writer)?;
+        output.write(writer)?;
+    }
+    Ok(())
+}
+
+fn read_outputs( reader: &mut dyn Reader ) -> Result<Vec<( TransactionInput, TransactionOutput )>, Error>{
+    let count = u32::read(reader)?;
+    let mut outputs = Vec::new();
+    for _ in 0..count {
+        let id = TransactionInput::read(reader)?;
+        let output = TransactionOutput::read(reader)?;
+        outputs.push((id, output));
+    }
+    Ok(outputs)
+}
+
+/// The undo records of the applied blocks by block id. Only the records of the
+/// last `MAX_REORG_DEPTH` blocks are kept in memory. With an archive path all records
+/// are persisted next to the archived blocks, and older ones are read from there.
+pub struct UndoJournal {
+    records: HashMap<u32, BlockUndo>,
+    archive_path: Option<String>
+}
+
+impl UndoJournal {
+
+    pub fn new() -> UndoJournal {
+        UndoJournal {
+            records: HashMap::new(),
+            archive_path: None
+        }
+    }
+
+    pub fn set_archive_path( &mut self, archive_path: String ) {
+        self.archive_path = Some(archive_path);
+    }
+
+    pub fn push( &mut self, block_id: u32, undo: BlockUndo ) -> Result<(), ProtocolError> {
+        if let Some(ref archive_path) = self.archive_path {
+            write_undo(&archive_file_name(archive_path, "undo", block_id), &undo)?;
+        }
+        self.records.insert(block_id, undo);
+        if block_id > MAX_REORG_DEPTH {
+            self.records.remove(&(block_id - MAX_REORG_DEPTH));
+        }
+        Ok(())
+    }
+
+    pub fn pop( &mut self, block_id: u32 ) -> Result<BlockUndo, ProtocolError> {
+        if let Some(undo) = self.records.remove(&block_id) {
+            return Ok(undo);
+        }
+        // after a restart the record is only on disc
+        match self.archive_path {
+            Some(ref archive_path) => Ok(read_undo(&archive_file_name(archive_path, "undo", block_id))?),
+            None => Err(ProtocolError::MissingUndo),
+        }
+    }
+}
+
+// a checksummed record written to a temporary file first, such that a crash
+// leaves either the complete record or none
+fn write_undo( path: &str, undo: &BlockUndo ) -> Result<(), Error> {
+    let mut payload = Vec::new();
+    undo.write(&mut payload)?;
+    let temporary_path = format!("{}.tmp", path);
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(&with_checksum(payload)?)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+    match Path::new(path).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => File::open(directory)?.sync_all(),
+        _ => Ok(()),
+    }
+}
+
+fn read_undo( path: &str ) -> Result<BlockUndo, Error> {
+    let bytes = fs::read(path)?;
+    let payload = verify_checksum(&bytes).ok_or(Error::new(ErrorKind::InvalidData, "invalid undo record"))?;
+    BlockUndo::read(&mut &payload[..])
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::transaction::Address;
+    use utils::Hash;
+    use std::{ env, fs };
+
+    fn undo( block_id: u32 ) -> BlockUndo {
+        let mut undo = BlockUndo::new();
+        let id = TransactionInput{ block_id, transaction_id: 0, output_id: 0 };
+        undo.removed.push(( id, TransactionOutput::new(Address::new([1; 32]), block_id as u64) ));
+        undo
+    }
+
+    #[test]
+    fn reorg_window() {
+        let blocks = MAX_REORG_DEPTH + 10;
+        let mut journal = UndoJournal::new();
+        for block_id in 1..blocks + 1 {
+            journal.push(block_id, undo(block_id)).unwrap();
+        }
+        assert_eq!(journal.records.len(), MAX_REORG_DEPTH as usize);
+        for block_id in (11..blocks + 1).rev() {
+            assert_eq!(journal.pop(block_id).unwrap().removed[0].1.value, block_id as u64);
+        }
+        // older records are only in the archive
+        assert!(journal.pop(10).is_err());
+
+        let path = env::temp_dir().join(format!("treecoin-undo-{}", Hash::random().to_hex()));
+        fs::create_dir_all(&path).unwrap();
+        let mut journal = UndoJournal::new();
+        journal.set_archive_path(format!("{}/", path.to_str().unwrap()));
+        for block_id in 1..blocks + 1 {
+            journal.push(block_id, undo(block_id)).unwrap();
+        }
+        assert_eq!(journal.records.len(), MAX_REORG_DEPTH as usize);
+        for block_id in (1..blocks + 1).rev() {
+            assert_eq!(journal.pop(block_id).unwrap().removed[0].1.value, block_id as u64);
+        }
+
+        // a record torn by a crash is rejected
+        let file_name = archive_file_name(journal.archive_path.as_ref().unwrap(), "undo", 1);
+        let length = fs::metadata(&file_name).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&file_name).unwrap().set_len(length - 1).unwrap();
+        assert!(journal.pop(1).is_err());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
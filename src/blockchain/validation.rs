@@ -0,0 +1,153 @@
+use blockchain::blockchain::UnspentOutputs;
+use blockchain::transaction::{ Address, Transaction, TransactionInput, TransactionOutput };
+use blockchain::undo::BlockUndo;
+use protocol::event::Error;
+use utils::Hash;
+use std::collections::{ HashMap, HashSet };
+
+/// A block's changes to the UTXO set, staged on top of the `UnspentOutputs`
+/// without modifying them. The changes are committed only once the whole block is valid.
+pub struct BlockValidationContext<'a> {
+    base: &'a UnspentOutputs,
+    block_id: u32,
+    // outputs consumed by the block's transactions so far
+    spent: HashSet<TransactionInput>,
+    // the added outputs by address
+    added_by_address: HashMap<Address, TransactionInput>,
+    changes: BlockChanges
+}
+
+/// The staged changes of a `BlockValidationContext`.
+pub struct BlockChanges {
+    // outputs created by the block
+    pub added: HashMap<TransactionInput, TransactionOutput>,
+    // outputs of the base removed by the block
+    pub removed: HashSet<TransactionInput>,
+    pub undo: BlockUndo
+}
+
+impl <'a> BlockValidationContext<'a> {
+
+    pub fn new( base: &'a UnspentOutputs, block_id: u32 ) -> BlockValidationContext<'a> {
+        BlockValidationContext {
+            base,
+            block_id,
+            spent: HashSet::new(),
+            added_by_address: HashMap::new(),
+            changes: BlockChanges {
+                added: HashMap::new(),
+                removed: HashSet::new(),
+                undo: BlockUndo::new()
+            }
+        }
+    }
+
+    pub fn block_id(&self) -> u32 {
+        self.block_id
+    }
+
+    pub fn get(&self, id: &TransactionInput) -> Option<TransactionOutput> {
+        if let Some(output) = self.changes.added.get(id) {
+            return Some(*output);
+        }
+        if self.changes.removed.contains(id) { return None }
+        self.base.get(id)
+    }
+
+    pub fn get_id_by_address(&self, address: Address) -> Option<TransactionInput> {
+        if let Some(id) = self.added_by_address.get(&address) {
+            return Some(*id);
+        }
+        match self.base.get_id_by_address(address) {
+            Some(id) => if self.changes.removed.contains(&id) { None } else { Some(id) },
+            None => None,
+        }
+    }
+
+    // whether an earlier transaction of the block spent the output
+    pub fn is_spent(&self, id: &TransactionInput) -> bool {
+        self.spent.contains(id)
+    }
+
+    fn remove(&mut self, id: &TransactionInput) -> Option<TransactionOutput> {
+        if let Some(output) = self.changes.added.remove(id) {
+            self.added_by_address.remove(&output.address);
+            return Some(output);
+        }
+        let output = self.get(id)?;
+        self.changes.removed.insert(*id);
+        Some(output)
+    }
+
+    pub fn apply_transaction(&mut self, transaction: &Transaction, transaction_id: u32) -> Result<(), Error> {
+        // remove spent outputs
+        for input in &transaction.inputs {
+            self.spent.insert(*input);
+            if let Some(output) = self.remove(input) {
+                self.changes.undo.removed.push((*input, output));
+            }
+        }
+
+        self.apply_outputs(transaction, transaction_id)
+    }
+
+    /// Adds the transaction's outputs. Every output's balance must be the
+    /// address's balance after the transaction's inputs are spent plus its value.
+    pub fn apply_outputs(&mut self, transaction: &Transaction, transaction_id: u32) -> Result<(), Error> {
+        // add unspent outputs
+        for (output_id, output) in transaction.outputs.iter().enumerate() {
+            let id = TransactionInput{ block_id: self.block_id, transaction_id, output_id: output_id as u32 };
+            // we aggregate all outputs of an address into one
+            // therefore we have to delete all other outputs with the same address
+            let mut old_balance = 0;
+            if let Some(replaced_id) = self.get_id_by_address(output.address) {
+                let replaced = self.remove(&replaced_id).unwrap();
+                old_balance = replaced.balance;
+                self.changes.undo.replaced.push((replaced_id, replaced));
+            }
+            if old_balance.checked_add(output.value) != Some(output.balance) { return Err(Error::InvalidBalance) }
+            self.changes.added.insert(id, *output);
+            self.added_by_address.insert(output.address, id);
+            self.changes.undo.created.push(id);
+        }
+        Ok(())
+    }
+
+    // whether the transaction's balances are the ones `update_balances` sets
+    pub fn verify_balances(&self, transaction: &Transaction) -> bool {
+        let mut expected = transaction.clone();
+        self.update_balances(&mut expected);
+        expected.outputs.iter().zip(&transaction.outputs).all(|(expected, output)| expected.balance == output.balance)
+    }
+
+    /// Sets the balances of the transaction's outputs as `apply_transaction` expects them.
+    pub fn update_balances(&self, transaction: &mut Transaction) {
+        // the balances of the outputs of this transaction by address
+        let mut balances = HashMap::new();
+        for output in &mut transaction.outputs {
+            let old_balance = match balances.get(&output.address) {
+                Some(balance) => *balance,
+                None => match self.get_id_by_address(output.address) {
+                    // a spent output does not count towards the balance
+                    Some(id) => if transaction.inputs.contains(&id) { 0 } else { self.get(&id).unwrap().balance },
+                    None => 0,
+                },
+            };
+            // an overflowing balance is rejected by `apply_outputs`
+            output.balance = old_balance.saturating_add(output.value);
+            balances.insert(output.address, output.balance);
+        }
+    }
+
+    /// The root of the address index after the staged changes.
+    pub fn address_index_root(&self) -> Hash {
+        let mut addresses: HashSet<Address> = self.changes.removed.iter().map(|id| self.base.get(id).unwrap().address).collect();
+        addresses.extend(self.changes.added.values().map(|output| output.address));
+        let updates: Vec<_> = addresses.into_iter().map(|address| (address, self.get_id_by_address(address))).collect();
+        self.base.address_index().root_after(&updates)
+    }
+
+    pub fn into_changes(self) -> BlockChanges {
+        self.changes
+    }
+}
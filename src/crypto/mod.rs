@@ -0,0 +1,10 @@
+extern crate curve25519_dalek;
+extern crate blake2_rfc as blake2;
+
+pub mod schnorr;
+// interactive signing, threshold addresses and the swaps built on them
+// have no interface in the node yet, only the tests use them
+#[cfg(test)]
+pub mod musig;
+#[cfg(test)]
+pub mod threshold;
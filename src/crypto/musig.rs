@@ -0,0 +1,287 @@
+use crypto::curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+use crypto::curve25519_dalek::ristretto::RistrettoPoint;
+use crypto::curve25519_dalek::scalar::Scalar;
+use crypto::schnorr::{ AggregatedKey, AdaptorSignature, PublicKey, SecretKey, challenge, random_scalar, to_signature, verify_with_key };
+use utils::Hash;
+use utils::hash::HashWriter;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::io::{ Error, ErrorKind };
+
+/// The commitment to a nonce, exchanged before the nonces themselves
+/// such that no signer can choose its nonce depending on the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NonceCommitment(pub Hash);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PublicNonce(pub PublicKey);
+
+impl PublicNonce {
+    pub fn commitment(&self) -> NonceCommitment {
+        let mut writer = HashWriter::new();
+        writer.write_fixed_size(&(self.0).0).unwrap();
+        NonceCommitment(writer.finalize())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialSignature(pub [u8;32]);
+
+/// One signer's state in an interactive signing session of a single address
+/// whose key is shared by several signers. The signers exchange their nonce
+/// commitments, then their nonces, then their partial signatures.
+pub struct SigningSession {
+    // this signer's share of the address's signing key
+    secret: Scalar,
+    // the key the signature is verified with
+    key: RistrettoPoint,
+    message: Hash,
+    adaptor: Option<PublicKey>,
+    // taken by the partial signature, two signatures with one nonce reveal our share
+    secret_nonce: Option<Scalar>,
+    public_nonce: PublicNonce,
+    // the commitments of all signers including ours
+    commitments: Vec<NonceCommitment>,
+    // the sum of all nonces
+    nonce_point: Option<RistrettoPoint>
+}
+
+impl SigningSession {
+
+    /// A session to spend from the MuSig aggregation of the participants' keys,
+    /// that is the address `joint_address(participants)`.
+    pub fn joint( secret_key: &SecretKey, participants: &[PublicKey], message: Hash ) -> Option<SigningSession> {
+        let participants_key = AggregatedKey::new(participants)?;
+        let share = participants_key.coefficient(&secret_key.public_key())? * secret_key.as_scalar();
+        SigningSession::new(share, participants_key.public_key(), message)
+    }
+
+    /// A session to spend from `address` given this signer's share of its secret key.
+    pub fn new( share: Scalar, address: PublicKey, message: Hash ) -> Option<SigningSession> {
+        // a transaction's signature is verified by the aggregation of its input addresses
+        let address_key = AggregatedKey::new(&[address])?;
+        let secret_nonce = random_scalar();
+        let public_nonce = PublicNonce(PublicKey::from_point(&(&secret_nonce * &RISTRETTO_BASEPOINT_TABLE)));
+        Some(SigningSession {
+            secret: address_key.coefficient(&address)? * share,
+            key: address_key.point(),
+            message,
+            adaptor: None,
+            secret_nonce: Some(secret_nonce),
+            public_nonce,
+            commitments: vec![],
+            nonce_point: None
+        })
+    }
+
+    /// The signature will be an adaptor signature, completed by the secret of `adaptor`.
+    pub fn set_adaptor( &mut self, adaptor: PublicKey ) {
+        self.adaptor = Some(adaptor);
+    }
+
+    pub fn commitment( &self ) -> NonceCommitment {
+        self.public_nonce.commitment()
+    }
+
+    pub fn nonce( &self ) -> PublicNonce {
+        self.public_nonce
+    }
+
+    // the commitments of all signers, including our own, a replayed commitment is rejected
+    pub fn set_commitments( &mut self, commitments: Vec<NonceCommitment> ) -> bool {
+        if self.secret_nonce.is_none() { return false }
+        if !commitments.contains(&self.commitment()) { return false }
+        for (index, commitment) in commitments.iter().enumerate() {
+            if commitments[..index].contains(commitment) { return false }
+        }
+        self.commitments = commitments;
+        true
+    }
+
+    // the nonces of all signers, in the order of their commitments,
+    // they cannot change once we signed
+    pub fn set_nonces( &mut self, nonces: &[PublicNonce] ) -> bool {
+        if self.secret_nonce.is_none() { return false }
+        if nonces.len() != self.commitments.len() { return false }
+        let mut nonce_point = RistrettoPoint::default();
+        for (nonce, commitment) in nonces.iter().zip(&self.commitments) {
+            if nonce.commitment() != *commitment { return false }
+            nonce_point += match nonce.0.point() {
+                Some(point) => point,
+                None => return false,
+            };
+        }
+        self.nonce_point = Some(nonce_point);
+        true
+    }
+
+    // the challenge once all nonces are known
+    fn challenge( &self ) -> Option<Scalar> {
+        let mut nonce_point = self.nonce_point?;
+        if let Some(adaptor) = self.adaptor {
+            nonce_point += adaptor.point()?;
+        }
+        Some(challenge(&nonce_point, &self.key, &self.message))
+    }
+
+    /// Our partial signature, only once per session.
+    pub fn partial_sign( &mut self ) -> Option<PartialSignature> {
+        let challenge = self.challenge()?;
+        let s = self.secret_nonce.take()? + challenge * self.secret;
+        Some(PartialSignature(s.to_bytes()))
+    }
+
+    fn sum( &self, partials: &[PartialSignature] ) -> Option<Scalar> {
+        let mut s = Scalar::zero();
+        for partial in partials {
+            s += Scalar::from_canonical_bytes(partial.0)?;
+        }
+        Some(s)
+    }
+
+    /// The signature of all partial signatures, if they are valid.
+    pub fn combine( &self, partials: &[PartialSignature] ) -> Option<[u8;64]> {
+        if self.adaptor.is_some() { return None }
+        let signature = to_signature(&self.nonce_point?, &self.sum(partials)?);
+        if !verify_with_key(&signature, &self.key, &self.message) { return None }
+        Some(signature)
+    }
+
+    /// The adaptor signature of all partial signatures, if they are valid.
+    pub fn combine_adaptor( &self, partials: &[PartialSignature] ) -> Option<AdaptorSignature> {
+        let adaptor = self.adaptor?;
+        let adaptor_signature = AdaptorSignature::new(&self.nonce_point?, &self.sum(partials)?);
+        if !adaptor_signature.verify_with_key(&self.key, &self.message, &adaptor) { return None }
+        Some(adaptor_signature)
+    }
+}
+
+/// The address whose key is the MuSig aggregation of the participants' keys.
+pub fn joint_address( participants: &[PublicKey] ) -> Option<PublicKey> {
+    Some(AggregatedKey::new(participants)?.public_key())
+}
+
+impl Writeable for NonceCommitment {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.0.write(writer)
+    }
+}
+
+impl Readable for NonceCommitment {
+    fn read(reader: &mut dyn Reader) -> Result<NonceCommitment, Error>{
+        Ok(NonceCommitment(Hash::read(reader)?))
+    }
+}
+
+impl Writeable for PublicNonce {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        writer.write_fixed_size(&(self.0).0)
+    }
+}
+
+impl Readable for PublicNonce {
+    fn read(reader: &mut dyn Reader) -> Result<PublicNonce, Error>{
+        let mut bytes = [0u8;32];
+        reader.read_fixed_size(&mut bytes)?;
+        let nonce = PublicNonce(PublicKey(bytes));
+        if nonce.0.point().is_none() { return Err(Error::new(ErrorKind::InvalidData, "invalid nonce")) }
+        Ok(nonce)
+    }
+}
+
+impl Writeable for PartialSignature {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        writer.write_fixed_size(&self.0)
+    }
+}
+
+impl Readable for PartialSignature {
+    fn read(reader: &mut dyn Reader) -> Result<PartialSignature, Error>{
+        let mut bytes = [0u8;32];
+        reader.read_fixed_size(&mut bytes)?;
+        if Scalar::from_canonical_bytes(bytes).is_none() { return Err(Error::new(ErrorKind::InvalidData, "invalid partial signature")) }
+        Ok(PartialSignature(bytes))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::schnorr::verify;
+
+    // runs the three rounds of a session between all signers
+    fn run( sessions: &mut [SigningSession] ) -> Vec<PartialSignature> {
+        let commitments: Vec<NonceCommitment> = sessions.iter().map(|session| session.commitment()).collect();
+        let nonces: Vec<PublicNonce> = sessions.iter().map(|session| session.nonce()).collect();
+        for session in sessions.iter_mut() {
+            assert!(session.set_commitments(commitments.clone()));
+            assert!(session.set_nonces(&nonces));
+        }
+        sessions.iter_mut().map(|session| session.partial_sign().unwrap()).collect()
+    }
+
+    #[test]
+    fn joint_signature() {
+        let message = Hash::new([7u8; 32]);
+        let secret_keys = [SecretKey::new([1u8; 32]), SecretKey::new([2u8; 32])];
+        let participants: Vec<PublicKey> = secret_keys.iter().map(|key| key.public_key()).collect();
+        let address = joint_address(&participants).unwrap();
+
+        let mut sessions: Vec<SigningSession> = secret_keys.iter()
+            .map(|key| SigningSession::joint(key, &participants, message).unwrap())
+            .collect();
+        let partials = run(&mut sessions);
+        let signature = sessions[0].combine(&partials).unwrap();
+        assert!(verify(&signature, &[address], &message));
+
+        // a missing partial signature
+        assert!(sessions[0].combine(&partials[..1]).is_none());
+
+        // the nonce is used up, neither another signature nor other nonces are accepted
+        assert!(sessions[0].partial_sign().is_none());
+        let other = SigningSession::joint(&secret_keys[1], &participants, message).unwrap();
+        let ( commitment, nonce ) = ( sessions[0].commitment(), sessions[0].nonce() );
+        assert!(!sessions[0].set_commitments(vec![commitment, other.commitment()]));
+        assert!(!sessions[0].set_nonces(&[nonce, other.nonce()]));
+        assert!(sessions[0].partial_sign().is_none());
+    }
+
+    #[test]
+    fn joint_adaptor_signature() {
+        let message = Hash::new([7u8; 32]);
+        let secret_keys = [SecretKey::new([1u8; 32]), SecretKey::new([2u8; 32])];
+        let participants: Vec<PublicKey> = secret_keys.iter().map(|key| key.public_key()).collect();
+        let address = joint_address(&participants).unwrap();
+        let adaptor_secret = SecretKey::new([9u8; 32]);
+
+        let mut sessions: Vec<SigningSession> = secret_keys.iter().map(|key| {
+            let mut session = SigningSession::joint(key, &participants, message).unwrap();
+            session.set_adaptor(adaptor_secret.public_key());
+            session
+        }).collect();
+        let partials = run(&mut sessions);
+        let adaptor_signature = sessions[1].combine_adaptor(&partials).unwrap();
+        assert!(adaptor_signature.verify(&[address], &message, &adaptor_secret.public_key()));
+
+        let signature = adaptor_signature.complete(&adaptor_secret).unwrap();
+        assert!(verify(&signature, &[address], &message));
+        assert_eq!(adaptor_signature.extract(&signature).unwrap().public_key(), adaptor_secret.public_key());
+    }
+
+    #[test]
+    fn reject_wrong_nonce() {
+        let message = Hash::new([7u8; 32]);
+        let secret_keys = [SecretKey::new([1u8; 32]), SecretKey::new([2u8; 32])];
+        let participants: Vec<PublicKey> = secret_keys.iter().map(|key| key.public_key()).collect();
+        let mut sessions: Vec<SigningSession> = secret_keys.iter()
+            .map(|key| SigningSession::joint(key, &participants, message).unwrap())
+            .collect();
+
+        let commitments = vec![sessions[0].commitment(), sessions[1].commitment()];
+        assert!(sessions[0].set_commitments(commitments));
+        // the second nonce does not match its commitment
+        let other = SigningSession::joint(&secret_keys[1], &participants, message).unwrap();
+        let nonces = [sessions[0].nonce(), other.nonce()];
+        assert!(!sessions[0].set_nonces(&nonces));
+    }
+}
@@ -0,0 +1,377 @@
+extern crate rand;
+
+use crypto::curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+use crypto::curve25519_dalek::ristretto::{ CompressedRistretto, RistrettoPoint };
+use crypto::curve25519_dalek::scalar::Scalar;
+use crypto::blake2::blake2b::Blake2b;
+use utils::Hash;
+use utils::hex;
+
+// domain separation of the hashes
+const KEYS_TAG: &[u8] = b"TreeCoin/keys";
+const COEFFICIENT_TAG: &[u8] = b"TreeCoin/coefficient";
+const NONCE_TAG: &[u8] = b"TreeCoin/nonce";
+const CHALLENGE_TAG: &[u8] = b"TreeCoin/challenge";
+
+/// A Schnorr secret key, a scalar of the Ristretto group.
+#[derive(Clone)]
+pub struct SecretKey(Scalar);
+
+impl SecretKey {
+
+    pub fn new(bytes: [u8;32]) -> SecretKey {
+        SecretKey(Scalar::from_bytes_mod_order(bytes))
+    }
+
+    #[cfg(test)]
+    pub fn random() -> SecretKey {
+        SecretKey(random_scalar())
+    }
+
+    pub fn from_hex(string: String) -> SecretKey {
+        let byte_vec = hex::from_hex(string).unwrap();
+        let mut bytes = [0u8;32];
+        for (place, element) in bytes.iter_mut().zip(byte_vec.iter()) {
+            *place = *element;
+        }
+        SecretKey::new(bytes)
+    }
+
+    #[cfg(test)]
+    pub fn from_scalar(scalar: Scalar) -> SecretKey {
+        SecretKey(scalar)
+    }
+
+    #[cfg(test)]
+    pub fn as_scalar(&self) -> &Scalar {
+        &self.0
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_point(&(&self.0 * &RISTRETTO_BASEPOINT_TABLE))
+    }
+}
+
+#[cfg(test)]
+pub fn random_scalar() -> Scalar {
+    let mut bytes = [0u8;64];
+    bytes[..32].copy_from_slice(&rand::random::<[u8;32]>());
+    bytes[32..].copy_from_slice(&rand::random::<[u8;32]>());
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+/// A compressed Ristretto point, 32 bytes as committed to by an address.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct PublicKey(pub [u8;32]);
+
+impl PublicKey {
+
+    pub fn from_point(point: &RistrettoPoint) -> PublicKey {
+        PublicKey(point.compress().to_bytes())
+    }
+
+    pub fn point(&self) -> Option<RistrettoPoint> {
+        CompressedRistretto(self.0).decompress()
+    }
+}
+
+// hashes the parts to a scalar, with 512 bits to avoid a bias
+pub fn hash_to_scalar(tag: &[u8], parts: &[&[u8]]) -> Scalar {
+    let mut state = Blake2b::new(64);
+    state.update(tag);
+    for part in parts {
+        state.update(part);
+    }
+    let mut bytes = [0u8;64];
+    bytes.copy_from_slice(state.finalize().as_bytes());
+    Scalar::from_bytes_mod_order_wide(&bytes)
+}
+
+// the challenge of a signature with the nonce point `nonce` by the key `key`
+pub fn challenge(nonce: &RistrettoPoint, key: &RistrettoPoint, message: &Hash) -> Scalar {
+    hash_to_scalar(CHALLENGE_TAG, &[nonce.compress().as_bytes(), key.compress().as_bytes(), message.as_bytes()])
+}
+
+/// The MuSig aggregation of a set of public keys into a single key.
+/// Every key is weighted by a coefficient bound to the whole set,
+/// such that no signer can choose its key to cancel out the others.
+pub struct AggregatedKey {
+    // the distinct keys in ascending order
+    keys: Vec<PublicKey>,
+    coefficients: Vec<Scalar>,
+    point: RistrettoPoint
+}
+
+impl AggregatedKey {
+
+    /// Aggregates the keys, duplicates count once. Fails for an empty set or invalid points.
+    pub fn new(keys: &[PublicKey]) -> Option<AggregatedKey> {
+        let mut keys = keys.to_vec();
+        keys.sort();
+        keys.dedup();
+        if keys.is_empty() { return None }
+
+        let mut state = Blake2b::new(32);
+        state.update(KEYS_TAG);
+        for key in &keys {
+            state.update(&key.0);
+        }
+        let keys_hash = state.finalize();
+
+        let mut coefficients = Vec::new();
+        let mut point = RistrettoPoint::default();
+        for key in &keys {
+            let coefficient = hash_to_scalar(COEFFICIENT_TAG, &[keys_hash.as_bytes(), &key.0]);
+            point += coefficient * key.point()?;
+            coefficients.push(coefficient);
+        }
+        Some(AggregatedKey{ keys, coefficients, point })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from_point(&self.point)
+    }
+
+    #[cfg(test)]
+    pub fn point(&self) -> RistrettoPoint {
+        self.point
+    }
+
+    // the coefficient of a key of the set
+    pub fn coefficient(&self, key: &PublicKey) -> Option<Scalar> {
+        let index = self.keys.binary_search(key).ok()?;
+        Some(self.coefficients[index])
+    }
+}
+
+// signs as a signer holding all keys, the nonce point is offset by the adaptor
+fn sign_with(secret_keys: &[SecretKey], message: &Hash, adaptor: Option<&PublicKey>) -> Option<(RistrettoPoint, Scalar)> {
+    let public_keys: Vec<PublicKey> = secret_keys.iter().map(|key| key.public_key()).collect();
+    let aggregated_key = AggregatedKey::new(&public_keys)?;
+    let aggregated_bytes = aggregated_key.public_key();
+    let adaptor_bytes = adaptor.map_or([0u8;32], |adaptor| adaptor.0);
+    let adaptor_point = match adaptor {
+        Some(adaptor) => adaptor.point()?,
+        None => RistrettoPoint::default(),
+    };
+
+    // every distinct key contributes a nonce and a partial signature
+    let mut signers: Vec<(&SecretKey, PublicKey)> = secret_keys.iter().zip(public_keys).collect();
+    signers.sort_by_key(|&(_, public_key)| public_key);
+    signers.dedup_by_key(|&mut (_, public_key)| public_key);
+
+    let nonces: Vec<Scalar> = signers.iter()
+        .map(|&(secret_key, _)| {
+            let mut parts: Vec<&[u8]> = vec![secret_key.0.as_bytes(), &aggregated_bytes.0, message.as_bytes()];
+            if adaptor.is_some() { parts.push(&adaptor_bytes) }
+            hash_to_scalar(NONCE_TAG, &parts)
+        })
+        .collect();
+    let nonce_point = nonces.iter().fold(RistrettoPoint::default(), |sum, nonce| sum + nonce * &RISTRETTO_BASEPOINT_TABLE);
+    let challenge = challenge(&(nonce_point + adaptor_point), &aggregated_key.point, message);
+
+    let mut s = Scalar::zero();
+    for (&(secret_key, public_key), nonce) in signers.iter().zip(nonces) {
+        s += nonce + challenge * aggregated_key.coefficient(&public_key)? * secret_key.0;
+    }
+    Some((nonce_point, s))
+}
+
+pub fn to_signature(nonce_point: &RistrettoPoint, s: &Scalar) -> [u8;64] {
+    let mut signature = [0u8;64];
+    signature[..32].copy_from_slice(nonce_point.compress().as_bytes());
+    signature[32..].copy_from_slice(s.as_bytes());
+    signature
+}
+
+// the nonce point and the scalar of a signature
+fn from_signature(signature: &[u8;64]) -> Option<(RistrettoPoint, Scalar)> {
+    let mut nonce_bytes = [0u8;32];
+    nonce_bytes.copy_from_slice(&signature[..32]);
+    let mut s_bytes = [0u8;32];
+    s_bytes.copy_from_slice(&signature[32..]);
+    Some((CompressedRistretto(nonce_bytes).decompress()?, Scalar::from_canonical_bytes(s_bytes)?))
+}
+
+/// Signs the message with all secret keys of a set, as a signer holding all of them.
+/// The nonces are derived from the keys and the message, such that no randomness can leak a key.
+pub fn sign(secret_keys: &[SecretKey], message: &Hash) -> Option<[u8;64]> {
+    let (nonce_point, s) = sign_with(secret_keys, message, None)?;
+    Some(to_signature(&nonce_point, &s))
+}
+
+/// Verifies a signature of the message by the aggregation of the public keys.
+pub fn verify(signature: &[u8;64], public_keys: &[PublicKey], message: &Hash) -> bool {
+    match AggregatedKey::new(public_keys) {
+        Some(aggregated_key) => verify_with_key(signature, &aggregated_key.point, message),
+        None => false,
+    }
+}
+
+// verifies a signature by the already aggregated `key`
+pub fn verify_with_key(signature: &[u8;64], key: &RistrettoPoint, message: &Hash) -> bool {
+    let (nonce_point, s) = match from_signature(signature) {
+        Some(signature) => signature,
+        None => return false,
+    };
+    // s * G == R + c * X
+    &s * &RISTRETTO_BASEPOINT_TABLE == nonce_point + challenge(&nonce_point, key, message) * key
+}
+
+/// A signature missing the secret of its adaptor point. Completing it with the
+/// secret yields a valid signature, and publishing that signature reveals the secret
+/// to everyone holding the adaptor signature.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdaptorSignature {
+    // the nonce point without the adaptor point
+    pub nonce: PublicKey,
+    pub s: [u8;32]
+}
+
+#[cfg(test)]
+impl AdaptorSignature {
+
+    pub fn new(nonce_point: &RistrettoPoint, s: &Scalar) -> AdaptorSignature {
+        AdaptorSignature{ nonce: PublicKey::from_point(nonce_point), s: s.to_bytes() }
+    }
+
+    /// Verifies that the completion with the secret of `adaptor` is a signature by the public keys.
+    pub fn verify(&self, public_keys: &[PublicKey], message: &Hash, adaptor: &PublicKey) -> bool {
+        match AggregatedKey::new(public_keys) {
+            Some(aggregated_key) => self.verify_with_key(&aggregated_key.point, message, adaptor),
+            None => false,
+        }
+    }
+
+    pub fn verify_with_key(&self, key: &RistrettoPoint, message: &Hash, adaptor: &PublicKey) -> bool {
+        let (nonce_point, adaptor_point, s) = match (self.nonce.point(), adaptor.point(), Scalar::from_canonical_bytes(self.s)) {
+            (Some(nonce_point), Some(adaptor_point), Some(s)) => (nonce_point, adaptor_point, s),
+            _ => return false,
+        };
+        // s * G == R + c * X, with the challenge over R + T
+        &s * &RISTRETTO_BASEPOINT_TABLE == nonce_point + challenge(&(nonce_point + adaptor_point), key, message) * key
+    }
+
+    /// The signature completed with the adaptor's secret.
+    pub fn complete(&self, secret: &SecretKey) -> Option<[u8;64]> {
+        let nonce_point = self.nonce.point()? + &secret.0 * &RISTRETTO_BASEPOINT_TABLE;
+        let s = Scalar::from_canonical_bytes(self.s)? + secret.0;
+        Some(to_signature(&nonce_point, &s))
+    }
+
+    /// The adaptor's secret revealed by the completed signature.
+    pub fn extract(&self, signature: &[u8;64]) -> Option<SecretKey> {
+        let (_, s) = from_signature(signature)?;
+        Some(SecretKey(s - Scalar::from_canonical_bytes(self.s)?))
+    }
+}
+
+/// An adaptor signature by all secret keys of a set, completed by the secret of `adaptor`.
+#[cfg(test)]
+pub fn adaptor_sign(secret_keys: &[SecretKey], message: &Hash, adaptor: &PublicKey) -> Option<AdaptorSignature> {
+    let (nonce_point, s) = sign_with(secret_keys, message, Some(adaptor))?;
+    Some(AdaptorSignature::new(&nonce_point, &s))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(count: u8) -> Vec<SecretKey> {
+        (1..count + 1).map(|i| SecretKey::new([i; 32])).collect()
+    }
+
+    fn public_keys(secret_keys: &[SecretKey]) -> Vec<PublicKey> {
+        secret_keys.iter().map(|key| key.public_key()).collect()
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let message = Hash::new([7u8; 32]);
+        for count in 1..4 {
+            let secret_keys = keys(count);
+            let signature = sign(&secret_keys, &message).unwrap();
+            let mut public_keys = public_keys(&secret_keys);
+            assert!(verify(&signature, &public_keys, &message));
+
+            // the order of the keys does not matter
+            public_keys.reverse();
+            assert!(verify(&signature, &public_keys, &message));
+
+            // other message
+            assert!(!verify(&signature, &public_keys, &Hash::new([8u8; 32])));
+
+            // tampered signature
+            let mut tampered = signature;
+            tampered[40] ^= 1;
+            assert!(!verify(&tampered, &public_keys, &message));
+        }
+
+        // all keys must sign
+        let secret_keys = keys(3);
+        let signature = sign(&secret_keys[..2], &message).unwrap();
+        assert!(!verify(&signature, &public_keys(&secret_keys), &message));
+        assert!(!verify(&signature, &[], &message));
+    }
+
+    #[test]
+    fn adaptor_signatures() {
+        let message = Hash::new([7u8; 32]);
+        let secret_keys = keys(2);
+        let public_keys = public_keys(&secret_keys);
+        let adaptor_secret = SecretKey::new([9u8; 32]);
+        let adaptor = adaptor_secret.public_key();
+
+        let adaptor_signature = adaptor_sign(&secret_keys, &message, &adaptor).unwrap();
+        assert!(adaptor_signature.verify(&public_keys, &message, &adaptor));
+        assert!(!adaptor_signature.verify(&public_keys, &message, &public_keys[0]));
+        assert!(!adaptor_signature.verify(&public_keys, &Hash::new([8u8; 32]), &adaptor));
+
+        // not valid before its completion
+        let incomplete = to_signature(&adaptor_signature.nonce.point().unwrap(), &Scalar::from_canonical_bytes(adaptor_signature.s).unwrap());
+        assert!(!verify(&incomplete, &public_keys, &message));
+
+        // the completion is valid and reveals the secret
+        let signature = adaptor_signature.complete(&adaptor_secret).unwrap();
+        assert!(verify(&signature, &public_keys, &message));
+        assert_eq!(adaptor_signature.extract(&signature).unwrap().public_key(), adaptor);
+
+        // another secret does not complete it
+        let signature = adaptor_signature.complete(&SecretKey::new([10u8; 32])).unwrap();
+        assert!(!verify(&signature, &public_keys, &message));
+    }
+
+    #[test]
+    fn ristretto_vectors() {
+        // the encodings of the first multiples of the base point, from RFC 9496, appendix A.1
+        let multiples = [
+            "e2f2ae0a6abc4e71a884a961c500515f58e30b6aa582dd8db6a65945e08d2d76",
+            "6a493210f7499cd17fecb510ae0cea23a110e8d5b901f8acadd3095c73a3b919",
+            "94741f5d5d52755ece4f23f044ee27d5d1ea1e2bd196b462166b16152a9d0259",
+            "da80862773358b466ffadfe0b3293ab3d9fd53c5ea6c955358f568322daf6a57"
+        ];
+        for (index, encoding) in multiples.iter().enumerate() {
+            let mut scalar = [0u8; 32];
+            scalar[0] = index as u8 + 1;
+            assert_eq!(hex::to_hex(SecretKey::new(scalar).public_key().0.to_vec()), *encoding);
+        }
+    }
+
+    // not published vectors, the values of this implementation pin the
+    // hashing of keys and challenges against unnoticed changes
+    #[test]
+    fn regression_vectors() {
+        let message = Hash::new([7u8; 32]);
+
+        let secret_keys = keys(1);
+        assert_eq!(hex::to_hex(secret_keys[0].public_key().0.to_vec()), "3e440469a098036d89ffb2d77a4542928f2f74c2b5769da7480736ace829dc10");
+        assert_eq!(hex::to_hex(sign(&secret_keys, &message).unwrap().to_vec()), "c23ad0eff8760e3de9c4ce90224cf680d3ebab8e5571a83ab139db5c86ebfb798a4fe22846141e17c520039d193639133059b3302cccba18c04e349143cdd404");
+
+        let secret_keys = keys(2);
+        let aggregated_key = AggregatedKey::new(&public_keys(&secret_keys)).unwrap();
+        assert_eq!(hex::to_hex(aggregated_key.public_key().0.to_vec()), "18aa342338db372e4a00c77f25349f9a42da3a14a4c5128501e402355771d013");
+        assert_eq!(hex::to_hex(sign(&secret_keys, &message).unwrap().to_vec()), "026a8af58c597e2302a152d44b797a3f5102c4a11e9452b158a3f31f965c43599a3ba4def4b208f94447aad52a5c30efedc2b99f1c829ab4bc6e0509b2908200");
+    }
+}
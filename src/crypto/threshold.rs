@@ -0,0 +1,371 @@
+use crypto::curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+use crypto::curve25519_dalek::ristretto::RistrettoPoint;
+use crypto::curve25519_dalek::scalar::Scalar;
+use crypto::schnorr::{ PublicKey, SecretKey, random_scalar, sign, verify };
+use crypto::musig::SigningSession;
+use utils::Hash;
+use utils::hash::HashWriter;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::io::{ Error, ErrorKind };
+
+// domain separation of the proofs of knowledge
+const KEY_SETUP_TAG: &[u8] = b"TreeCoin/threshold";
+
+/// A participant's broadcast in the first round of the key setup: the commitments to
+/// the coefficients of its secret polynomial and a proof that it knows the constant one,
+/// such that no participant can choose its polynomial to cancel out the others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyCommitments {
+    pub dealer: u8,
+    pub coefficients: Vec<PublicKey>,
+    pub proof: [u8;64]
+}
+
+/// A participant's private message to participant `index` in the second round of the
+/// key setup: its polynomial evaluated at `index`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyShare {
+    pub dealer: u8,
+    pub index: u8,
+    pub share: [u8;32]
+}
+
+/// One participant's state in the setup of an N-of-M key without a trusted dealer.
+/// Every participant deals shares of its own random secret to all others; the key's
+/// secret is the sum of all these secrets and no participant ever learns it.
+/// Participants are numbered from 1 to M and agree on a fresh session id per setup.
+pub struct KeySetup {
+    session_id: Hash,
+    index: u8,
+    threshold: u8,
+    participants: u8,
+    // our secret polynomial of degree `threshold - 1`
+    polynomial: Vec<Scalar>,
+    // the commitments of all dealers, by dealer
+    commitments: Vec<Option<KeyCommitments>>,
+    // our shares dealt by all dealers, by dealer
+    shares: Vec<Option<Scalar>>
+}
+
+impl KeySetup {
+
+    pub fn new( session_id: Hash, index: u8, threshold: u8, participants: u8 ) -> Option<KeySetup> {
+        if threshold == 0 || threshold > participants || index == 0 || index > participants { return None }
+        let polynomial: Vec<Scalar> = (0..threshold).map(|_| random_scalar()).collect();
+        let mut setup = KeySetup {
+            session_id,
+            index,
+            threshold,
+            participants,
+            polynomial,
+            commitments: vec![None; participants as usize],
+            shares: vec![None; participants as usize]
+        };
+        let commitments = setup.commitments();
+        let share = setup.share_for(index)?;
+        setup.commitments[index as usize - 1] = Some(commitments);
+        setup.shares[index as usize - 1] = Scalar::from_canonical_bytes(share.share);
+        Some(setup)
+    }
+
+    pub fn commitments( &self ) -> KeyCommitments {
+        let coefficients: Vec<PublicKey> = self.polynomial.iter()
+            .map(|coefficient| PublicKey::from_point(&(coefficient * &RISTRETTO_BASEPOINT_TABLE)))
+            .collect();
+        let secret = SecretKey::from_scalar(self.polynomial[0]);
+        let proof = sign(&[secret], &self.proof_message(self.index, &coefficients)).unwrap();
+        KeyCommitments{ dealer: self.index, coefficients, proof }
+    }
+
+    pub fn share_for( &self, index: u8 ) -> Option<KeyShare> {
+        if index == 0 || index > self.participants { return None }
+        let share = evaluate(&self.polynomial, index);
+        Some(KeyShare{ dealer: self.index, index, share: share.to_bytes() })
+    }
+
+    /// Stores the commitments of another dealer, if its proof is valid.
+    /// A dealer commits to one polynomial, shares already checked against it must not change.
+    pub fn add_commitments( &mut self, commitments: KeyCommitments ) -> bool {
+        let dealer = commitments.dealer;
+        if dealer == 0 || dealer > self.participants || commitments.coefficients.len() != self.threshold as usize { return false }
+        if let Some(ref known) = self.commitments[dealer as usize - 1] {
+            return known.coefficients == commitments.coefficients;
+        }
+        let message = self.proof_message(dealer, &commitments.coefficients);
+        if !verify(&commitments.proof, &commitments.coefficients[..1], &message) { return false }
+        self.commitments[dealer as usize - 1] = Some(commitments);
+        true
+    }
+
+    /// Stores our share of another dealer's secret, if it matches the dealer's commitments.
+    pub fn add_share( &mut self, share: KeyShare ) -> bool {
+        if share.index != self.index || share.dealer == 0 || share.dealer > self.participants { return false }
+        let scalar = match Scalar::from_canonical_bytes(share.share) {
+            Some(scalar) => scalar,
+            None => return false,
+        };
+        let expected = match self.commitments[share.dealer as usize - 1] {
+            Some(ref commitments) => match evaluate_commitments(&commitments.coefficients, self.index) {
+                Some(point) => point,
+                None => return false,
+            },
+            None => return false,
+        };
+        if &scalar * &RISTRETTO_BASEPOINT_TABLE != expected { return false }
+        self.shares[share.dealer as usize - 1] = Some(scalar);
+        true
+    }
+
+    // what a dealer's proof of knowledge signs, bound to the setup and to all its commitments,
+    // such that a proof cannot be replayed in another setup or for other commitments
+    fn proof_message( &self, dealer: u8, coefficients: &[PublicKey] ) -> Hash {
+        let mut writer = HashWriter::new();
+        writer.write_fixed_size(KEY_SETUP_TAG).unwrap();
+        self.session_id.write(&mut writer).unwrap();
+        writer.write_fixed_size(&[dealer, self.threshold, self.participants]).unwrap();
+        for coefficient in coefficients {
+            write_key(coefficient, &mut writer).unwrap();
+        }
+        writer.finalize()
+    }
+
+    /// Our share of the key once all dealers' commitments and shares are received.
+    pub fn finish( &self ) -> Option<ThresholdKey> {
+        let mut share = Scalar::zero();
+        let mut point = RistrettoPoint::default();
+        for ( commitments, dealt ) in self.commitments.iter().zip(&self.shares) {
+            point += commitments.as_ref()?.coefficients[0].point()?;
+            share += (*dealt)?;
+        }
+        Some(ThresholdKey {
+            index: self.index,
+            threshold: self.threshold,
+            participants: self.participants,
+            share,
+            address: PublicKey::from_point(&point)
+        })
+    }
+}
+
+/// A participant's share of an N-of-M key. Any `threshold` participants can sign
+/// for the key's address, which is a single key like the key of any other address.
+pub struct ThresholdKey {
+    pub index: u8,
+    pub threshold: u8,
+    pub participants: u8,
+    share: Scalar,
+    address: PublicKey
+}
+
+impl ThresholdKey {
+
+    pub fn address( &self ) -> PublicKey {
+        self.address
+    }
+
+    /// A session to sign the message together with the other `signers`, which include us.
+    /// Our share is weighted such that the shares of exactly these signers sum up to the key.
+    pub fn signing_session( &self, signers: &[u8], message: Hash ) -> Option<SigningSession> {
+        let mut signers = signers.to_vec();
+        signers.sort();
+        signers.dedup();
+        if signers.len() != self.threshold as usize || !signers.contains(&self.index) { return None }
+        if signers.iter().any(|&signer| signer == 0 || signer > self.participants) { return None }
+        SigningSession::new(lagrange_coefficient(self.index, &signers) * self.share, self.address, message)
+    }
+}
+
+// the polynomial at `x`
+fn evaluate( polynomial: &[Scalar], x: u8 ) -> Scalar {
+    let x = Scalar::from(x as u64);
+    polynomial.iter().rev().fold(Scalar::zero(), |sum, coefficient| sum * x + coefficient)
+}
+
+// the committed polynomial at `x`, committed to as well
+fn evaluate_commitments( coefficients: &[PublicKey], x: u8 ) -> Option<RistrettoPoint> {
+    let x = Scalar::from(x as u64);
+    let mut sum = RistrettoPoint::default();
+    for coefficient in coefficients.iter().rev() {
+        sum = sum * x + coefficient.point()?;
+    }
+    Some(sum)
+}
+
+// weights a signer's share to interpolate the secret at zero from the signers' shares
+fn lagrange_coefficient( index: u8, signers: &[u8] ) -> Scalar {
+    let x = Scalar::from(index as u64);
+    let mut numerator = Scalar::one();
+    let mut denominator = Scalar::one();
+    for &signer in signers {
+        if signer == index { continue }
+        let other = Scalar::from(signer as u64);
+        numerator *= other;
+        denominator *= other - x;
+    }
+    numerator * denominator.invert()
+}
+
+fn write_key( key: &PublicKey, writer: &mut dyn Writer ) -> Result<(), Error> {
+    writer.write_fixed_size(&key.0)
+}
+
+fn read_key( reader: &mut dyn Reader ) -> Result<PublicKey, Error> {
+    let mut bytes = [0u8;32];
+    reader.read_fixed_size(&mut bytes)?;
+    let key = PublicKey(bytes);
+    if key.point().is_none() { return Err(Error::new(ErrorKind::InvalidData, "invalid key")) }
+    Ok(key)
+}
+
+impl Writeable for KeyCommitments {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.dealer.write(writer)?;
+        (self.coefficients.len() as u8).write(writer)?;
+        for coefficient in &self.coefficients {
+            write_key(coefficient, writer)?;
+        }
+        writer.write_fixed_size(&self.proof)
+    }
+}
+
+impl Readable for KeyCommitments {
+    fn read(reader: &mut dyn Reader) -> Result<KeyCommitments, Error>{
+        let dealer = u8::read(reader)?;
+        let coefficients_count = u8::read(reader)?;
+        let mut coefficients = Vec::new();
+        for _ in 0..coefficients_count {
+            coefficients.push(read_key(reader)?);
+        }
+        let mut proof = [0u8;64];
+        reader.read_fixed_size(&mut proof)?;
+        Ok(KeyCommitments{ dealer, coefficients, proof })
+    }
+}
+
+impl Writeable for KeyShare {
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+        self.dealer.write(writer)?;
+        self.index.write(writer)?;
+        writer.write_fixed_size(&self.share)
+    }
+}
+
+impl Readable for KeyShare {
+    fn read(reader: &mut dyn Reader) -> Result<KeyShare, Error>{
+        let dealer = u8::read(reader)?;
+        let index = u8::read(reader)?;
+        let mut share = [0u8;32];
+        reader.read_fixed_size(&mut share)?;
+        if Scalar::from_canonical_bytes(share).is_none() { return Err(Error::new(ErrorKind::InvalidData, "invalid share")) }
+        Ok(KeyShare{ dealer, index, share })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::musig::{ NonceCommitment, PublicNonce, PartialSignature };
+    use blockchain::transaction::{ Address, Signature };
+
+    // serializes and deserializes a message, as exchanged between the participants
+    fn transmit<T: Writeable + Readable>( message: &T ) -> T {
+        let mut bytes = Vec::new();
+        message.write(&mut bytes).unwrap();
+        T::read(&mut &bytes[..]).unwrap()
+    }
+
+    // runs the key setup between all participants
+    fn setup( threshold: u8, participants: u8 ) -> Vec<ThresholdKey> {
+        let session_id = Hash::random();
+        let mut setups: Vec<KeySetup> = (1..participants + 1).map(|index| KeySetup::new(session_id, index, threshold, participants).unwrap()).collect();
+        let commitments: Vec<KeyCommitments> = setups.iter().map(|setup| setup.commitments()).collect();
+        for setup in setups.iter_mut() {
+            for commitment in &commitments {
+                assert!(setup.add_commitments(transmit(commitment)));
+            }
+        }
+        for dealer in 0..participants as usize {
+            for index in 1..participants + 1 {
+                let share = setups[dealer].share_for(index).unwrap();
+                assert!(setups[index as usize - 1].add_share(transmit(&share)));
+            }
+        }
+        setups.iter().map(|setup| setup.finish().unwrap()).collect()
+    }
+
+    // runs the signing rounds between the signers
+    fn sign_with( keys: &[&ThresholdKey], message: Hash ) -> Option<[u8;64]> {
+        let signers: Vec<u8> = keys.iter().map(|key| key.index).collect();
+        let mut sessions: Vec<SigningSession> = keys.iter().map(|key| key.signing_session(&signers, message).unwrap()).collect();
+        let commitments: Vec<NonceCommitment> = sessions.iter().map(|session| transmit(&session.commitment())).collect();
+        let nonces: Vec<PublicNonce> = sessions.iter().map(|session| transmit(&session.nonce())).collect();
+        for session in sessions.iter_mut() {
+            assert!(session.set_commitments(commitments.clone()));
+            assert!(session.set_nonces(&nonces));
+        }
+        let partials: Vec<PartialSignature> = sessions.iter_mut().map(|session| transmit(&session.partial_sign().unwrap())).collect();
+        sessions[0].combine(&partials)
+    }
+
+    #[test]
+    fn two_of_three() {
+        let keys = setup(2, 3);
+        let address = keys[0].address();
+        assert!(keys.iter().all(|key| key.address() == address));
+        let message = Hash::new([7u8; 32]);
+
+        // any two participants sign for the address
+        for &( first, second ) in &[ (0, 1), (0, 2), (1, 2) ] {
+            let signature = sign_with(&[&keys[first], &keys[second]], message).unwrap();
+            assert!(verify(&signature, &[address], &message));
+        }
+
+        // the treasury's address verifies like any other address
+        let signature = Signature::new(sign_with(&[&keys[2], &keys[0]], message).unwrap());
+        assert!(signature.verify_multi_sig(&message, vec![Address::from(address)]).is_ok());
+
+        // a single participant cannot sign
+        assert!(keys[0].signing_session(&[1], message).is_none());
+    }
+
+    #[test]
+    fn reject_invalid_setup() {
+        let session_id = Hash::random();
+        let mut alice = KeySetup::new(session_id, 1, 2, 3).unwrap();
+        let bob = KeySetup::new(session_id, 2, 2, 3).unwrap();
+        let carol = KeySetup::new(session_id, 3, 2, 3).unwrap();
+
+        // bob's share without his commitments
+        assert!(!alice.add_share(bob.share_for(1).unwrap()));
+        assert!(alice.add_commitments(bob.commitments()));
+
+        // bob's share for carol, and a share from carol's polynomial claimed as bob's
+        assert!(!alice.add_share(bob.share_for(3).unwrap()));
+        let mut forged = carol.share_for(1).unwrap();
+        forged.dealer = 2;
+        assert!(!alice.add_share(forged));
+        assert!(alice.add_share(bob.share_for(1).unwrap()));
+
+        // bob's commitments delivered again, but no other set of his or of alice herself
+        assert!(alice.add_commitments(bob.commitments()));
+        assert!(!alice.add_commitments(KeySetup::new(session_id, 2, 2, 3).unwrap().commitments()));
+        assert!(!alice.add_commitments(KeySetup::new(session_id, 1, 2, 3).unwrap().commitments()));
+
+        // carol's commitments with a proof for another dealer
+        let mut commitments = carol.commitments();
+        commitments.proof = bob.commitments().proof;
+        assert!(!alice.add_commitments(commitments));
+
+        // carol's commitments from another setup of the same participants
+        let replayed = KeySetup::new(Hash::random(), 3, 2, 3).unwrap().commitments();
+        assert!(!alice.add_commitments(replayed.clone()));
+
+        // carol's proof for other commitments
+        let mut commitments = carol.commitments();
+        commitments.coefficients[1] = replayed.coefficients[1];
+        assert!(!alice.add_commitments(commitments));
+        assert!(alice.add_commitments(carol.commitments()));
+        assert!(alice.finish().is_none());
+    }
+}
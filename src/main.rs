@@ -1,10 +1,16 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
 #[macro_use]
 extern crate serde_derive;
+#[cfg(all(test, feature = "bench"))]
+extern crate test;
 
 mod utils;
 mod protocol;
 mod network;
 mod blockchain;
+mod pow;
+mod crypto;
 mod miner;
 mod archive;
 mod wallet;
@@ -14,6 +20,7 @@ use archive::archive::start_archive;
 use protocol::protocol::{ Protocol };
 use protocol::protocol_config::ProtocolConfig;
 use std::env;
+use std::process;
 
 fn main() {
 	let args: Vec<String> = env::args().collect();
@@ -25,6 +32,12 @@ fn main() {
 
 	start_archive(config.get_archive_address(), config.archive_path.to_string());
 
-	let mut protocol = Protocol::new( config, genesis_block );
+	let mut protocol = match Protocol::new( config, genesis_block ) {
+		Ok(protocol) => protocol,
+		Err(err) => {
+			eprintln!("Failed to open the chain data: {}", err);
+			process::exit(1);
+		}
+	};
 	protocol.start();
 }
\ No newline at end of file
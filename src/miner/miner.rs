@@ -1,17 +1,30 @@
-use blockchain::transaction::Value;
-use std::collections::HashMap;
 use miner::transactions_pool::TransactionsPool;
 use blockchain::blockchain::Blockchain;
+use blockchain::block_tree::{ ChainUpdate, ChainListener };
 use protocol::event::{ EventResult, Event };
 use blockchain::block::{ Block, BlockHeader };
-use blockchain::transaction::{ Transaction, TransactionOutput, Address };
+use blockchain::transaction::{ Transaction, TransactionOutput, Address, Value };
+use pow::cuckoo::{ Cuckoo, CycleSearch, SearchStep };
+use std::time::{ Duration, Instant };
+use utils::clock::{ Clock, SystemClock };
 extern crate rand;
 
+// time spent searching for a proof of work per poll
+const MINING_TIME_SLICE_MS: u64 = 5;
+
+// edges added to the graph of a nonce between checks of the time slice
+const MINING_STEP_EDGES: u64 = 1 << 12;
+
 pub struct Miner {
 	
 	is_active: bool,
 	transactions_pool : TransactionsPool,
-    miner_address : Address
+    miner_address : Address,
+    candidate : Option<Block>,
+    // the search in the graph of the candidate's nonce, it spans several polls
+    search : Option<CycleSearch>,
+    // stamps the composed blocks
+    clock : Box<dyn Clock>
 
 }
 
@@ -22,28 +35,62 @@ impl Miner {
 		Miner {
 			is_active : true,
 			transactions_pool : TransactionsPool::new(),
-            miner_address : miner_address
+            miner_address,
+            candidate : None,
+            search : None,
+            clock : Box::new(SystemClock)
 		}
 		
 	}
 
-    pub fn on_state_update( &mut self, block : &Block, _blockchain: &Blockchain ){
-
-        // delete all spent inputs from transactions pool       
-        self.transactions_pool.delete_spent_inputs(block);
-    }
-
     pub fn poll_new_block( &mut self, blockchain: &Blockchain ) -> EventResult {
     	if !self.is_active { return Ok(Event::Nothing) }
 
-    	// Simulate mining with a dummy 
-    	let random_value: u32 = rand::random();
-		if random_value > 5000000 { return Ok(Event::Nothing) }
-
 		// build a block 
-		let block = self.compose_block( blockchain );
+        if self.candidate.is_none() {
+            let mut block = self.compose_block( blockchain );
+            block.header.nonce = rand::random();
+            self.candidate = Some( block );
+        }
+
+        // search for a proof of work until the time slice is over
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis( MINING_TIME_SLICE_MS ) {
+            if self.search_proof( blockchain ) {
+                let block = self.candidate.take().unwrap();
+                // validate the block before we broadcast it
+                blockchain.verify_block( &block )?;
+                return Ok(Event::BlockMined(block))
+            }
+        }
 
-        Ok(Event::BlockMined(block))
+        Ok(Event::Nothing)
+    }
+
+    // continues the search for a proof of the candidate, moves on to the next nonce
+    // once the graph of the current one has no proof of the difficulty
+    fn search_proof( &mut self, blockchain: &Blockchain ) -> bool {
+        let header = &mut self.candidate.as_mut().unwrap().header;
+        if self.search.is_none() {
+            header.nonce = header.nonce.wrapping_add(1);
+            let params = &blockchain.params;
+            let cuckoo = Cuckoo::new( header.pre_pow_hash(), params.edge_bits, params.proof_size );
+            self.search = Some( CycleSearch::new( cuckoo ) );
+        }
+
+        match self.search.as_mut().unwrap().step( MINING_STEP_EDGES ) {
+            SearchStep::Found( proof ) => {
+                self.search = None;
+                if proof.difficulty() < header.difficulty_target as u64 { return false }
+                header.proof = proof;
+                true
+            },
+            SearchStep::Exhausted => {
+                self.search = None;
+                false
+            },
+            SearchStep::Searching => false,
+        }
     }
 
     pub fn add_transaction_to_pool( &mut self, transaction : Transaction ){
@@ -52,51 +99,78 @@ impl Miner {
 
     fn compose_block( &mut self, blockchain: &Blockchain ) -> Block {
 
-    	// create a dummy block
-        let timestamp = 1234;
-        let block_header = BlockHeader::new(blockchain.state_hash(), timestamp, blockchain.difficulty_target );
+    	// create a block header
+        // a clock behind the chain must not produce invalid blocks
+        let timestamp = ::std::cmp::max( self.clock.now(), blockchain.median_time_past() + 1 );
+        let mut block_header = BlockHeader::new(blockchain.state_hash(), timestamp, blockchain.difficulty_target() );
 
-        // create a coinbase transaction to reward this miner
-        let value = blockchain.current_reward(); 
-        let mut coinbase_output = TransactionOutput::new( self.miner_address, value );
-        coinbase_output.balance = value; // Todo: what if miner_address's balance is non-zero ? 
-        let reward_transaction = Transaction::new_coinbase(coinbase_output);
+        // select valid transactions from transactions pool
+        let (transactions, value) = self.select_transactions( blockchain );
+
+        // stage the block's changes to aggregate the balances
+        let mut context = blockchain.validation_context();
+        for ( transaction_id, transaction ) in transactions.iter().enumerate() {
+            context.apply_transaction( transaction, transaction_id as u32 + 1 ).unwrap();
+        }
+
+        // create a coinbase transaction to reward this miner and claim the fees
+        let mut reward_transaction = Transaction::new_coinbase( TransactionOutput::new( self.miner_address, value ) );
+        // the reward is added to the miner's balance after the block's transactions
+        context.update_balances( &mut reward_transaction );
+        context.apply_outputs( &reward_transaction, 0 ).unwrap();
+        // commit to the address index after the block
+        block_header.address_index_root = context.address_index_root();
 
         // create a new block
     	let mut block = Block::new( block_header , reward_transaction );
-    	
-        // if an address receives multiple outputs in a single block we need to aggregate them
-        let mut state_cache = HashMap::new();
-        // fill the block with transactions from transactions pool
-        loop {
-            match self.transactions_pool.pop() {
-                Some( transaction ) => {
-                    let transaction = self.prepare_transaction( transaction, blockchain, &mut state_cache );
-                    block.add_transaction( transaction );
-                },
-                None => break,
-            }
+        for transaction in transactions {
+            block.add_transaction( transaction );
         }
+        block.update_transactions_root();
 
-        return block
+        block
     }
 
-    fn prepare_transaction( &self, mut transaction: Transaction, blockchain: &Blockchain, state_cache : &mut HashMap<Address,Value> ) -> Transaction{
-        
-        // set resulting balance of every output
-        for output in &mut transaction.outputs {
-            
-            let old_balance = match state_cache.get( &output.address ) {
-                Some( balance ) => *balance,
-                None => blockchain.unspent_outputs.get_balance_by_address( output.address ),
+    // the valid transactions of the pool and the block reward including their fees
+    fn select_transactions( &mut self, blockchain: &Blockchain ) -> ( Vec<Transaction>, Value ) {
+        let mut context = blockchain.validation_context();
+        let mut transactions = Vec::new();
+        // transactions which may be valid in another block
+        let mut skipped = Vec::new();
+        let mut reward = blockchain.current_reward();
+        while let Some( transaction ) = self.transactions_pool.pop() {
+            // the balances must match the outputs aggregated so far,
+            // and a locked transaction waits for a later block
+            if !context.verify_balances( &transaction ) || transaction.lock_height > context.block_id() {
+                skipped.push( transaction );
+                continue
+            }
+            // drop invalid transactions
+            let fee = match blockchain.verify_transaction( &transaction, &context ) {
+                Ok( fee ) => fee,
+                Err( _ ) => continue,
             };
-
-            let new_balance = old_balance + output.value;
-            state_cache.insert(output.address, new_balance);
-            output.balance = new_balance;
+            // the reward must not overflow
+            let next_reward = match reward.checked_add( fee ) {
+                Some( next_reward ) => next_reward,
+                None => {
+                    skipped.push( transaction );
+                    continue
+                },
+            };
+            let transaction_id = transactions.len() as u32 + 1;
+            // the staged changes are incomplete if this fails
+            if context.apply_transaction( &transaction, transaction_id ).is_err() {
+                skipped.push( transaction );
+                break
+            }
+            reward = next_reward;
+            transactions.push( transaction );
         }
-
-        transaction
+        for transaction in skipped {
+            self.transactions_pool.add( transaction );
+        }
+        ( transactions, reward )
     }
 
     pub fn pool_count(&self) -> usize { 
@@ -114,6 +188,32 @@ impl Miner {
 
 
 
+impl ChainListener for Miner {
+
+    fn on_chain_update( &mut self, update: &ChainUpdate, _blockchain: &Blockchain ){
+
+        // the candidate block is outdated, return its transactions to the pool
+        self.search = None;
+        if let Some( candidate ) = self.candidate.take() {
+            for transaction in candidate.transactions {
+                self.transactions_pool.add( transaction );
+            }
+        }
+
+        // transactions of disconnected blocks are unconfirmed again
+        for block in &update.disconnected {
+            for transaction in &block.transactions {
+                self.transactions_pool.add( transaction.clone() );
+            }
+        }
+
+        // delete all spent inputs from transactions pool       
+        for block in &update.connected {
+            self.transactions_pool.delete_spent_inputs(block);
+        }
+    }
+}
+
 // impl EventSource for Miner {
 	
 //     fn poll(&mut self) -> EventResult {
@@ -123,3 +223,55 @@ impl Miner {
 // 		}
 // 	}
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::test_helpers::{ test_params, genesis };
+    use crypto::schnorr::SecretKey;
+    use std::slice;
+
+    fn mine( miner: &mut Miner, blockchain: &Blockchain ) -> Block {
+        loop {
+            if let Event::BlockMined( block ) = miner.poll_new_block( blockchain ).unwrap() { return block }
+        }
+    }
+
+    #[test]
+    fn mine_payment_to_miner() {
+        let alice_key = SecretKey::new([1; 32]);
+        let alice = Address::from( alice_key.public_key() );
+        let bob = Address::from( SecretKey::new([2; 32]).public_key() );
+        let mut blockchain = Blockchain::new( &genesis(), test_params() );
+
+        let mut alice_miner = Miner::new( alice );
+        let block = mine( &mut alice_miner, &blockchain );
+        blockchain.apply_block( &block ).unwrap();
+        let alice_balance = blockchain.unspent_outputs.get_balance_by_address( alice );
+        let alice_id = blockchain.validation_context().get_id_by_address( alice ).unwrap();
+
+        // alice pays bob, who mines the block, and a fee of 1
+        let mut bob_miner = Miner::new( bob );
+        let outputs = vec![ TransactionOutput::new( bob, 5 ), TransactionOutput::new( alice, alice_balance - 6 ) ];
+        let mut payment = Transaction::new( vec![ alice_id ], outputs.clone() );
+        blockchain.validation_context().update_balances( &mut payment );
+        payment.sign( slice::from_ref( &alice_key ) );
+        // the same payment with a balance that does not match
+        let mut mismatch = Transaction::new( vec![ alice_id ], outputs );
+        blockchain.validation_context().update_balances( &mut mismatch );
+        mismatch.outputs[0].balance += 1;
+        mismatch.sign( slice::from_ref( &alice_key ) );
+        bob_miner.add_transaction_to_pool( mismatch );
+        bob_miner.add_transaction_to_pool( payment.clone() );
+
+        let reward = blockchain.current_reward();
+        let block = mine( &mut bob_miner, &blockchain );
+        assert_eq!( block.transactions.len(), 1 );
+        assert_eq!( block.transactions[0].id(), payment.id() );
+        blockchain.apply_block( &block ).unwrap();
+        assert_eq!( blockchain.unspent_outputs.get_balance_by_address( bob ), 5 + reward + 1 );
+
+        // the mismatching transaction is skipped for this block only
+        assert_eq!( bob_miner.pool_count(), 1 );
+    }
+}
@@ -1,11 +1,14 @@
 use blockchain::block::Block;
 use blockchain::transaction::Transaction;
-use std::collections::HashMap;
+use std::collections::{ HashMap, HashSet };
 use blockchain::transaction::TransactionInput;
+use utils::Hash;
 
 pub struct TransactionsPool {
     pool : Vec<Transaction>,
-    input_index: HashMap<TransactionInput, usize>
+    input_index: HashMap<TransactionInput, usize>,
+    // ids of the pooled transactions, a relayed copy with another signature has the same id
+    ids: HashSet<Hash>
 }
 
 impl TransactionsPool {
@@ -13,11 +16,13 @@ impl TransactionsPool {
     pub fn new() -> TransactionsPool {
         TransactionsPool{
             pool : Vec::new(),
-            input_index : HashMap::new()
+            input_index : HashMap::new(),
+            ids : HashSet::new()
         }
     }
     
     pub fn add( &mut self, mut transaction: Transaction ) {
+        if !self.ids.insert( transaction.id() ) { return }
 
         let index = self.pool.len();
 
@@ -42,6 +47,7 @@ impl TransactionsPool {
             for input in &transaction.inputs {
                 self.input_index.remove( &input );
             }
+            self.ids.remove( &transaction.id() );
         }
 
         self.pool.remove( index ); 
@@ -55,6 +61,7 @@ impl TransactionsPool {
                 for input in &transaction.inputs {
                     self.input_index.remove( &input );
                 }
+                self.ids.remove( &transaction.id() );
                 Some(transaction)
             },
             None => None,
@@ -27,7 +27,7 @@ impl <T:Writeable>Message<T> {
 }
 
 impl <T:Writeable> Writeable for Message<T> {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         self.header.write(writer)?;
         self.body.write(writer)?;
         writer.flush()
@@ -52,7 +52,7 @@ impl MessageHeader {
 }
 
 impl Writeable for MessageHeader {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         self.magic_bytes.write(writer)?;
 	    self.message_type.write(writer)?;
 	    Ok(())
@@ -60,7 +60,7 @@ impl Writeable for MessageHeader {
 } 
   
 impl Readable for MessageHeader{
-    fn read(reader: &mut Reader) -> Result<MessageHeader, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<MessageHeader, Error>{
         Ok( MessageHeader{ 
             magic_bytes: u16::read(reader)?, 
             message_type: u32::read(reader)?
@@ -82,7 +82,7 @@ impl EmptyMessageBody {
 }
 
 impl Writeable for EmptyMessageBody {
-     fn write(&self, _writer: &mut Writer) -> Result<(), Error>{
+     fn write(&self, _writer: &mut dyn Writer) -> Result<(), Error>{
         Ok(())
     }
 }
\ No newline at end of file
@@ -88,7 +88,7 @@ impl PeerAddress{
 }
 
 impl Writeable for PeerAddress{
-     fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+     fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         let self_bytes =  self.string.as_bytes();
         let bytes = &mut [0u8;14];
         bytes.copy_from_slice(&self_bytes[0..14]);
@@ -98,7 +98,7 @@ impl Writeable for PeerAddress{
  }
 
  impl Readable for PeerAddress{
-    fn read(reader: &mut Reader) -> Result<PeerAddress, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<PeerAddress, Error>{
         let mut bytes = [0u8;14];
         reader.read_fixed_size(&mut bytes)?;
         PeerAddress::from_utf8(bytes)
@@ -125,7 +125,7 @@ impl PeerInfo {
 }
 
 impl Writeable for PeerInfo {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         self.server_address.write(writer)?;
         self.chain_height.write(writer)?;
         Ok(())
@@ -133,7 +133,7 @@ impl Writeable for PeerInfo {
 } 
   
 impl Readable for PeerInfo{
-    fn read(reader: &mut Reader) -> Result<PeerInfo, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<PeerInfo, Error>{
         Ok( PeerInfo{ 
             server_address: PeerAddress::read(reader)?,
             chain_height: u32::read(reader)?
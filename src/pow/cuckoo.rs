@@ -0,0 +1,355 @@
+use pow::siphash::siphash24;
+use utils::Hash;
+use utils::hash::Hashable;
+use utils::serializer::{ Reader, Readable, Writer, Writeable };
+use std::collections::HashSet;
+use std::io::{ Error, ErrorKind };
+use std::fmt;
+
+// proofs longer than this are rejected while reading
+pub const MAX_PROOF_SIZE: usize = 64;
+
+// the nodes of larger graphs do not fit into u32 next to `NIL`
+pub const MAX_EDGE_BITS: u8 = 30;
+
+// bound on the length of paths followed by the solver
+const MAX_PATH_LENGTH: usize = 8192;
+
+const NIL: u32 = u32::MAX;
+
+/// A Cuckoo Cycle graph. Every nonce below `2^edge_bits` is an edge between
+/// an even node `u` and an odd node `v`, both derived from the header's
+/// pre-pow hash via siphash. A proof is a cycle of `proof_size` edges.
+/// `edge_bits` must be at most `MAX_EDGE_BITS`.
+#[derive(Clone)]
+pub struct Cuckoo {
+	k0: u64,
+	k1: u64,
+	edge_bits: u8,
+	proof_size: usize
+}
+
+impl Cuckoo {
+
+	pub fn new( key: Hash, edge_bits: u8, proof_size: usize ) -> Cuckoo {
+		let bytes = key.as_bytes();
+		Cuckoo {
+			k0: read_u64(&bytes[0..8]),
+			k1: read_u64(&bytes[8..16]),
+			edge_bits,
+			proof_size
+		}
+	}
+
+	fn edges_count(&self) -> u64 {
+		1 << self.edge_bits
+	}
+
+	fn node(&self, nonce: u64, uorv: u64) -> u64 {
+		let mask = self.edges_count() - 1;
+		2 * ( siphash24(self.k0, self.k1, 2 * nonce + uorv) & mask ) + uorv
+	}
+
+	pub fn verify(&self, proof: &Proof) -> bool {
+		let nonces = &proof.0;
+		if nonces.len() != self.proof_size { return false }
+
+		// collect the endpoints of all edges
+		let mut uvs = vec![0u64; 2 * self.proof_size];
+		let mut xor_u = 0;
+		let mut xor_v = 0;
+		for n in 0..self.proof_size {
+			// nonces must be in the graph and strictly ascending
+			if nonces[n] as u64 >= self.edges_count() { return false }
+			if n > 0 && nonces[n] <= nonces[n - 1] { return false }
+			uvs[2 * n] = self.node(nonces[n] as u64, 0);
+			uvs[2 * n + 1] = self.node(nonces[n] as u64, 1);
+			xor_u ^= uvs[2 * n];
+			xor_v ^= uvs[2 * n + 1];
+		}
+		// every node must appear an even number of times
+		if xor_u != 0 || xor_v != 0 { return false }
+
+		// follow the cycle through all edges
+		let mut n = 0;
+		let mut i = 0;
+		loop {
+			let mut j = i;
+			let mut k = ( i + 2 ) % ( 2 * self.proof_size );
+			while k != i {
+				if uvs[k] == uvs[i] {
+					// branch
+					if j != i { return false }
+					j = k;
+				}
+				k = ( k + 2 ) % ( 2 * self.proof_size );
+			}
+			// dead end
+			if j == i { return false }
+			i = j ^ 1;
+			n += 1;
+			if i == 0 { break }
+		}
+		n == self.proof_size
+	}
+
+	/// Searches the graph for a cycle of length `proof_size`.
+	#[cfg(test)]
+	pub fn find_cycle(&self) -> Option<Proof> {
+		let mut search = CycleSearch::new(self.clone());
+		match search.step(self.edges_count()) {
+			SearchStep::Found(proof) => Some(proof),
+			_ => None,
+		}
+	}
+
+	fn recover_nonces(&self, us: &[u32], nu: usize, vs: &[u32], nv: usize) -> Proof {
+		// the cycle's edges as ( even node, odd node ) pairs
+		let mut cycle = HashSet::new();
+		cycle.insert((us[0], vs[0]));
+		// even nodes sit at even positions of the u path and at odd positions of the v path
+		for i in 0..nu {
+			cycle.insert((us[(i + 1) & !1], us[i | 1]));
+		}
+		for i in 0..nv {
+			cycle.insert((vs[i | 1], vs[(i + 1) & !1]));
+		}
+
+		let mut nonces = Vec::new();
+		for nonce in 0..self.edges_count() {
+			let edge = (self.node(nonce, 0) as u32, self.node(nonce, 1) as u32);
+			if cycle.remove(&edge) {
+				nonces.push(nonce as u32);
+			}
+		}
+		Proof(nonces)
+	}
+}
+
+/// The result of a step of a `CycleSearch`.
+pub enum SearchStep {
+	Found(Proof),
+	// the graph has no cycle of the length, or its paths are too long to follow
+	Exhausted,
+	Searching
+}
+
+/// A search for a cycle that adds the graph's edges in steps, such that a
+/// caller can bound the time it spends on a graph before it returns.
+pub struct CycleSearch {
+	cuckoo: Cuckoo,
+	// the directed forest of the edges added so far
+	graph: Vec<u32>,
+	us: Vec<u32>,
+	vs: Vec<u32>,
+	next_nonce: u64
+}
+
+impl CycleSearch {
+
+	pub fn new(cuckoo: Cuckoo) -> CycleSearch {
+		let nodes_count = 2 * cuckoo.edges_count() as usize;
+		CycleSearch {
+			cuckoo,
+			graph: vec![NIL; nodes_count],
+			us: vec![0u32; MAX_PATH_LENGTH],
+			vs: vec![0u32; MAX_PATH_LENGTH],
+			next_nonce: 0
+		}
+	}
+
+	/// Adds up to `max_edges` further edges to the graph.
+	pub fn step(&mut self, max_edges: u64) -> SearchStep {
+		let end = ::std::cmp::min(self.next_nonce.saturating_add(max_edges), self.cuckoo.edges_count());
+		while self.next_nonce < end {
+			let nonce = self.next_nonce;
+			self.next_nonce += 1;
+			match self.add_edge(nonce) {
+				Some(Some(proof)) => return SearchStep::Found(proof),
+				Some(None) => {},
+				None => {
+					self.next_nonce = self.cuckoo.edges_count();
+					return SearchStep::Exhausted;
+				},
+			}
+		}
+		if self.next_nonce == self.cuckoo.edges_count() { SearchStep::Exhausted } else { SearchStep::Searching }
+	}
+
+	// adds the edge and returns the cycle it closes, none if a path is too long
+	fn add_edge(&mut self, nonce: u64) -> Option<Option<Proof>> {
+		let cuckoo = &mut self.graph;
+		let us = &mut self.us;
+		let vs = &mut self.vs;
+		let u0 = self.cuckoo.node(nonce, 0) as u32;
+		let v0 = self.cuckoo.node(nonce, 1) as u32;
+		// skip duplicate edges
+		if cuckoo[u0 as usize] == v0 || cuckoo[v0 as usize] == u0 { return Some(None) }
+
+		us[0] = u0;
+		vs[0] = v0;
+		let mut nu = path(cuckoo, cuckoo[u0 as usize], us)?;
+		let mut nv = path(cuckoo, cuckoo[v0 as usize], vs)?;
+
+		if us[nu] == vs[nv] {
+			// both paths lead to the same root, so we found a cycle
+			let min = if nu < nv { nu } else { nv };
+			nu -= min;
+			nv -= min;
+			while us[nu] != vs[nv] {
+				nu += 1;
+				nv += 1;
+			}
+			if nu + nv + 1 == self.cuckoo.proof_size {
+				return Some(Some(self.cuckoo.recover_nonces(us, nu, vs, nv)));
+			}
+			return Some(None);
+		}
+
+		// reverse the shorter path and link the new edge
+		if nu < nv {
+			while nu > 0 {
+				cuckoo[us[nu] as usize] = us[nu - 1];
+				nu -= 1;
+			}
+			cuckoo[u0 as usize] = v0;
+		} else {
+			while nv > 0 {
+				cuckoo[vs[nv] as usize] = vs[nv - 1];
+				nv -= 1;
+			}
+			cuckoo[v0 as usize] = u0;
+		}
+		Some(None)
+	}
+}
+
+// follows the path from `u` to its root, returns the index of the root in `path`
+fn path(cuckoo: &[u32], mut u: u32, path: &mut [u32]) -> Option<usize> {
+	let mut nu = 0;
+	while u != NIL {
+		nu += 1;
+		if nu >= MAX_PATH_LENGTH { return None }
+		path[nu] = u;
+		u = cuckoo[u as usize];
+	}
+	Some(nu)
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+	let mut result = 0u64;
+	for (i, byte) in bytes.iter().enumerate() {
+		result |= (*byte as u64) << (8 * i);
+	}
+	result
+}
+
+/// The nonces of the edges forming a cycle, in ascending order.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Proof(pub Vec<u32>);
+
+impl Proof {
+
+	pub fn empty() -> Proof {
+		Proof(vec![])
+	}
+
+	// the difficulty achieved by this proof
+	pub fn difficulty(&self) -> u64 {
+		let hash = self.hash().to_u64();
+		if hash == 0 { return u64::MAX }
+		u64::MAX / hash
+	}
+}
+
+impl Writeable for Proof {
+	fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
+		let proof_size = self.0.len() as u8;
+		proof_size.write(writer)?;
+		for nonce in &self.0 {
+			nonce.write(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl Readable for Proof {
+	fn read(reader: &mut dyn Reader) -> Result<Proof, Error>{
+		let proof_size = u8::read(reader)? as usize;
+		if proof_size > MAX_PROOF_SIZE {
+			return Err(Error::new(ErrorKind::InvalidData, "Proof too long"));
+		}
+		let mut nonces = Vec::new();
+		for _ in 0..proof_size {
+			nonces.push(u32::read(reader)?);
+		}
+		Ok(Proof(nonces))
+	}
+}
+
+impl Hashable for Proof {}
+
+impl fmt::Debug for Proof {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{:?}", self.0)
+	}
+}
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn solve(edge_bits: u8, proof_size: usize) -> (Cuckoo, Proof) {
+		let mut key = [0u8; 32];
+		loop {
+			key[0] += 1;
+			let cuckoo = Cuckoo::new(Hash::new(key), edge_bits, proof_size);
+			if let Some(proof) = cuckoo.find_cycle() {
+				return (cuckoo, proof);
+			}
+		}
+	}
+
+	#[test]
+	fn find_and_verify() {
+		let (cuckoo, proof) = solve(12, 8);
+		assert_eq!(proof.0.len(), 8);
+		assert!(cuckoo.verify(&proof));
+	}
+
+	#[test]
+	fn search_in_steps() {
+		let (cuckoo, proof) = solve(12, 8);
+		let mut search = CycleSearch::new(cuckoo.clone());
+		let mut steps = 0;
+		loop {
+			steps += 1;
+			match search.step(64) {
+				SearchStep::Found(found) => { assert_eq!(found, proof); break },
+				SearchStep::Exhausted => panic!("the graph has a cycle"),
+				SearchStep::Searching => {},
+			}
+		}
+		assert!(steps > 1);
+	}
+
+	#[test]
+	fn reject_invalid() {
+		let (cuckoo, proof) = solve(12, 8);
+
+		// wrong proof size
+		let mut short = proof.clone();
+		short.0.pop();
+		assert!(!cuckoo.verify(&short));
+
+		// edge not in the cycle
+		let mut tampered = proof.clone();
+		tampered.0[7] += 1;
+		assert!(!cuckoo.verify(&tampered));
+
+		// other graph
+		let other = Cuckoo::new(Hash::zeros(), 12, 8);
+		assert!(!other.verify(&proof));
+	}
+}
@@ -0,0 +1,2 @@
+pub mod siphash;
+pub mod cuckoo;
@@ -0,0 +1,36 @@
+/// SipHash-2-4 of a single 64-bit word, as used by Cuckoo Cycle to
+/// derive the endpoints of each edge from its nonce.
+pub fn siphash24(k0: u64, k1: u64, nonce: u64) -> u64 {
+	let mut v0 = k0 ^ 0x736f6d6570736575;
+	let mut v1 = k1 ^ 0x646f72616e646f6d;
+	let mut v2 = k0 ^ 0x6c7967656e657261;
+	let mut v3 = k1 ^ 0x7465646279746573 ^ nonce;
+
+	sip_round(&mut v0, &mut v1, &mut v2, &mut v3);
+	sip_round(&mut v0, &mut v1, &mut v2, &mut v3);
+	v0 ^= nonce;
+	v2 ^= 0xff;
+	sip_round(&mut v0, &mut v1, &mut v2, &mut v3);
+	sip_round(&mut v0, &mut v1, &mut v2, &mut v3);
+	sip_round(&mut v0, &mut v1, &mut v2, &mut v3);
+	sip_round(&mut v0, &mut v1, &mut v2, &mut v3);
+
+	v0 ^ v1 ^ v2 ^ v3
+}
+
+fn sip_round(v0: &mut u64, v1: &mut u64, v2: &mut u64, v3: &mut u64) {
+	*v0 = v0.wrapping_add(*v1);
+	*v2 = v2.wrapping_add(*v3);
+	*v1 = v1.rotate_left(13);
+	*v3 = v3.rotate_left(16);
+	*v1 ^= *v0;
+	*v3 ^= *v2;
+	*v0 = v0.rotate_left(32);
+	*v2 = v2.wrapping_add(*v1);
+	*v0 = v0.wrapping_add(*v3);
+	*v1 = v1.rotate_left(17);
+	*v3 = v3.rotate_left(21);
+	*v1 ^= *v2;
+	*v3 ^= *v0;
+	*v2 = v2.rotate_left(32);
+}
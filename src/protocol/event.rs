@@ -36,6 +36,28 @@ pub enum Error {
 	InvalidDifficulty,
 	InvalidCoinSum,
     InvalidReward,
+	InvalidProofOfWork,
+	MissingUndo,
+	InvalidTransactionsRoot,
+	InvalidBalanceProof,
+	InvalidBalance,
+	DoubleSpend,
+	InvalidCoinbase,
+	BlockTooLarge,
+	TooManyTransactions,
+	TooManyInputs,
+	TooManyOutputs,
+	TimestampTooEarly,
+	TimestampTooFarAhead,
+	InvalidSignature,
+	#[cfg(test)]
+	InvalidSwap,
+	InvalidOutputId,
+	InvalidAddressProof,
+	InvalidChainProof,
+	ForkTooDeep,
+	TransactionLocked,
+	InvalidAddressIndexRoot,
 	Io( io::Error )
 }
 
@@ -47,13 +69,35 @@ impl fmt::Display for Error {
             Error::StateMissMatch => write!(f, "StateMissMatch Error"),
             Error::InvalidDifficulty => write!(f, "InvalidDifficulty Error"),
             Error::InvalidCoinSum => write!(f, "InvalidCoinSum Error"),
-            Error::InvalidReward => write!(f, "InvalidReward Error")
+            Error::InvalidReward => write!(f, "InvalidReward Error"),
+            Error::InvalidProofOfWork => write!(f, "InvalidProofOfWork Error"),
+            Error::MissingUndo => write!(f, "MissingUndo Error"),
+            Error::InvalidTransactionsRoot => write!(f, "InvalidTransactionsRoot Error"),
+            Error::InvalidBalanceProof => write!(f, "InvalidBalanceProof Error"),
+            Error::InvalidBalance => write!(f, "InvalidBalance Error"),
+            Error::DoubleSpend => write!(f, "DoubleSpend Error"),
+            Error::InvalidCoinbase => write!(f, "InvalidCoinbase Error"),
+            Error::BlockTooLarge => write!(f, "BlockTooLarge Error"),
+            Error::TooManyTransactions => write!(f, "TooManyTransactions Error"),
+            Error::TooManyInputs => write!(f, "TooManyInputs Error"),
+            Error::TooManyOutputs => write!(f, "TooManyOutputs Error"),
+            Error::TimestampTooEarly => write!(f, "TimestampTooEarly Error"),
+            Error::TimestampTooFarAhead => write!(f, "TimestampTooFarAhead Error"),
+            Error::InvalidSignature => write!(f, "InvalidSignature Error"),
+            #[cfg(test)]
+            Error::InvalidSwap => write!(f, "InvalidSwap Error"),
+            Error::InvalidOutputId => write!(f, "InvalidOutputId Error"),
+            Error::InvalidAddressProof => write!(f, "InvalidAddressProof Error"),
+            Error::InvalidChainProof => write!(f, "InvalidChainProof Error"),
+            Error::ForkTooDeep => write!(f, "ForkTooDeep Error"),
+            Error::TransactionLocked => write!(f, "TransactionLocked Error"),
+            Error::InvalidAddressIndexRoot => write!(f, "InvalidAddressIndexRoot Error")
         }
     }
 }
 
 impl error::Error for Error {
-	fn cause( &self ) -> Option<&error::Error> {
+	fn cause( &self ) -> Option<&dyn error::Error> {
         match *self {
             Error::Io(ref err) => Some(err),
             Error::InvalidInput => None,
@@ -62,8 +106,19 @@ impl error::Error for Error {
     }
 }
 
+impl Error {
+    // to report a violated limit while deserializing
+    pub fn into_io(self) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, self)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Error {
+        // unwrap the errors of `into_io`
+        if err.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+            return *err.into_inner().unwrap().downcast::<Error>().unwrap();
+        }
         Error::Io(err)
     }
 }
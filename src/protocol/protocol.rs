@@ -1,15 +1,21 @@
-use blockchain::transaction::Transaction;
+use blockchain::transaction::{ Transaction, Address };
+use blockchain::balance_proof::{ BalanceProof, RefreshedBalanceProof, StateTreeExtension };
+use blockchain::address_index::AddressOutputProof;
+use blockchain::chain_proof::ChainProof;
 use wallet::wallet::Wallet;
 use protocol::protocol_config::ProtocolConfig;
 use blockchain::blockchain::Blockchain;
+use blockchain::block_tree::{ BlockTree, BlockStore, ChainUpdate, ChainListener };
 use network::message::{ EmptyMessageBody, Message };
 use network::peer::{ PeerTracker, PeerChannel, PeerInfo, PeerAddress };
 use network::network::Network;
-use protocol::event::{ EventResult, Event, EventListener, EventSource };
+use protocol::event::{ EventResult, Event, EventListener, EventSource, Error };
 use blockchain::block::Block;
 use miner::miner::Miner;
+use utils::hash::Hashable;
 use utils::serializer::{ Readable, Writeable, DiscWriter };
 use std::{ thread, time };
+use std::cmp::Ordering;
 
 
 
@@ -33,6 +39,7 @@ impl EventListener for Protocol {
 pub struct Protocol {
     config: ProtocolConfig,
     blockchain : Blockchain,
+    block_tree : BlockTree,
     network : Network,
     miner : Miner,
     wallet: Wallet, 
@@ -45,6 +52,14 @@ pub mod message_type {
 	pub const ADDRESS: 			u32 = 3;
 	pub const BLOCK: 			u32 = 6;
 	pub const TRANSACTION: 		u32 = 7;
+	pub const GET_BALANCE_PROOF: 	u32 = 8;
+	pub const BALANCE_PROOF: 	u32 = 9;
+	pub const GET_STATE_TREE_EXTENSION: u32 = 10;
+	pub const STATE_TREE_EXTENSION: u32 = 11;
+	pub const GET_ADDRESS_PROOF: 	u32 = 12;
+	pub const ADDRESS_PROOF: 	u32 = 13;
+	pub const GET_CHAIN_PROOF: 	u32 = 14;
+	pub const CHAIN_PROOF: 		u32 = 15;
 	// pub const GET_BLOCKS: 	u32 = 4;
 	// pub const INV: 			u32 = 5;
 }
@@ -61,6 +76,14 @@ impl Protocol {
 		    message_type::BLOCK => self.on_block_message(peer_channel),
 		    message_type::ADDRESS => self.on_address_message(peer_channel),
 		    message_type::TRANSACTION => self.on_transaction_message(peer_channel),
+		    message_type::GET_BALANCE_PROOF => self.on_get_balance_proof_message(peer_channel),
+		    message_type::BALANCE_PROOF => self.on_balance_proof_message(peer_channel),
+		    message_type::GET_STATE_TREE_EXTENSION => self.on_get_state_tree_extension_message(peer_channel),
+		    message_type::STATE_TREE_EXTENSION => self.on_state_tree_extension_message(peer_channel),
+		    message_type::GET_ADDRESS_PROOF => self.on_get_address_proof_message(peer_channel),
+		    message_type::ADDRESS_PROOF => self.on_address_proof_message(peer_channel),
+		    message_type::GET_CHAIN_PROOF => self.on_get_chain_proof_message(peer_channel),
+		    message_type::CHAIN_PROOF => self.on_chain_proof_message(peer_channel),
 		    
 		    t => {
 		    	println!("message type not implemented {:?}", t );
@@ -96,6 +119,10 @@ impl Protocol {
 
 		// broadcast our address
 		self.network.server.address().to_message().write(&mut *conn)?;
+
+		// check the peer agrees on our balance and output
+		Message::new(message_type::GET_BALANCE_PROOF, self.config.get_miner_address()).write(&mut *conn)?;
+		Message::new(message_type::GET_ADDRESS_PROOF, self.config.get_miner_address()).write(&mut *conn)?;
 		Ok(Event::Nothing)
 	}
 
@@ -105,6 +132,10 @@ impl Protocol {
 			let mut conn = peer.connection.write().unwrap();
 			let peer_info = PeerInfo::read(&mut *conn)?;
 			Message::new(message_type::VERSION_ACK, EmptyMessageBody).write(&mut *conn)?;
+			// compare the work of a longer chain
+			if peer_info.chain_height > self.blockchain.block_count() {
+				Message::new(message_type::GET_CHAIN_PROOF, EmptyMessageBody).write(&mut *conn)?;
+			}
 			peer_info
 		};
 		println!(">> Received: {:?}", peer_info);
@@ -141,24 +172,40 @@ impl Protocol {
 	}
 
     fn on_block_message( &mut self, channel: PeerChannel ) -> EventResult {
-		let peer = channel.peer.write().unwrap();
-		let mut conn = peer.connection.write().unwrap();
-		let mut block = Block::read(&mut *conn)?;
+		let block = {
+			let peer = channel.peer.write().unwrap();
+			let mut conn = peer.connection.write().unwrap();
+			Block::read(&mut *conn)?
+		};
 			    
     	println!(">> Received: {:?}", block);
-    	self.blockchain.verify_block(&mut block)?;
-    	self.blockchain.apply_block(&mut block)?;
-    	self.miner.on_state_update(&block, &self.blockchain);
-    	block.write( &mut DiscWriter::block_writer(&self.config.archive_path, self.blockchain.block_count() ))?;
+    	let update = self.block_tree.add_block(block, &mut self.blockchain)?;
+    	self.on_chain_update(update)
+	}
 
-    	Ok(Event::Nothing)
+	fn on_block_mined( &mut self, block: Block ) -> EventResult {
+		let update = self.block_tree.add_block(block, &mut self.blockchain)?;
+		for block in &update.connected {
+			self.network.broadcast( &block.clone().to_message() )?;
+		}
+		self.on_chain_update(update)
 	}
 
-	fn on_block_mined( &mut self, mut block: Block ) -> EventResult {
-		self.blockchain.apply_block( &mut block )?;
-		self.miner.on_state_update(&block, &self.blockchain);		
-    	block.write( &mut DiscWriter::block_writer( &self.config.archive_path, self.blockchain.block_count()))?;
-		self.network.broadcast( &block.to_message() )?;
+	fn on_chain_update( &mut self, update: ChainUpdate ) -> EventResult {
+		if update.is_empty() { return Ok(Event::Nothing) }
+		if !update.disconnected.is_empty() {
+			println!(">> Reorganization: {} blocks disconnected, {} blocks connected", update.disconnected.len(), update.connected.len());
+		}
+
+		self.miner.on_chain_update(&update, &self.blockchain);
+		self.wallet.on_chain_update(&update, &self.blockchain);
+
+		// archive the connected blocks
+		let mut block_id = self.blockchain.block_count() - update.connected.len() as u32;
+		for block in &update.connected {
+			block_id += 1;
+			block.write( &mut DiscWriter::block_writer( &self.config.archive_path, block_id ))?;
+		}
 		Ok(Event::Nothing)
 	}
 
@@ -173,6 +220,134 @@ impl Protocol {
 		Ok(Event::Nothing)
 	}
 
+	fn on_get_balance_proof_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let peer = channel.peer.write().unwrap();
+		let mut conn = peer.connection.write().unwrap();
+		let address = Address::read(&mut *conn)?;
+		// an address without unspent output has no balance to prove
+		if let Some(proof) = self.blockchain.balance_proof(address, &self.block_tree) {
+			Message::new(message_type::BALANCE_PROOF, proof).write(&mut *conn)?;
+		}
+		Ok(Event::Nothing)
+	}
+
+	fn on_balance_proof_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let proof = {
+			let peer = channel.peer.write().unwrap();
+			let mut conn = peer.connection.write().unwrap();
+			BalanceProof::read(&mut *conn)?
+		};
+
+		// the proof is valid at the peer's state, we refresh it with our blocks since
+		let state_root = proof.block_proof.root();
+		let head_id = match self.block_tree.main_height_of_state(&state_root) {
+			Some(head_id) => head_id,
+			None => {
+				println!(">> Received: balance proof at an unknown state {:?}", state_root);
+				return Ok(Event::Nothing)
+			},
+		};
+		let extension = self.blockchain.state_tree_extension(head_id, &self.block_tree).ok_or(Error::StateMissMatch)?;
+		match proof.refresh(state_root, &extension)? {
+			RefreshedBalanceProof::Refreshed{ proof, state_root } => {
+				let output = proof.verify(state_root)?;
+				println!(">> Received: balance {} of {:?}", output.balance, output.address);
+			},
+			RefreshedBalanceProof::Superseded{ block_id } => {
+				println!(">> Received: balance proof superseded in block {}", block_id);
+			},
+		}
+		Ok(Event::Nothing)
+	}
+
+	fn on_get_state_tree_extension_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let peer = channel.peer.write().unwrap();
+		let mut conn = peer.connection.write().unwrap();
+		let old_head_id = u32::read(&mut *conn)?;
+		// the blocks since the head of the peer's balance proofs
+		if let Some(extension) = self.blockchain.state_tree_extension(old_head_id, &self.block_tree) {
+			Message::new(message_type::STATE_TREE_EXTENSION, extension).write(&mut *conn)?;
+		}
+		Ok(Event::Nothing)
+	}
+
+	fn on_state_tree_extension_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let extension = {
+			let peer = channel.peer.write().unwrap();
+			let mut conn = peer.connection.write().unwrap();
+			StateTreeExtension::read(&mut *conn)?
+		};
+
+		// the extension starts at a head of our main chain, the next block commits to its state
+		let old_head_id = extension.head_proof.block_id;
+		let old_state_root = if old_head_id == self.blockchain.block_count() {
+			self.blockchain.state_hash()
+		} else {
+			match self.block_tree.get_block(old_head_id + 1) {
+				Some(block) => block.header.state,
+				None => {
+					println!(">> Received: state tree extension from an unknown head {}", old_head_id);
+					return Ok(Event::Nothing)
+				},
+			}
+		};
+		let state_root = extension.verify(old_state_root)?;
+		match self.block_tree.main_height_of_state(&state_root) {
+			Some(head_id) => println!(">> Received: state tree extension up to our block {}", head_id),
+			None => println!(">> Received: state tree extension to an unknown state {:?}", state_root),
+		}
+		Ok(Event::Nothing)
+	}
+
+	fn on_get_address_proof_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let peer = channel.peer.write().unwrap();
+		let mut conn = peer.connection.write().unwrap();
+		let address = Address::read(&mut *conn)?;
+		let proof = AddressOutputProof{ address, proof: self.blockchain.address_proof(address) };
+		Message::new(message_type::ADDRESS_PROOF, proof).write(&mut *conn)?;
+		Ok(Event::Nothing)
+	}
+
+	fn on_address_proof_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let message = {
+			let peer = channel.peer.write().unwrap();
+			let mut conn = peer.connection.write().unwrap();
+			AddressOutputProof::read(&mut *conn)?
+		};
+		// the peer's index is ours unless our heads differ
+		match message.proof.verify(message.address, self.blockchain.address_index_root()) {
+			Ok(output_id) => println!(">> Received: output {:?} of {:?}", output_id, message.address),
+			Err(_) => println!(">> Received: address proof of {:?} at another head", message.address),
+		}
+		Ok(Event::Nothing)
+	}
+
+	fn on_get_chain_proof_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let peer = channel.peer.write().unwrap();
+		let mut conn = peer.connection.write().unwrap();
+		// a chain of only the genesis block has no proof
+		if let Some(proof) = self.blockchain.chain_proof(&self.block_tree) {
+			Message::new(message_type::CHAIN_PROOF, proof).write(&mut *conn)?;
+		}
+		Ok(Event::Nothing)
+	}
+
+	fn on_chain_proof_message(&mut self, channel: PeerChannel ) -> EventResult {
+		let proof = {
+			let peer = channel.peer.write().unwrap();
+			let mut conn = peer.connection.write().unwrap();
+			ChainProof::read(&mut *conn)?
+		};
+		let genesis_hash = self.block_tree.get_block(0).ok_or(Error::StateMissMatch)?.hash();
+		proof.verify(genesis_hash, &self.blockchain.params)?;
+		let heavier = match self.blockchain.chain_proof(&self.block_tree) {
+			Some(our_proof) => proof.compare(&our_proof) == Ordering::Greater,
+			None => true,
+		};
+		println!(">> Received: chain proof of {} blocks, heavier than ours: {}", proof.head().unwrap().proof.block_id, heavier);
+		Ok(Event::Nothing)
+	}
+
 	fn on_transaction(&mut self, transaction: Transaction) -> EventResult {
 		let message = Message::new( message_type::TRANSACTION, transaction );
 		self.network.broadcast( &message )?;
@@ -201,19 +376,22 @@ impl Protocol {
 	// }
 
 	// boilerplate
-	pub fn new(config: ProtocolConfig, genesis_block: Block) -> Protocol{
+	// fails if the persisted chain state or the archive is corrupt
+	pub fn new(config: ProtocolConfig, genesis_block: Block) -> Result<Protocol, Error> {
 		let network = Network::new(&config);
-		let blockchain = Blockchain::new(&genesis_block);
-		let mut miner = Miner::new(config.get_miner_address());
-		miner.on_state_update(&genesis_block, &blockchain);
-    	Protocol{
+		let mut blockchain = Blockchain::open(&genesis_block, config.chain.clone(), &config.data_path)?;
+		blockchain.set_archive_path(config.archive_path.to_string());
+		let block_tree = BlockTree::load(genesis_block, &mut blockchain, &config.archive_path)?;
+		let miner = Miner::new(config.get_miner_address());
+    	Ok(Protocol{
 			miner,
 			network,
 			blockchain,
+			block_tree,
 			cycle_count: 0,
 			config,
 			wallet: Wallet::new()
-		}
+		})
 	} 
 
 	pub fn poll(&mut self) -> EventResult {
@@ -2,8 +2,10 @@ extern crate serde;
 extern crate serde_json;
 
 use blockchain::block::{ Block, BlockHeader };
-use blockchain::transaction::{ Transaction, Address };
+use blockchain::transaction::{ Transaction, Address, Value };
 use utils::Hash;
+use crypto::schnorr::SecretKey;
+use pow::cuckoo::{ MAX_EDGE_BITS, MAX_PROOF_SIZE };
 use std::path::Path;
 use std::collections::HashMap;
 use std::error::Error;
@@ -26,14 +28,78 @@ pub struct ProtocolConfig {
 	pub seed_nodes : Vec<String>,
 	pub services : HashMap <ServiceTypes, Service>,
 	pub archive_path : String,
-	pub miner_address : String
+	// directory of the persisted chain state
+	pub data_path : String,
+	// the miner's address is the public key of this key
+	pub miner_secret_key : String,
+	#[serde(default)]
+	pub chain : ChainParams
+}
+
+/// Consensus parameters of the chain. All nodes of a network must agree on them.
+#[derive(Debug, Clone , Deserialize)]
+#[serde(default)]
+pub struct ChainParams {
+	// the Cuckoo graph has 2^edge_bits edges
+	pub edge_bits : u8,
+	// length of the cycle to find
+	pub proof_size : usize,
+	// targeted time between blocks in seconds
+	pub block_time : u32,
+	// number of blocks averaged by the difficulty retargeting
+	pub retarget_window : u32,
+	// number of blocks whose median timestamp a new block must exceed
+	pub median_time_window : u32,
+	// how far in seconds a block's timestamp may be ahead of our clock
+	pub max_future_drift : u32,
+	// reward of the first block
+	pub initial_reward : Value,
+	// number of blocks after which the reward halves
+	pub halving_interval : u32,
+	// total amount of coins ever emitted
+	pub max_supply : Value
+}
+
+impl Default for ChainParams {
+	fn default() -> ChainParams {
+		ChainParams {
+			edge_bits : 20,
+			proof_size : 42,
+			block_time : 60,
+			retarget_window : 60,
+			median_time_window : 11,
+			max_future_drift : 2 * 60 * 60,
+			initial_reward : 100_000_000,
+			halving_interval : 2_100_000,
+			max_supply : 420_000_000_000_000
+		}
+	}
+}
+
+impl ChainParams {
+
+	/// Rejects parameters the consensus code cannot work with.
+	pub fn validate(&self) -> Result<(), String> {
+		if self.edge_bits == 0 || self.edge_bits > MAX_EDGE_BITS {
+			return Err(format!("edge_bits must be between 1 and {}", MAX_EDGE_BITS));
+		}
+		// cycles of the bipartite graph have an even length
+		if self.proof_size < 2 || self.proof_size > MAX_PROOF_SIZE || !self.proof_size.is_multiple_of(2) {
+			return Err(format!("proof_size must be even and between 2 and {}", MAX_PROOF_SIZE));
+		}
+		if self.block_time == 0 || self.retarget_window == 0 || self.median_time_window == 0 || self.halving_interval == 0 {
+			return Err("block_time, retarget_window, median_time_window and halving_interval must not be zero".to_string());
+		}
+		Ok(())
+	}
 }
 
 impl ProtocolConfig {
 	
-	pub fn read_from_file<P: AsRef<Path>>(file_name: P) -> Result<ProtocolConfig, Box<Error>>{
+	pub fn read_from_file<P: AsRef<Path>>(file_name: P) -> Result<ProtocolConfig, Box<dyn Error>>{
 		let file = File::open(file_name)?;
-    	let config = serde_json::from_reader(file)?;
+    	let config: ProtocolConfig = serde_json::from_reader(file)?;
+    	config.chain.validate()?;
     	print!("Read config: {:?}", config);
     	Ok(config)
 	}
@@ -48,8 +114,12 @@ impl ProtocolConfig {
 		[ service.ip_address.to_string(), service.port.to_string() ].join(":")
 	}
 
+	pub fn get_miner_secret_key(&self) -> SecretKey {
+		SecretKey::from_hex(self.miner_secret_key.to_string())
+	}
+
 	pub fn get_miner_address(&self) -> Address {
-		Address::from_hex(self.miner_address.to_string())
+		Address::from(self.get_miner_secret_key().public_key())
 	}
 
 	pub fn get_genesis_block() -> Block{
@@ -57,3 +127,31 @@ impl ProtocolConfig {
 		Block::new(BlockHeader::new( Hash::zeros(), 0, 8888), reward )
 	}
 }
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn validate_params() {
+		assert!(ChainParams::default().validate().is_ok());
+
+		let mut params = ChainParams{ edge_bits: MAX_EDGE_BITS, ..ChainParams::default() };
+		assert!(params.validate().is_ok());
+		params.edge_bits = MAX_EDGE_BITS + 1;
+		assert!(params.validate().is_err());
+		params.edge_bits = 64;
+		assert!(params.validate().is_err());
+		params.edge_bits = 0;
+		assert!(params.validate().is_err());
+
+		let mut params = ChainParams{ proof_size: 41, ..ChainParams::default() };
+		assert!(params.validate().is_err());
+		params.proof_size = MAX_PROOF_SIZE + 2;
+		assert!(params.validate().is_err());
+
+		let params = ChainParams{ block_time: 0, ..ChainParams::default() };
+		assert!(params.validate().is_err());
+	}
+}
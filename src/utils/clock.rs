@@ -0,0 +1,25 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+/// The source of the current time in seconds since the unix epoch.
+pub trait Clock {
+    fn now(&self) -> u32;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u32 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    }
+}
+
+/// A clock standing still, for deterministic tests.
+#[cfg(test)]
+pub struct FixedClock(pub u32);
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> u32 {
+        self.0
+    }
+}
@@ -17,6 +17,10 @@ impl Hash {
 	    Hash(buffer)
 	}
 
+	pub fn as_bytes(&self) -> &[u8;32] {
+		&self.0
+	}
+
 	pub fn to_hex(&self) -> String{
 		to_hex(self.0.to_vec())
 	}
@@ -44,14 +48,14 @@ impl fmt::Debug for Hash {
 }
 
 impl Writeable for Hash {
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
 	    writer.write_fixed_size(&self.0)?;
 	    Ok(())
 	}
 } 
 
 impl Readable for Hash {
-    fn read(reader: &mut Reader) -> Result<Hash, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<Hash, Error>{
     	let mut buffer = [0u8;32];
 		reader.read_fixed_size(&mut buffer)?;
 		Ok(Hash::new(buffer))
@@ -67,13 +71,13 @@ pub trait Hashable:Writeable {
     }
 }
 
-struct HashWriter{
+pub struct HashWriter{
 	state : Blake2b
 }
 
 impl HashWriter {
 	
-	fn new() -> HashWriter {
+	pub fn new() -> HashWriter {
 		HashWriter {
 			state: Blake2b::new(32),
 		}
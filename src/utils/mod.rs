@@ -1,4 +1,5 @@
 pub mod hex;
 pub mod serializer;
 pub mod hash;
+pub mod clock;
 pub use self::hash::Hash; 
\ No newline at end of file
@@ -45,17 +45,21 @@ pub struct DiscWriter {
 impl DiscWriter {
 
     pub fn block_writer( archive_path: &String, block_id: u32 ) -> DiscWriter {
-        let mut file_name = [
-                    archive_path,
-                    "block", 
-                    &format!("{:08}", block_id), 
-                    ".txt"].join("");
+        let mut file_name = archive_file_name(archive_path, "block", block_id);
         let file = File::create(&mut file_name).unwrap();
         DiscWriter { file }
     }
 
 }
 
+pub fn archive_file_name( archive_path: &str, prefix: &str, block_id: u32 ) -> String {
+    [
+        archive_path,
+        prefix, 
+        &format!("{:08}", block_id), 
+        ".txt"].join("")
+}
+
 impl Writer for DiscWriter{
 
     fn write_fixed_size(&mut self, buffer: &[u8] ) -> Result<(), Error>{
@@ -68,6 +72,100 @@ impl Writer for DiscWriter{
     }
 }
 
+pub struct DiscReader {
+    file : File
+}
+
+impl DiscReader {
+
+    pub fn block_reader( archive_path: &str, block_id: u32 ) -> Result<DiscReader, Error> {
+        let file = File::open(archive_file_name(archive_path, "block", block_id))?;
+        Ok(DiscReader { file })
+    }
+
+}
+
+impl Reader for DiscReader {
+
+    fn read_fixed_size(&mut self, buffer: &mut [u8] ) -> Result<(), Error>{
+        self.file.read_exact(buffer)
+    }
+}
+
+// in-memory serialization
+impl Writer for Vec<u8> {
+
+    fn write_fixed_size(&mut self, buffer: &[u8] ) -> Result<(), Error>{
+        self.extend_from_slice(buffer);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error>{
+        Ok(())
+    }
+}
+
+impl  Reader for &[u8] {
+
+    fn read_fixed_size(&mut self, buffer: &mut [u8] ) -> Result<(), Error>{
+        self.read_exact(buffer)
+    }
+}
+
+/// Reads at most `limit` bytes from the underlying reader.
+pub struct LimitedReader<'a> {
+    reader: &'a mut dyn Reader,
+    remaining: usize,
+    exceeded: bool
+}
+
+impl <'a> LimitedReader<'a> {
+
+    pub fn new( reader: &'a mut dyn Reader, limit: usize ) -> LimitedReader<'a> {
+        LimitedReader { reader, remaining: limit, exceeded: false }
+    }
+
+    // whether a read failed because of the limit
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl <'a> Reader for LimitedReader<'a> {
+
+    fn read_fixed_size(&mut self, buffer: &mut [u8] ) -> Result<(), Error>{
+        if buffer.len() > self.remaining {
+            self.exceeded = true;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "size limit exceeded"));
+        }
+        self.remaining -= buffer.len();
+        self.reader.read_fixed_size(buffer)
+    }
+}
+
+/// Counts the bytes of the serialization without writing them.
+pub struct SizeWriter {
+    pub size: usize
+}
+
+impl SizeWriter {
+    pub fn new() -> SizeWriter {
+        SizeWriter { size: 0 }
+    }
+}
+
+impl Writer for SizeWriter {
+
+    fn write_fixed_size(&mut self, buffer: &[u8] ) -> Result<(), Error>{
+        self.size += buffer.len();
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Error>{
+        Ok(())
+    }
+}
+
 /// The default implementation of read_exact is useless with async TcpStream as
 /// it will return as soon as something has been read, regardless of
 /// whether the buffer has been filled (and then errors). This implementation
@@ -123,7 +221,7 @@ pub fn read_exact(
 }
 
 /// Same as `read_exact` but for writing.
-pub fn write_all(conn: &mut Write, mut buf: &[u8], timeout: u32) -> io::Result<()> {
+pub fn write_all(conn: &mut dyn Write, mut buf: &[u8], timeout: u32) -> io::Result<()> {
     let sleep_time = time::Duration::from_millis(1);
     let mut count = 0;
 
@@ -168,7 +266,7 @@ pub fn write_all(conn: &mut Write, mut buf: &[u8], timeout: u32) -> io::Result<(
 /// underlying Write implementation.
 pub trait Writeable : Debug{
     /// Write the data held by this Writeable to the provided writer
-    fn write(&self, writer: &mut Writer) -> Result<(), Error>;
+    fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>;
 }
 
 /// Trait that every type that can be deserialized from binary must implement.
@@ -179,20 +277,20 @@ where
     Self: Sized,
 {
     /// Reads the data necessary to this Readable from the provided reader
-    fn read(reader: &mut Reader) -> Result<Self, Error>;
+    fn read(reader: &mut dyn Reader) -> Result<Self, Error>;
 }
 
 // Serialization for simple types 
 
 impl Writeable for u8{
-     fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+     fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         let bytes = unsafe { transmute::<u8, [u8;1]>(*self) };
         writer.write_fixed_size(&bytes)
     }
 }
 
 impl Readable for u8{
-    fn read(reader: &mut Reader) -> Result<u8, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<u8, Error>{
         let mut bytes = [0u8;1];
         reader.read_fixed_size(&mut bytes)?;
         Ok(unsafe { transmute::<[u8;1], u8>(bytes) })
@@ -200,14 +298,14 @@ impl Readable for u8{
 }
 
 impl Writeable for u16{
-     fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+     fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         let bytes = unsafe { transmute::<u16, [u8;2]>(*self) };
         writer.write_fixed_size(&bytes)
     }
 }
 
 impl Readable for u16{
-    fn read(reader: &mut Reader) -> Result<u16, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<u16, Error>{
         let mut bytes = [0u8;2];
         reader.read_fixed_size(&mut bytes)?;
         Ok(unsafe { transmute::<[u8;2], u16>(bytes) })
@@ -215,14 +313,14 @@ impl Readable for u16{
 }
 
 impl Writeable for u32{
-     fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+     fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         let bytes = unsafe { transmute::<u32, [u8;4]>(*self) };
         writer.write_fixed_size(&bytes)
     }
 }
 
 impl Readable for u32{
-    fn read(reader: &mut Reader) -> Result<u32, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<u32, Error>{
         let mut bytes = [0u8;4];
         reader.read_fixed_size(&mut bytes)?;
         Ok( unsafe { transmute::<[u8;4], u32>(bytes) } )
@@ -230,14 +328,14 @@ impl Readable for u32{
 }
 
 impl Writeable for u64{
-     fn write(&self, writer: &mut Writer) -> Result<(), Error>{
+     fn write(&self, writer: &mut dyn Writer) -> Result<(), Error>{
         let bytes = unsafe { transmute::<u64, [u8;8]>(*self) };
         writer.write_fixed_size(&bytes)
     }
 }
 
 impl Readable for u64{
-    fn read(reader: &mut Reader) -> Result<u64, Error>{
+    fn read(reader: &mut dyn Reader) -> Result<u64, Error>{
         let mut bytes = [0u8;8];
         reader.read_fixed_size(&mut bytes)?;
         Ok( unsafe { transmute::<[u8;8], u64>(bytes) })
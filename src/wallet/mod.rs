@@ -1 +1,4 @@
-pub mod wallet;
\ No newline at end of file
+pub mod wallet;
+// swaps have no interface in the node yet, only the tests use them
+#[cfg(test)]
+pub mod swap;
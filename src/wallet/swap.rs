@@ -0,0 +1,650 @@
+use blockchain::blockchain::Blockchain;
+use blockchain::transaction::{ Address, Signature, Transaction, TransactionInput, TransactionOutput, Value };
+use crypto::schnorr::{ AdaptorSignature, PublicKey, SecretKey };
+use crypto::musig::{ SigningSession, NonceCommitment, PublicNonce, PartialSignature, joint_address };
+use protocol::event::Error;
+use std::slice;
+
+/// The blocks each refund must leave on its chain for the counterparty's claim:
+/// the initiator's claim on chain B before the responder's refund unlocks, and the
+/// responder's claim on chain A once the initiator's claim was published on chain B.
+pub const REFUND_MARGIN: u32 = 2;
+
+/// The initiator gives coins on chain A and takes the responder's coins on chain B.
+/// Both can take back their coins from the first block of their refund height on.
+/// The initiator's refund must unlock after the responder's, such that the responder
+/// has the time to claim once the initiator claimed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SwapTerms {
+    pub give: Value,
+    pub take: Value,
+    pub refund_height_a: u32,
+    pub refund_height_b: u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapRole {
+    Initiator,
+    Responder
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapState {
+    // the initiator waits for the responder to accept
+    Offered,
+    // both fund the joint address on their chain
+    Funding,
+    // the claim and refund transactions are signed jointly
+    Committing,
+    // the refund transactions are signed first
+    Refunding,
+    Signing,
+    // the initiator may claim on chain B, which reveals the secret to the responder
+    Claiming,
+    Completed
+}
+
+/// The messages the two parties of a swap exchange, in this order.
+/// The signing messages carry one value per claim transaction, on chain A and on chain B,
+/// and one per refund transaction.
+#[derive(Clone, Debug)]
+pub enum SwapMessage {
+    Offer{ terms: SwapTerms, key: PublicKey, claim_address: Address, adaptor: PublicKey },
+    Accept{ key: PublicKey, claim_address: Address },
+    // the output of the joint address on the sender's chain
+    Funded{ output: TransactionInput },
+    Commitments{ chain_a: NonceCommitment, chain_b: NonceCommitment, refund_a: NonceCommitment, refund_b: NonceCommitment },
+    Nonces{ chain_a: PublicNonce, chain_b: PublicNonce, refund_a: PublicNonce, refund_b: PublicNonce },
+    // the sender's partial signature of the receiver's refund
+    RefundSignature{ partial_signature: PartialSignature },
+    PartialSignatures{ chain_a: PartialSignature, chain_b: PartialSignature }
+}
+
+// a transaction out of a joint address and its signing session
+struct Claim {
+    transaction: Transaction,
+    session: SigningSession,
+    partial_signature: Option<PartialSignature>,
+    adaptor_signature: Option<AdaptorSignature>
+}
+
+/// A scriptless atomic swap between two chains. Both parties lock their coins in the
+/// joint address of their keys. The claim transactions out of the joint addresses are
+/// signed with adaptor signatures for the same adaptor point, whose secret only the
+/// initiator knows. Claiming the responder's coins publishes the secret, which lets the
+/// responder complete the claim of the initiator's coins.
+///
+/// Before any claim is signed, both parties sign the refund transactions, which return
+/// the coins of a joint address to their owner once the refund height is reached.
+/// The output ids of the joint addresses are only known once funded, so coins locked
+/// in a joint address stay there if the counterparty disappears before the refunds are signed.
+/// The balances of the claims and refunds are not signed; they are updated to the chain
+/// when published, so deposits to the claim addresses do not invalidate them.
+pub struct AtomicSwap {
+    pub role: SwapRole,
+    pub state: SwapState,
+    pub terms: SwapTerms,
+    // our key of the joint addresses
+    key: SecretKey,
+    // receives the counterparty's coins or our refund
+    claim_address: Address,
+    counterparty_key: Option<PublicKey>,
+    counterparty_claim_address: Option<Address>,
+    adaptor: PublicKey,
+    // known to the initiator from the start and to the responder once the initiator claimed
+    adaptor_secret: Option<SecretKey>,
+    // the funded outputs of the joint address
+    chain_a_output: Option<TransactionInput>,
+    chain_b_output: Option<TransactionInput>,
+    claims: Option<( Claim, Claim )>,
+    refunds: Option<( Claim, Claim )>,
+    // our refund, once signed by both
+    refund: Option<Transaction>
+}
+
+impl AtomicSwap {
+
+    pub fn offer( terms: SwapTerms, key: SecretKey, claim_address: Address ) -> ( AtomicSwap, SwapMessage ) {
+        let adaptor_secret = SecretKey::random();
+        let swap = AtomicSwap {
+            role: SwapRole::Initiator,
+            state: SwapState::Offered,
+            terms,
+            key,
+            claim_address,
+            counterparty_key: None,
+            counterparty_claim_address: None,
+            adaptor: adaptor_secret.public_key(),
+            adaptor_secret: Some(adaptor_secret),
+            chain_a_output: None,
+            chain_b_output: None,
+            claims: None,
+            refunds: None,
+            refund: None
+        };
+        let message = SwapMessage::Offer {
+            terms,
+            key: swap.key.public_key(),
+            claim_address,
+            adaptor: swap.adaptor
+        };
+        ( swap, message )
+    }
+
+    /// Accepts an offer whose refund heights leave `REFUND_MARGIN` blocks for
+    /// each claim, counted from the current heights of both chains.
+    pub fn accept( offer: SwapMessage, key: SecretKey, claim_address: Address, chain_a: &Blockchain, chain_b: &Blockchain ) -> Result<( AtomicSwap, SwapMessage ), Error> {
+        let ( terms, counterparty_key, counterparty_claim_address, adaptor ) = match offer {
+            SwapMessage::Offer{ terms, key, claim_address, adaptor } => ( terms, key, claim_address, adaptor ),
+            _ => return Err( Error::InvalidSwap ),
+        };
+        if adaptor.point().is_none() || counterparty_key.point().is_none() { return Err( Error::InvalidSwap ) }
+
+        // the blocks until the refunds unlock on their chain
+        let blocks_a = terms.refund_height_a.checked_sub(chain_a.block_count() + 1).ok_or(Error::InvalidSwap)?;
+        let blocks_b = terms.refund_height_b.checked_sub(chain_b.block_count() + 1).ok_or(Error::InvalidSwap)?;
+        // the initiator claims before our refund unlocks, we claim before the initiator's
+        if blocks_b < REFUND_MARGIN || blocks_a < blocks_b.saturating_add(REFUND_MARGIN) { return Err( Error::InvalidSwap ) }
+
+        let swap = AtomicSwap {
+            role: SwapRole::Responder,
+            state: SwapState::Funding,
+            terms,
+            key,
+            claim_address,
+            counterparty_key: Some(counterparty_key),
+            counterparty_claim_address: Some(counterparty_claim_address),
+            adaptor,
+            adaptor_secret: None,
+            chain_a_output: None,
+            chain_b_output: None,
+            claims: None,
+            refunds: None,
+            refund: None
+        };
+        let message = SwapMessage::Accept{ key: swap.key.public_key(), claim_address };
+        Ok(( swap, message ))
+    }
+
+    // the keys of both parties
+    fn participants( &self ) -> Result<Vec<PublicKey>, Error> {
+        let counterparty_key = self.counterparty_key.ok_or(Error::InvalidSwap)?;
+        Ok(vec![ self.key.public_key(), counterparty_key ])
+    }
+
+    /// The address both parties lock their coins in.
+    pub fn joint_address( &self ) -> Result<Address, Error> {
+        let address = joint_address(&self.participants()?).ok_or(Error::InvalidSwap)?;
+        Ok(Address::from(address))
+    }
+
+    // the value we lock in the joint address
+    fn funding_value( &self ) -> Value {
+        match self.role {
+            SwapRole::Initiator => self.terms.give,
+            SwapRole::Responder => self.terms.take,
+        }
+    }
+
+    /// Locks our coins of `funding_key`'s address in the joint address, on our chain.
+    pub fn funding_transaction( &self, blockchain: &Blockchain, funding_key: &SecretKey ) -> Result<Transaction, Error> {
+        if self.state != SwapState::Funding { return Err( Error::InvalidSwap ) }
+        let funding_address = Address::from(funding_key.public_key());
+        let ( input, output ) = blockchain.unspent_outputs.get_by_address(funding_address).ok_or(Error::InvalidInput)?;
+        let value = self.funding_value();
+        if output.balance < value { return Err( Error::InvalidCoinSum ) }
+
+        let outputs = vec![
+            TransactionOutput::new(self.joint_address()?, value),
+            TransactionOutput::new(funding_address, output.balance - value)
+        ];
+        let mut transaction = Transaction::new(vec![input], outputs);
+        blockchain.validation_context().update_balances(&mut transaction);
+        transaction.sign(slice::from_ref(funding_key));
+        Ok(transaction)
+    }
+
+    /// Our funding transaction is confirmed with the joint address's output `output`.
+    pub fn funded( &mut self, output: TransactionInput ) -> SwapMessage {
+        match self.role {
+            SwapRole::Initiator => self.chain_a_output = Some(output),
+            SwapRole::Responder => self.chain_b_output = Some(output),
+        }
+        SwapMessage::Funded{ output }
+    }
+
+    /// Starts signing the claim transactions once both parties funded.
+    /// The counterparty's funding is checked on its chain.
+    pub fn start_signing( &mut self, counterparty_chain: &Blockchain ) -> Result<SwapMessage, Error> {
+        if self.state != SwapState::Funding { return Err( Error::InvalidSwap ) }
+        let ( chain_a_output, chain_b_output ) = match ( self.chain_a_output, self.chain_b_output ) {
+            ( Some(chain_a_output), Some(chain_b_output) ) => ( chain_a_output, chain_b_output ),
+            _ => return Err( Error::InvalidSwap ),
+        };
+
+        // the counterparty locked the agreed value in the joint address
+        let ( counterparty_output, counterparty_value ) = match self.role {
+            SwapRole::Initiator => ( chain_b_output, self.terms.take ),
+            SwapRole::Responder => ( chain_a_output, self.terms.give ),
+        };
+        let output = counterparty_chain.unspent_outputs.get(&counterparty_output).ok_or(Error::InvalidSwap)?;
+        if output.address != self.joint_address()? || output.balance != counterparty_value { return Err( Error::InvalidSwap ) }
+
+        // the counterparty cannot take back its coins right away
+        let counterparty_refund_height = match self.role {
+            SwapRole::Initiator => self.terms.refund_height_b,
+            SwapRole::Responder => self.terms.refund_height_a,
+        };
+        if counterparty_refund_height <= counterparty_chain.block_count() + 1 { return Err( Error::InvalidSwap ) }
+
+        // the initiator's coins go to the responder and vice versa
+        let counterparty_claim_address = self.counterparty_claim_address.ok_or(Error::InvalidSwap)?;
+        let ( initiator_claim_address, responder_claim_address ) = match self.role {
+            SwapRole::Initiator => ( self.claim_address, counterparty_claim_address ),
+            SwapRole::Responder => ( counterparty_claim_address, self.claim_address ),
+        };
+        let mut chain_a_claim = self.claim(chain_a_output, responder_claim_address, self.terms.give, 0)?;
+        let mut chain_b_claim = self.claim(chain_b_output, initiator_claim_address, self.terms.take, 0)?;
+        chain_a_claim.session.set_adaptor(self.adaptor);
+        chain_b_claim.session.set_adaptor(self.adaptor);
+        let refund_a = self.claim(chain_a_output, initiator_claim_address, self.terms.give, self.terms.refund_height_a)?;
+        let refund_b = self.claim(chain_b_output, responder_claim_address, self.terms.take, self.terms.refund_height_b)?;
+        let message = SwapMessage::Commitments {
+            chain_a: chain_a_claim.session.commitment(),
+            chain_b: chain_b_claim.session.commitment(),
+            refund_a: refund_a.session.commitment(),
+            refund_b: refund_b.session.commitment()
+        };
+        self.claims = Some(( chain_a_claim, chain_b_claim ));
+        self.refunds = Some(( refund_a, refund_b ));
+        self.state = SwapState::Committing;
+        Ok(message)
+    }
+
+    fn claim( &self, input: TransactionInput, address: Address, value: Value, lock_height: u32 ) -> Result<Claim, Error> {
+        let mut transaction = Transaction::new(vec![input], vec![TransactionOutput::new(address, value)]);
+        transaction.lock_height = lock_height;
+        let session = SigningSession::joint(&self.key, &self.participants()?, transaction.signature_hash()).ok_or(Error::InvalidSwap)?;
+        Ok(Claim{ transaction, session, partial_signature: None, adaptor_signature: None })
+    }
+
+    // orders our value and the counterparty's value by role
+    fn by_role<T>( &self, ours: T, theirs: T ) -> Vec<T> {
+        match self.role {
+            SwapRole::Initiator => vec![ ours, theirs ],
+            SwapRole::Responder => vec![ theirs, ours ],
+        }
+    }
+
+    /// Handles a message of the counterparty and returns the answer, if any.
+    pub fn on_message( &mut self, message: SwapMessage ) -> Result<Option<SwapMessage>, Error> {
+        match ( self.state, message ) {
+            ( SwapState::Offered, SwapMessage::Accept{ key, claim_address } ) => {
+                if key.point().is_none() { return Err( Error::InvalidSwap ) }
+                self.counterparty_key = Some(key);
+                self.counterparty_claim_address = Some(claim_address);
+                self.state = SwapState::Funding;
+                Ok(None)
+            },
+
+            ( SwapState::Funding, SwapMessage::Funded{ output } ) => {
+                match self.role {
+                    SwapRole::Initiator => self.chain_b_output = Some(output),
+                    SwapRole::Responder => self.chain_a_output = Some(output),
+                }
+                Ok(None)
+            },
+
+            ( SwapState::Committing, SwapMessage::Commitments{ chain_a, chain_b, refund_a, refund_b } ) => {
+                let commitments = {
+                    let ( claims, refunds ) = ( self.claims.as_ref().unwrap(), self.refunds.as_ref().unwrap() );
+                    [
+                        self.by_role(claims.0.session.commitment(), chain_a),
+                        self.by_role(claims.1.session.commitment(), chain_b),
+                        self.by_role(refunds.0.session.commitment(), refund_a),
+                        self.by_role(refunds.1.session.commitment(), refund_b)
+                    ]
+                };
+                let ( claims, refunds ) = ( self.claims.as_mut().unwrap(), self.refunds.as_mut().unwrap() );
+                let mut sessions = [ &mut claims.0.session, &mut claims.1.session, &mut refunds.0.session, &mut refunds.1.session ];
+                for ( session, commitments ) in sessions.iter_mut().zip(commitments.iter()) {
+                    if !session.set_commitments(commitments.clone()) { return Err( Error::InvalidSwap ) }
+                }
+                Ok(Some(SwapMessage::Nonces {
+                    chain_a: claims.0.session.nonce(),
+                    chain_b: claims.1.session.nonce(),
+                    refund_a: refunds.0.session.nonce(),
+                    refund_b: refunds.1.session.nonce()
+                }))
+            },
+
+            ( SwapState::Committing, SwapMessage::Nonces{ chain_a, chain_b, refund_a, refund_b } ) => {
+                let nonces = {
+                    let ( claims, refunds ) = ( self.claims.as_ref().unwrap(), self.refunds.as_ref().unwrap() );
+                    [
+                        self.by_role(claims.0.session.nonce(), chain_a),
+                        self.by_role(claims.1.session.nonce(), chain_b),
+                        self.by_role(refunds.0.session.nonce(), refund_a),
+                        self.by_role(refunds.1.session.nonce(), refund_b)
+                    ]
+                };
+                let ( claims, refunds ) = ( self.claims.as_mut().unwrap(), self.refunds.as_mut().unwrap() );
+                {
+                    let mut sessions = [ &mut claims.0.session, &mut claims.1.session, &mut refunds.0.session, &mut refunds.1.session ];
+                    for ( session, nonces ) in sessions.iter_mut().zip(nonces.iter()) {
+                        if !session.set_nonces(nonces) { return Err( Error::InvalidSwap ) }
+                    }
+                }
+                for claim in &mut [ &mut claims.0, &mut claims.1, &mut refunds.0, &mut refunds.1 ] {
+                    claim.partial_signature = claim.session.partial_sign();
+                }
+                self.state = SwapState::Refunding;
+
+                // we sign the counterparty's refund
+                let counterparty_refund = match self.role {
+                    SwapRole::Initiator => &refunds.1,
+                    SwapRole::Responder => &refunds.0,
+                };
+                Ok(Some(SwapMessage::RefundSignature{ partial_signature: counterparty_refund.partial_signature.unwrap() }))
+            },
+
+            ( SwapState::Refunding, SwapMessage::RefundSignature{ partial_signature } ) => {
+                {
+                    let refunds = self.refunds.as_ref().unwrap();
+                    let refund = match self.role {
+                        SwapRole::Initiator => &refunds.0,
+                        SwapRole::Responder => &refunds.1,
+                    };
+                    let signature = refund.session.combine(&[ refund.partial_signature.unwrap(), partial_signature ]).ok_or(Error::InvalidSwap)?;
+                    let mut transaction = refund.transaction.clone();
+                    transaction.add_signature(Signature::new(signature));
+                    self.refund = Some(transaction);
+                }
+                self.state = SwapState::Signing;
+
+                // the initiator signs first, the responder only once it can verify the adaptor signatures
+                match self.role {
+                    SwapRole::Initiator => Ok(Some(self.partial_signatures())),
+                    SwapRole::Responder => Ok(None),
+                }
+            },
+
+            ( SwapState::Signing, SwapMessage::PartialSignatures{ chain_a, chain_b } ) => {
+                {
+                    let claims = self.claims.as_mut().unwrap();
+                    for &mut ( ref mut claim, partial_signature ) in &mut [ ( &mut claims.0, chain_a ), ( &mut claims.1, chain_b ) ] {
+                        let partials = [ claim.partial_signature.unwrap(), partial_signature ];
+                        claim.adaptor_signature = claim.session.combine_adaptor(&partials);
+                        if claim.adaptor_signature.is_none() { return Err( Error::InvalidSwap ) }
+                    }
+                }
+                self.state = SwapState::Claiming;
+
+                match self.role {
+                    SwapRole::Initiator => Ok(None),
+                    SwapRole::Responder => Ok(Some(self.partial_signatures())),
+                }
+            },
+
+            _ => Err( Error::InvalidSwap ),
+        }
+    }
+
+    fn partial_signatures( &self ) -> SwapMessage {
+        let claims = self.claims.as_ref().unwrap();
+        SwapMessage::PartialSignatures {
+            chain_a: claims.0.partial_signature.unwrap(),
+            chain_b: claims.1.partial_signature.unwrap()
+        }
+    }
+
+    /// Our refund on our chain, valid from the first block of our refund height on.
+    /// It is signed before the claims, so both can take back their coins if the swap stalls.
+    pub fn refund_transaction( &self, blockchain: &Blockchain ) -> Result<Transaction, Error> {
+        let mut transaction = self.refund.clone().ok_or(Error::InvalidSwap)?;
+        blockchain.validation_context().update_balances(&mut transaction);
+        Ok(transaction)
+    }
+
+    /// The initiator's claim of the responder's coins on chain B.
+    /// Publishing it reveals the adaptor secret to the responder.
+    pub fn initiator_claim( &mut self, chain_b: &Blockchain ) -> Result<Transaction, Error> {
+        if self.role != SwapRole::Initiator || self.state != SwapState::Claiming { return Err( Error::InvalidSwap ) }
+        let claim = &self.claims.as_ref().unwrap().1;
+        let signature = claim.adaptor_signature.unwrap().complete(self.adaptor_secret.as_ref().unwrap()).ok_or(Error::InvalidSwap)?;
+        let mut transaction = claim.transaction.clone();
+        transaction.add_signature(Signature::new(signature));
+        chain_b.validation_context().update_balances(&mut transaction);
+        self.state = SwapState::Completed;
+        Ok(transaction)
+    }
+
+    /// The responder's claim of the initiator's coins on chain A, given the
+    /// initiator's claim as published on chain B.
+    pub fn responder_claim( &mut self, initiator_claim: &Transaction, chain_a: &Blockchain ) -> Result<Transaction, Error> {
+        if self.role != SwapRole::Responder || self.state != SwapState::Claiming { return Err( Error::InvalidSwap ) }
+        let claims = self.claims.as_ref().unwrap();
+        // the published balances may differ from the signed claim's
+        if initiator_claim.signature_hash() != claims.1.transaction.signature_hash() { return Err( Error::InvalidSwap ) }
+
+        // the published signature reveals the adaptor secret
+        let adaptor_secret = claims.1.adaptor_signature.unwrap().extract(initiator_claim.signature.as_bytes()).ok_or(Error::InvalidSwap)?;
+        if adaptor_secret.public_key() != self.adaptor { return Err( Error::InvalidSwap ) }
+
+        let signature = claims.0.adaptor_signature.unwrap().complete(&adaptor_secret).ok_or(Error::InvalidSwap)?;
+        let mut transaction = claims.0.transaction.clone();
+        transaction.add_signature(Signature::new(signature));
+        chain_a.validation_context().update_balances(&mut transaction);
+        self.adaptor_secret = Some(adaptor_secret);
+        self.state = SwapState::Completed;
+        Ok(transaction)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blockchain::test_helpers::{ test_params, genesis, next_block };
+    use wallet::wallet::Wallet;
+
+    fn key( seed: u8 ) -> SecretKey {
+        SecretKey::new([seed; 32])
+    }
+
+    fn address( key: &SecretKey ) -> Address {
+        Address::from(key.public_key())
+    }
+
+    // mines a block of `transactions` whose reward goes to a fresh address
+    fn mine( blockchain: &mut Blockchain, transactions: Vec<Transaction>, seed: u64 ) {
+        let reward = Transaction::new_coinbase(TransactionOutput::new(Address::new([seed as u8; 32]), blockchain.current_reward()));
+        let block = next_block(blockchain, reward, transactions, seed);
+        blockchain.apply_block(&block).unwrap();
+    }
+
+    fn swap( wallet: &mut Wallet ) -> &mut AtomicSwap {
+        wallet.swap.as_mut().unwrap()
+    }
+
+    // delivers a message and returns the answer
+    fn send( wallet: &mut Wallet, message: SwapMessage ) -> Option<SwapMessage> {
+        swap(wallet).on_message(message).unwrap()
+    }
+
+    // alice gives 7 coins on chain A for 5 of bob's coins on chain B,
+    // returns the chains once both locked their coins in the joint address
+    fn funded_swap( alice: &mut Wallet, bob: &mut Wallet ) -> ( Blockchain, Blockchain ) {
+        let mut chain_a = Blockchain::new(&genesis(), test_params());
+        let mut chain_b = Blockchain::new(&genesis(), test_params());
+
+        // alice owns coins on chain A, bob owns coins on chain B
+        let alice_funds = key(1);
+        let bob_funds = key(2);
+        let reward = chain_a.current_reward();
+        let block = next_block(&chain_a, Transaction::new_coinbase(TransactionOutput::new(address(&alice_funds), reward)), vec![], 1);
+        chain_a.apply_block(&block).unwrap();
+        let block = next_block(&chain_b, Transaction::new_coinbase(TransactionOutput::new(address(&bob_funds), reward)), vec![], 1);
+        chain_b.apply_block(&block).unwrap();
+
+        let terms = SwapTerms{ give: 7, take: 5, refund_height_a: 10, refund_height_b: 6 };
+        let offer = alice.offer_swap(terms, key(5), address(&key(3)));
+        let accept = bob.accept_swap(offer, key(6), address(&key(4)), &chain_a, &chain_b).unwrap();
+        assert!(send(alice, accept).is_none());
+        let joint_address = swap(alice).joint_address().unwrap();
+        assert_eq!(swap(bob).joint_address().unwrap(), joint_address);
+
+        // both lock their coins in the joint address
+        let funding = swap(alice).funding_transaction(&chain_a, &alice_funds).unwrap();
+        mine(&mut chain_a, vec![funding], 2);
+        let funded = swap(alice).funded(chain_a.unspent_outputs.get_id_by_address(joint_address).unwrap());
+        send(bob, funded);
+        let funding = swap(bob).funding_transaction(&chain_b, &bob_funds).unwrap();
+        mine(&mut chain_b, vec![funding], 2);
+        let funded = swap(bob).funded(chain_b.unspent_outputs.get_id_by_address(joint_address).unwrap());
+        send(alice, funded);
+        ( chain_a, chain_b )
+    }
+
+    // signs the refunds and returns alice's partial signatures of the claims
+    fn sign_refunds( alice: &mut Wallet, bob: &mut Wallet, chain_a: &Blockchain, chain_b: &Blockchain ) -> SwapMessage {
+        let alice_commitments = swap(alice).start_signing(chain_b).unwrap();
+        let bob_commitments = swap(bob).start_signing(chain_a).unwrap();
+
+        // commitments replayed to their sender are rejected
+        assert!(swap(alice).on_message(alice_commitments.clone()).is_err());
+
+        let alice_nonces = send(alice, bob_commitments).unwrap();
+        let bob_nonces = send(bob, alice_commitments).unwrap();
+        let alice_refund_signature = send(alice, bob_nonces).unwrap();
+        let bob_refund_signature = send(bob, alice_nonces).unwrap();
+        assert!(send(bob, alice_refund_signature).is_none());
+        send(alice, bob_refund_signature).unwrap()
+    }
+
+    #[test]
+    fn atomic_swap() {
+        let mut alice = Wallet::new();
+        let mut bob = Wallet::new();
+        let ( mut chain_a, mut chain_b ) = funded_swap(&mut alice, &mut bob);
+        let reward = chain_a.current_reward();
+        let ( alice_claim, bob_claim ) = ( address(&key(3)), address(&key(4)) );
+        let joint_address = swap(&mut alice).joint_address().unwrap();
+
+        // both sign the refunds first, then the claims
+        let alice_partials = sign_refunds(&mut alice, &mut bob, &chain_a, &chain_b);
+        let bob_partials = send(&mut bob, alice_partials).unwrap();
+        assert!(send(&mut alice, bob_partials).is_none());
+
+        // bob cannot claim before alice did
+        assert!(swap(&mut bob).initiator_claim(&chain_b).is_err());
+
+        // alice claims on chain B, which reveals the secret to bob
+        let alice_claim_transaction = swap(&mut alice).initiator_claim(&chain_b).unwrap();
+        mine(&mut chain_b, vec![alice_claim_transaction.clone()], 3);
+        assert_eq!(chain_b.unspent_outputs.get_balance_by_address(alice_claim), 5);
+
+        // a forged claim reveals nothing
+        let mut forged = alice_claim_transaction.clone();
+        forged.add_signature(Signature::new([1u8; 64]));
+        assert!(swap(&mut bob).responder_claim(&forged, &chain_a).is_err());
+
+        let bob_claim_transaction = swap(&mut bob).responder_claim(&alice_claim_transaction, &chain_a).unwrap();
+        mine(&mut chain_a, vec![bob_claim_transaction], 3);
+        assert_eq!(chain_a.unspent_outputs.get_balance_by_address(bob_claim), 7);
+        assert_eq!(chain_a.unspent_outputs.get_balance_by_address(address(&key(1))), reward - 7);
+        assert_eq!(chain_b.unspent_outputs.get_balance_by_address(address(&key(2))), reward - 5);
+        assert_eq!(chain_a.unspent_outputs.get_balance_by_address(joint_address), 0);
+    }
+
+    #[test]
+    fn refund() {
+        let mut alice = Wallet::new();
+        let mut bob = Wallet::new();
+        let ( mut chain_a, mut chain_b ) = funded_swap(&mut alice, &mut bob);
+        let ( alice_claim, bob_claim ) = ( address(&key(3)), address(&key(4)) );
+
+        // no refund before both signed it
+        assert!(swap(&mut alice).refund_transaction(&chain_a).is_err());
+
+        // bob disappears after the refunds are signed
+        sign_refunds(&mut alice, &mut bob, &chain_a, &chain_b);
+        let alice_refund = swap(&mut alice).refund_transaction(&chain_a).unwrap();
+        let bob_refund = swap(&mut bob).refund_transaction(&chain_b).unwrap();
+
+        // the refunds are locked until the refund heights
+        let is_locked = |chain: &Blockchain, refund: &Transaction| matches!(chain.verify_transaction(refund, &chain.validation_context()), Err(Error::TransactionLocked));
+        assert!(is_locked(&chain_a, &alice_refund));
+        assert!(is_locked(&chain_b, &bob_refund));
+        while chain_b.block_count() + 1 < 6 {
+            let seed = chain_b.block_count() as u64 + 10;
+            mine(&mut chain_b, vec![], seed);
+        }
+        assert!(!is_locked(&chain_b, &bob_refund));
+        assert!(is_locked(&chain_a, &alice_refund));
+        mine(&mut chain_b, vec![bob_refund], 20);
+        assert_eq!(chain_b.unspent_outputs.get_balance_by_address(bob_claim), 5);
+
+        while chain_a.block_count() + 1 < 10 {
+            let seed = chain_a.block_count() as u64 + 10;
+            mine(&mut chain_a, vec![], seed);
+        }
+        mine(&mut chain_a, vec![alice_refund], 20);
+        assert_eq!(chain_a.unspent_outputs.get_balance_by_address(alice_claim), 7);
+    }
+
+    #[test]
+    fn refund_heights() {
+        let mut chain_a = Blockchain::new(&genesis(), test_params());
+        let chain_b = Blockchain::new(&genesis(), test_params());
+        let accepts = |chain_a: &Blockchain, refund_height_a: u32, refund_height_b: u32| {
+            let terms = SwapTerms{ give: 7, take: 5, refund_height_a, refund_height_b };
+            let ( _, offer ) = AtomicSwap::offer(terms, key(5), address(&key(3)));
+            AtomicSwap::accept(offer, key(6), address(&key(4)), chain_a, &chain_b).is_ok()
+        };
+        let height_b = chain_b.block_count() + 1 + REFUND_MARGIN;
+        let height_a = chain_a.block_count() + 1 + 2 * REFUND_MARGIN;
+        assert!(accepts(&chain_a, height_a, height_b));
+
+        // the initiator's refund unlocks first
+        assert!(!accepts(&chain_a, height_b, height_a));
+        // too little time for the initiator's claim
+        assert!(!accepts(&chain_a, height_a, height_b - 1));
+        // too little time for the responder's claim
+        assert!(!accepts(&chain_a, height_a - 1, height_b));
+
+        // the margin counts from the current height of each chain
+        mine(&mut chain_a, vec![], 1);
+        assert!(!accepts(&chain_a, height_a, height_b));
+        assert!(accepts(&chain_a, height_a + 1, height_b));
+    }
+
+    #[test]
+    fn dusted_claim_address() {
+        let mut alice = Wallet::new();
+        let mut bob = Wallet::new();
+        let ( mut chain_a, mut chain_b ) = funded_swap(&mut alice, &mut bob);
+        let ( alice_claim, bob_claim ) = ( address(&key(3)), address(&key(4)) );
+        let alice_partials = sign_refunds(&mut alice, &mut bob, &chain_a, &chain_b);
+        let bob_partials = send(&mut bob, alice_partials).unwrap();
+        send(&mut alice, bob_partials);
+
+        // someone deposits to both claim addresses after the claims were signed
+        let dust = |chain: &mut Blockchain, address: Address| {
+            let reward = Transaction::new_coinbase(TransactionOutput::new(address, chain.current_reward()));
+            let block = next_block(chain, reward, vec![], 30);
+            chain.apply_block(&block).unwrap();
+        };
+        dust(&mut chain_a, bob_claim);
+        dust(&mut chain_b, alice_claim);
+        let reward = chain_a.current_reward();
+
+        let alice_claim_transaction = swap(&mut alice).initiator_claim(&chain_b).unwrap();
+        mine(&mut chain_b, vec![alice_claim_transaction.clone()], 3);
+        assert_eq!(chain_b.unspent_outputs.get_balance_by_address(alice_claim), reward + 5);
+
+        let bob_claim_transaction = swap(&mut bob).responder_claim(&alice_claim_transaction, &chain_a).unwrap();
+        mine(&mut chain_a, vec![bob_claim_transaction], 3);
+        assert_eq!(chain_a.unspent_outputs.get_balance_by_address(bob_claim), reward + 7);
+    }
+}
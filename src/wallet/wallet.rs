@@ -1,19 +1,40 @@
 use protocol::protocol_config::ProtocolConfig;
 use protocol::event::{ Event, EventResult };
-use blockchain::transaction::{ Transaction, TransactionOutput, Address, Signature};
+#[cfg(test)]
+use protocol::event::Error;
+use blockchain::transaction::{ Transaction, TransactionInput, TransactionOutput, Address };
 use blockchain::blockchain::Blockchain;
+use blockchain::block_tree::{ ChainUpdate, ChainListener };
+#[cfg(test)]
+use crypto::schnorr::SecretKey;
+#[cfg(test)]
+use wallet::swap::{ AtomicSwap, SwapMessage, SwapTerms };
+use std::collections::HashSet;
 extern crate rand;
 
 
-pub struct Wallet;
+pub struct Wallet {
+	// inputs spent by our transactions which are not yet confirmed
+	pending_inputs: HashSet<TransactionInput>,
+	// inputs spent by our transactions in the main chain
+	confirmed_inputs: HashSet<TransactionInput>,
+	// our atomic swap in progress, swaps are not yet negotiated with peers
+	#[cfg(test)]
+	pub swap: Option<AtomicSwap>
+}
 
 impl Wallet{
 
 	pub fn new() -> Wallet {
-		Wallet {}
+		Wallet {
+			pending_inputs: HashSet::new(),
+			confirmed_inputs: HashSet::new(),
+			#[cfg(test)]
+			swap: None
+		}
 	}
 
-	pub fn poll_new_transaction( &self, blockchain: &Blockchain, config : &ProtocolConfig ) -> EventResult{
+	pub fn poll_new_transaction( &mut self, blockchain: &Blockchain, config : &ProtocolConfig ) -> EventResult{
 		let random_value: u32 = rand::random();
 		if random_value > 10000000 { return Ok(Event::Nothing) }
 		
@@ -21,6 +42,9 @@ impl Wallet{
 
 		match option {
 		    Some( (transaction_input, mut transaction_output) ) => {
+		    	// wait until our last transaction is confirmed
+		    	if self.pending_inputs.contains(&transaction_input) { return Ok( Event::Nothing ) }
+
 		    	let recipient_value = 2;
 				let recipient_address = Address::new(rand::random());
 				let recipient_output = TransactionOutput::new(recipient_address, recipient_value);
@@ -31,9 +55,10 @@ impl Wallet{
 				let outputs = vec![recipient_output, transaction_output];
 				
 				let mut transaction = Transaction::new(inputs, outputs);
-				
-				let signature = Signature::new([255u8;64]);
-				transaction.add_signature(signature);
+				// the balances as of the current head
+				blockchain.validation_context().update_balances(&mut transaction);
+				transaction.sign(&[config.get_miner_secret_key()]);
+				self.pending_inputs.insert(transaction_input);
 				Ok( Event::Transaction( transaction ))
 		    },
 		    None => Ok( Event::Nothing ),
@@ -44,6 +69,51 @@ impl Wallet{
 	}
 }
 
+#[cfg(test)]
+impl Wallet {
+
+	// offers a swap with `key` as our key of the joint addresses, returns the offer for the counterparty
+	pub fn offer_swap( &mut self, terms: SwapTerms, key: SecretKey, claim_address: Address ) -> SwapMessage {
+		let ( swap, offer ) = AtomicSwap::offer(terms, key, claim_address);
+		self.swap = Some(swap);
+		offer
+	}
+
+	// accepts a counterparty's offer given both chains, returns the answer
+	pub fn accept_swap( &mut self, offer: SwapMessage, key: SecretKey, claim_address: Address, chain_a: &Blockchain, chain_b: &Blockchain ) -> Result<SwapMessage, Error> {
+		let ( swap, answer ) = AtomicSwap::accept(offer, key, claim_address, chain_a, chain_b)?;
+		self.swap = Some(swap);
+		Ok(answer)
+	}
+}
+
+impl ChainListener for Wallet {
+
+	fn on_chain_update( &mut self, update: &ChainUpdate, _blockchain: &Blockchain ){
+		// our transactions in disconnected blocks are back in the transactions pool
+		for block in &update.disconnected {
+			for transaction in &block.transactions {
+				for input in &transaction.inputs {
+					if self.confirmed_inputs.remove(input) {
+						self.pending_inputs.insert(*input);
+					}
+				}
+			}
+		}
+
+		// our transactions in connected blocks are confirmed
+		for block in &update.connected {
+			for transaction in &block.transactions {
+				for input in &transaction.inputs {
+					if self.pending_inputs.remove(input) {
+						self.confirmed_inputs.insert(*input);
+					}
+				}
+			}
+		}
+	}
+}
+
 // fn create_dummy_transaction() -> Transaction {
 
 // 	let transaction_input_1 = TransactionInput{ block_id: rand::random(), transaction_id:rand::random() };